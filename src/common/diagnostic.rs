@@ -0,0 +1,42 @@
+use std::fmt;
+
+// Error attached to the position of the token that caused it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Diagnostic {
+    pub fn new(message: String, line: u32, column: u32) -> Diagnostic {
+        Diagnostic {
+            message,
+            line,
+            column,
+        }
+    }
+
+    // Shows the message followed by the offending source line with a caret
+    // under the column of the error
+    pub fn render(&self, source: &str) -> String {
+        let Some(source_line) = source.lines().nth((self.line as usize).saturating_sub(1)) else {
+            return self.to_string();
+        };
+
+        // Keep tabs so the caret lines up with the source
+        let padding: String = source_line
+            .chars()
+            .take((self.column as usize).saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        format!("{}\n{}\n{}^", self, source_line, padding)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}:{}: {}", self.line, self.column, self.message)
+    }
+}
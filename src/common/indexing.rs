@@ -0,0 +1,11 @@
+// Resolves an index into a collection of the given length, negative indices
+// count from the end. Returns None when the index is out of bounds
+pub fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let resolved = if index < 0 { len as i64 + index } else { index };
+
+    if resolved >= 0 && (resolved as usize) < len {
+        Some(resolved as usize)
+    } else {
+        None
+    }
+}
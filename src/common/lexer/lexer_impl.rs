@@ -15,6 +15,8 @@ pub struct Lexer<'a> {
     current_char: Option<char>,
     // Line number starting at 1
     line: u32,
+    // Byte position where the current line starts
+    line_start: usize,
     keywords: HashMap<&'static str, TokenType>,
 }
 
@@ -28,6 +30,7 @@ impl<'a> Lexer<'a> {
             position: 0,
             current_char,
             line: 1,
+            line_start: 0,
             keywords: keywords(),
         }
     }
@@ -36,6 +39,9 @@ impl<'a> Lexer<'a> {
     fn read_char(&mut self) {
         if let Some(c) = self.current_char {
             self.position += c.len_utf8();
+            if c == '\n' {
+                self.line_start = self.position;
+            }
         }
 
         self.current_char = self.chars.next();
@@ -46,20 +52,50 @@ impl<'a> Lexer<'a> {
         self.chars.peek().copied()
     }
 
-    fn skip_whitespaces(&mut self) {
+    // Column of the current char, starting at 1
+    fn column(&self) -> u32 {
+        self.input[self.line_start..self.position].chars().count() as u32 + 1
+    }
+
+    // Skips whitespaces and comments, so tokens start at their first char.
+    // Returns an error token for a block comment running to the end of input
+    fn skip_whitespaces(&mut self) -> Option<Token> {
         while let Some(c) = self.current_char {
             if c == '\t' || c == ' ' || c == '\r' {
                 self.read_char();
+            } else if c == '/' && self.peek_char() == Some('/') {
+                self.read_char();
+                self.read_one_line_comment();
+            } else if c == '/' && self.peek_char() == Some('*') {
+                let (line, column) = (self.line, self.column());
+                self.read_char();
+                if !self.read_multiple_line_comment() {
+                    let mut token =
+                        Token::new(TokenType::Error, "unterminated comment".to_string(), line);
+                    token.column = column;
+                    return Some(token);
+                }
             } else {
                 break;
             }
         }
+        None
     }
 
     // Consumes characters until it can form a token
     pub fn next_token(&mut self) -> Token {
-        self.skip_whitespaces();
+        if let Some(error) = self.skip_whitespaces() {
+            return error;
+        }
 
+        let column = self.column();
+        let mut token = self.scan_token();
+        token.column = column;
+
+        token
+    }
+
+    fn scan_token(&mut self) -> Token {
         match self.current_char {
             Some('(') => {
                 self.read_char();
@@ -77,30 +113,57 @@ impl<'a> Lexer<'a> {
                 self.read_char();
                 Token::new(TokenType::RightBrace, "}".to_string(), self.line)
             }
+            Some('[') => {
+                self.read_char();
+                Token::new(TokenType::LeftBracket, "[".to_string(), self.line)
+            }
+            Some(']') => {
+                self.read_char();
+                Token::new(TokenType::RightBracket, "]".to_string(), self.line)
+            }
             Some('-') => {
                 self.read_char();
-                Token::new(TokenType::Minus, "-".to_string(), self.line)
+                if self.current_char == Some('=') {
+                    self.read_char();
+                    Token::new(TokenType::MinusEqual, "-=".to_string(), self.line)
+                } else {
+                    Token::new(TokenType::Minus, "-".to_string(), self.line)
+                }
             }
             Some('+') => {
                 self.read_char();
-                Token::new(TokenType::Plus, "+".to_string(), self.line)
+                if self.current_char == Some('=') {
+                    self.read_char();
+                    Token::new(TokenType::PlusEqual, "+=".to_string(), self.line)
+                } else {
+                    Token::new(TokenType::Plus, "+".to_string(), self.line)
+                }
             }
             Some('*') => {
                 self.read_char();
-                Token::new(TokenType::Star, "*".to_string(), self.line)
+                if self.current_char == Some('*') {
+                    self.read_char();
+                    Token::new(TokenType::StarStar, "**".to_string(), self.line)
+                } else if self.current_char == Some('=') {
+                    self.read_char();
+                    Token::new(TokenType::StarEqual, "*=".to_string(), self.line)
+                } else {
+                    Token::new(TokenType::Star, "*".to_string(), self.line)
+                }
             }
             Some('/') => {
                 self.read_char();
-                if self.current_char == Some('/') {
-                    self.read_one_line_comment();
-                    self.next_token()
-                } else if self.current_char == Some('*') {
-                    self.read_multiple_line_comment();
-                    return self.next_token();
+                if self.current_char == Some('=') {
+                    self.read_char();
+                    Token::new(TokenType::SlashEqual, "/=".to_string(), self.line)
                 } else {
                     Token::new(TokenType::Slash, "/".to_string(), self.line)
                 }
             }
+            Some('%') => {
+                self.read_char();
+                Token::new(TokenType::Percent, "%".to_string(), self.line)
+            }
             Some('=') => {
                 self.read_char();
                 if self.current_char == Some('=') {
@@ -136,6 +199,10 @@ impl<'a> Lexer<'a> {
                 self.read_char();
                 Token::new(TokenType::Comma, ",".to_string(), self.line)
             }
+            Some(';') => {
+                self.read_char();
+                Token::new(TokenType::Semicolon, ";".to_string(), self.line)
+            }
             Some('.') => {
                 let start_position = self.position;
                 self.read_char();
@@ -147,7 +214,15 @@ impl<'a> Lexer<'a> {
             }
             Some('?') => {
                 self.read_char();
-                Token::new(TokenType::Question, "?".to_string(), self.line)
+                if self.current_char == Some('.') {
+                    self.read_char();
+                    Token::new(TokenType::QuestionDot, "?.".to_string(), self.line)
+                } else if self.current_char == Some('?') {
+                    self.read_char();
+                    Token::new(TokenType::QuestionQuestion, "??".to_string(), self.line)
+                } else {
+                    Token::new(TokenType::Question, "?".to_string(), self.line)
+                }
             }
             Some('!') => {
                 self.read_char();
@@ -210,19 +285,30 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn read_multiple_line_comment(&mut self) {
+    // Block comments nest, so commenting out code that has comments works
+    // Returns whether the comment was closed before the end of input
+    fn read_multiple_line_comment(&mut self) -> bool {
         // Skip first char
         self.read_char();
+        let mut depth = 1;
         while let Some(c) = self.current_char {
             if c == '\n' {
                 self.line += 1;
+            } else if c == '/' && self.peek_char() == Some('*') {
+                self.read_char(); // Skip /
+                depth += 1;
             } else if c == '*' && self.peek_char() == Some('/') {
                 self.read_char(); // Skip *
                 self.read_char(); // Skip /
-                break;
+                depth -= 1;
+                if depth == 0 {
+                    return true;
+                }
+                continue;
             }
             self.read_char();
         }
+        false
     }
 
     fn read_identifier_or_keyword(&mut self) -> Token {
@@ -260,6 +346,15 @@ impl<'a> Lexer<'a> {
 
         let lexeme: String = self.input[start_position..self.position].to_string();
 
+        // Integers must fit in 64 bits, bigger literals would wrap or lose precision
+        if lexeme.parse::<i64>().is_err() {
+            return Token::new(
+                TokenType::Error,
+                "integer literal out of range".to_string(),
+                self.line,
+            );
+        }
+
         Token::new(TokenType::Integer, lexeme, self.line)
     }
 
@@ -276,22 +371,101 @@ impl<'a> Lexer<'a> {
         Token::new(TokenType::Float, lexeme, self.line)
     }
 
+    // Strings may span lines, the token is placed where it starts
     fn read_string(&mut self) -> Token {
+        let line = self.line;
         // Skip "
         self.read_char();
-        let start_position = self.position;
 
+        let mut lexeme = String::new();
+        let mut error = None;
         while let Some(c) = self.current_char {
-            if c == '"' {
-                break;
+            match c {
+                '"' => break,
+                '\\' => {
+                    // Skip \
+                    self.read_char();
+                    match self.read_escape() {
+                        Ok(escaped) => lexeme.push(escaped),
+                        Err(message) => error = error.or(Some(message)),
+                    }
+                }
+                _ => {
+                    if c == '\n' {
+                        self.line += 1;
+                    }
+                    lexeme.push(c);
+                    self.read_char();
+                }
             }
-            self.read_char();
         }
-
-        let lexeme: String = self.input[start_position..self.position].to_string();
+        if self.current_char.is_none() {
+            error = error.or(Some("unterminated string".to_string()));
+        }
         // Skip "
         self.read_char();
 
-        Token::new(TokenType::String, lexeme, self.line)
+        // The whole string is consumed so lexing goes on after it
+        if let Some(message) = error {
+            return Token::new(TokenType::Error, message, line);
+        }
+        Token::new(TokenType::String, lexeme, line)
+    }
+
+    // Reads the escape after a \, \xHH and \u{H...} give the char with that
+    // code
+    fn read_escape(&mut self) -> Result<char, String> {
+        match self.current_char {
+            Some(c @ ('n' | 't' | '0' | '\\' | '"')) => {
+                self.read_char();
+                Ok(match c {
+                    'n' => '\n',
+                    't' => '\t',
+                    '0' => '\0',
+                    c => c,
+                })
+            }
+            Some('x') => {
+                self.read_char();
+                let mut code = 0;
+                for _ in 0..2 {
+                    let Some(digit) = self.current_char.and_then(|c| c.to_digit(16)) else {
+                        return Err("invalid hex escape".to_string());
+                    };
+                    code = code * 16 + digit;
+                    self.read_char();
+                }
+                // Two hex digits are always a valid char
+                Ok(char::from(code as u8))
+            }
+            Some('u') => {
+                self.read_char();
+                if self.current_char != Some('{') {
+                    return Err("invalid unicode escape".to_string());
+                }
+                self.read_char();
+
+                let mut code: u32 = 0;
+                let mut digits = 0;
+                while let Some(digit) = self.current_char.and_then(|c| c.to_digit(16)) {
+                    code = code.saturating_mul(16).saturating_add(digit);
+                    digits += 1;
+                    self.read_char();
+                }
+                if self.current_char != Some('}') || !(1..=6).contains(&digits) {
+                    return Err("invalid unicode escape".to_string());
+                }
+                self.read_char();
+
+                // Surrogates and codes past 10FFFF aren't chars
+                char::from_u32(code).ok_or("unicode escape out of range".to_string())
+            }
+            Some(c) => {
+                self.read_char();
+                Err(format!("unknown escape \\{}", c))
+            }
+            // The string ends right after the \
+            None => Err("unterminated string".to_string()),
+        }
     }
 }
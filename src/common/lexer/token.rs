@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::fmt;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Hash, Eq)]
 pub enum TokenType {
     // Single character tokens
@@ -8,17 +9,28 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Minus,
+    MinusEqual,
     Plus,
+    PlusEqual,
     Star,
+    StarStar,
+    StarEqual,
     Slash,
+    SlashEqual,
+    Percent,
     Equal,
     Greater,
     Less,
     Colon,
     Comma,
+    Semicolon,
     Dot,
     Question,
+    QuestionDot,
+    QuestionQuestion,
     Bang,
     NewLine, // Line terminator
     // Operators
@@ -41,7 +53,13 @@ pub enum TokenType {
     False,
     If,
     Else,
+    Elif,
     While,
+    For,
+    In,
+    Continue,
+    Break,
+    Yield,
     Null,
     // End of file
     EOF,
@@ -57,6 +75,8 @@ impl fmt::Display for TokenType {
             TokenType::RightParen => ")",
             TokenType::LeftBrace => "{",
             TokenType::RightBrace => "}",
+            TokenType::LeftBracket => "[",
+            TokenType::RightBracket => "]",
             TokenType::Illegal => "Illegal",
             TokenType::EOF => "EOF",
             TokenType::Identifier => "Identifier",
@@ -64,17 +84,26 @@ impl fmt::Display for TokenType {
             TokenType::Integer => "Integer",
             TokenType::Float => "Float",
             TokenType::Plus => "+",
+            TokenType::PlusEqual => "+=",
             TokenType::Minus => "-",
+            TokenType::MinusEqual => "-=",
             TokenType::Star => "*",
+            TokenType::StarStar => "**",
+            TokenType::StarEqual => "*=",
             TokenType::Slash => "/",
+            TokenType::SlashEqual => "/=",
+            TokenType::Percent => "%",
             TokenType::Equal => "=",
             TokenType::Less => "<",
             TokenType::Greater => ">",
             TokenType::Colon => ":",
             TokenType::Comma => ",",
+            TokenType::Semicolon => ";",
             TokenType::Dot => ".",
             TokenType::NewLine => "\\n",
             TokenType::Question => "?",
+            TokenType::QuestionDot => "?.",
+            TokenType::QuestionQuestion => "??",
             TokenType::Bang => "!",
             TokenType::BangEqual => "!=",
             TokenType::EqualEqual => "==",
@@ -89,7 +118,13 @@ impl fmt::Display for TokenType {
             TokenType::False => "false",
             TokenType::If => "if",
             TokenType::Else => "else",
+            TokenType::Elif => "elif",
             TokenType::While => "while",
+            TokenType::For => "for",
+            TokenType::In => "in",
+            TokenType::Continue => "continue",
+            TokenType::Break => "break",
+            TokenType::Yield => "yield",
             TokenType::Null => "null",
             TokenType::Error => "error",
             TokenType::Default => "default",
@@ -98,11 +133,21 @@ impl fmt::Display for TokenType {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct Token {
     pub kind: TokenType,
     pub lexeme: String,
     pub line: u32,
+    // Column of the first char of the token, starting at 1
+    pub column: u32,
+}
+
+// Tokens are equal when they have the same kind and lexeme, wherever they are
+// in the source
+impl PartialEq for Token {
+    fn eq(&self, other: &Token) -> bool {
+        self.kind == other.kind && self.lexeme == other.lexeme
+    }
 }
 
 impl Default for Token {
@@ -111,13 +156,19 @@ impl Default for Token {
             kind: TokenType::Error,
             lexeme: String::new(),
             line: 0,
+            column: 0,
         }
     }
 }
 
 impl Token {
     pub fn new(kind: TokenType, lexeme: String, line: u32) -> Token {
-        Token { kind, lexeme, line }
+        Token {
+            kind,
+            lexeme,
+            line,
+            column: 0,
+        }
     }
 }
 
@@ -127,10 +178,16 @@ pub fn keywords() -> HashMap<&'static str, TokenType> {
     m.insert("fn", TokenType::Function);
     m.insert("if", TokenType::If);
     m.insert("else", TokenType::Else);
+    m.insert("elif", TokenType::Elif);
     m.insert("return", TokenType::Return);
     m.insert("true", TokenType::True);
     m.insert("false", TokenType::False);
     m.insert("while", TokenType::While);
+    m.insert("for", TokenType::For);
+    m.insert("in", TokenType::In);
+    m.insert("continue", TokenType::Continue);
+    m.insert("break", TokenType::Break);
+    m.insert("yield", TokenType::Yield);
     m.insert("null", TokenType::Null);
 
     m
@@ -1,2 +1,4 @@
+pub mod diagnostic;
+pub mod indexing;
 pub mod lexer;
 pub mod precedences;
@@ -6,13 +6,18 @@ use super::lexer::token::TokenType;
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Precedence {
     Lowest,      // default value
-    Assigment,   // =
+    Assigment,   // =, +=, -=, *=, /=
+    Coalesce,    // ??
+    Or,          // ||
+    And,         // &&
     Equals,      // ==, !=
     LessGreater, // >, <, >=, <=
     Sum,         // +, -
-    Product,     // *, /
+    Product,     // *, /, %
     Prefix,      // -X, !X
+    Power,       // **
     Call,        // myFunction(X)
+    Index,       // array[index], array?[index]
 }
 
 pub fn create_precedences() -> HashMap<TokenType, Precedence> {
@@ -27,8 +32,30 @@ pub fn create_precedences() -> HashMap<TokenType, Precedence> {
     precedences.insert(TokenType::Minus, Precedence::Sum);
     precedences.insert(TokenType::Star, Precedence::Product);
     precedences.insert(TokenType::Slash, Precedence::Product);
+    precedences.insert(TokenType::Percent, Precedence::Product);
+    precedences.insert(TokenType::StarStar, Precedence::Power);
     precedences.insert(TokenType::LeftParen, Precedence::Call);
+    precedences.insert(TokenType::LeftBracket, Precedence::Index);
+    precedences.insert(TokenType::Question, Precedence::Index);
+    precedences.insert(TokenType::QuestionDot, Precedence::Index);
+    precedences.insert(TokenType::Dot, Precedence::Index);
     precedences.insert(TokenType::Equal, Precedence::Assigment);
+    precedences.insert(TokenType::PlusEqual, Precedence::Assigment);
+    precedences.insert(TokenType::MinusEqual, Precedence::Assigment);
+    precedences.insert(TokenType::StarEqual, Precedence::Assigment);
+    precedences.insert(TokenType::SlashEqual, Precedence::Assigment);
+    precedences.insert(TokenType::QuestionQuestion, Precedence::Coalesce);
+    precedences.insert(TokenType::Or, Precedence::Or);
+    precedences.insert(TokenType::And, Precedence::And);
 
     precedences
 }
+
+// Precedence of the right operand of an infix operator. Right associative
+// operators go one level lower, so 2 ** 3 ** 2 is 2 ** (3 ** 2)
+pub fn right_operand_precedence(operator: TokenType, precedence: Precedence) -> Precedence {
+    match operator {
+        TokenType::StarStar => Precedence::Prefix,
+        _ => precedence,
+    }
+}
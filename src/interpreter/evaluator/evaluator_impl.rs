@@ -1,4 +1,5 @@
 use super::object::{Environment, Function, Object};
+use crate::common::indexing::resolve_index;
 use crate::interpreter::parser::ast::{
     Expression, Identifier, IfExpression, Node, Statement, WhileStatement,
 };
@@ -37,6 +38,7 @@ fn eval_expression(expression: Expression, environment: &mut Environment) -> Obj
         Expression::Float(float) => Object::Float(float.value),
         Expression::Identifier(identifier) => eval_identifier(identifier, environment),
         Expression::Boolean(boolean) => eval_boolean(boolean.value),
+        Expression::Null(_) => NULL,
         Expression::String(string) => Object::String(string.value),
         Expression::Prefix(prefix_expression) => {
             let right = eval(Node::Expression(*prefix_expression.right), environment);
@@ -100,6 +102,41 @@ fn eval_expression(expression: Expression, environment: &mut Environment) -> Obj
                 Err(error) => error,
             }
         }
+        Expression::Array(array_literal) => {
+            match eval_expressions(array_literal.elements, environment) {
+                Ok(elements) => Object::Array(elements),
+                Err(error) => error,
+            }
+        }
+        Expression::Index(index_expression) => {
+            let left = eval_expression(*index_expression.left, environment);
+            if is_error(&left) {
+                return left;
+            }
+
+            let index = eval_expression(*index_expression.index, environment);
+            if is_error(&index) {
+                return index;
+            }
+
+            eval_index_expression(left, index)
+        }
+    }
+}
+
+fn eval_index_expression(left: Object, index: Object) -> Object {
+    match (left, index) {
+        (Object::Array(mut elements), Object::Integer(index)) => {
+            match resolve_index(index, elements.len()) {
+                Some(position) => elements.swap_remove(position),
+                None => Object::Error(format!("index out of bounds: {}", index)),
+            }
+        }
+        (left, index) => Object::Error(format!(
+            "index operator not supported: {}[{}]",
+            left.object_type(),
+            index.object_type()
+        )),
     }
 }
 
@@ -200,6 +237,21 @@ fn eval_integer_infix_expression(left_value: i64, operator: &str, right_value: i
                 Object::Error("error division by 0".to_string())
             }
         }
+        "%" => {
+            if right_value != 0 {
+                Object::Integer(left_value.wrapping_rem(right_value))
+            } else {
+                Object::Error("error modulo by 0".to_string())
+            }
+        }
+        // Negative exponents give floats
+        "**" => match u32::try_from(right_value) {
+            Ok(exponent) => match left_value.checked_pow(exponent) {
+                Some(result) => Object::Integer(result),
+                None => Object::Error("error integer overflow".to_string()),
+            },
+            Err(_) => Object::Float((left_value as f64).powf(right_value as f64)),
+        },
         "==" => eval_boolean(left_value == right_value),
         "!=" => eval_boolean(left_value != right_value),
         ">" => eval_boolean(left_value > right_value),
@@ -222,6 +274,14 @@ fn eval_float_infix_expression(left_value: f64, operator: &str, right_value: f64
                 Object::Error("error division by 0".to_string())
             }
         }
+        "%" => {
+            if right_value != 0.0 {
+                Object::Float(left_value % right_value)
+            } else {
+                Object::Error("error modulo by 0".to_string())
+            }
+        }
+        "**" => Object::Float(left_value.powf(right_value)),
         "==" => eval_boolean(left_value == right_value),
         "!=" => eval_boolean(left_value != right_value),
         ">" => eval_boolean(left_value > right_value),
@@ -245,6 +305,10 @@ fn eval_string_infix_expression(left_value: String, operator: &str, right_value:
         "+" => Object::String(format!("{}{}", left_value, right_value)),
         "==" => eval_boolean(left_value == right_value),
         "!=" => eval_boolean(left_value != right_value),
+        ">" => eval_boolean(left_value > right_value),
+        ">=" => eval_boolean(left_value >= right_value),
+        "<" => eval_boolean(left_value < right_value),
+        "<=" => eval_boolean(left_value <= right_value),
         _ => Object::Error(format!("unknow operator: {}", operator)),
     }
 }
@@ -274,6 +338,7 @@ fn is_truthy(object: &Object) -> bool {
     }
 }
 
+#[allow(clippy::result_large_err)]
 fn eval_expressions(
     expressions: Vec<Expression>,
     environment: &mut Environment,
@@ -10,6 +10,7 @@ pub enum Object {
     String(String),
     Return(Box<Object>),
     Function(Function),
+    Array(Vec<Object>),
     Error(String),
     Null,
 }
@@ -23,6 +24,14 @@ impl Display for Object {
             Object::String(string) => string.to_string(),
             Object::Return(return_object) => return_object.to_string(),
             Object::Function(function) => function.to_string(),
+            Object::Array(elements) => format!(
+                "[{}]",
+                elements
+                    .iter()
+                    .map(|element| element.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
             Object::Error(error_message) => error_message.to_string(),
             Object::Null => "null".to_string(),
         };
@@ -40,6 +49,7 @@ impl Object {
             Object::String(_) => "STRING".to_string(),
             Object::Return(_) => "RETURN".to_string(),
             Object::Function(_) => "FUNCTION".to_string(),
+            Object::Array(_) => "ARRAY".to_string(),
             Object::Error(_) => "ERROR".to_string(),
             Object::Null => "NULL".to_string(),
         }
@@ -81,7 +91,7 @@ impl Function {
 }
 
 // Store for identifier values
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Default, PartialEq, Clone)]
 pub struct Environment {
     inner: HashMap<String, Object>,
     outer: Option<Box<Environment>>, // Outer environment of the function
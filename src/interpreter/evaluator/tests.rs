@@ -247,6 +247,25 @@ fn division_by_zero() {
     assert_eq!(result, Object::Error("error division by 0".to_string()));
 }
 
+#[test]
+fn power() {
+    assert_eq!(test_eval("2 ** 10\n"), Object::Integer(1024));
+    assert_eq!(test_eval("2 ** 3 ** 2\n"), Object::Integer(512));
+    assert_eq!(test_eval("2 ** -1\n"), Object::Float(0.5));
+    assert_eq!(test_eval("4.0 ** 0.5\n"), Object::Float(2.0));
+}
+
+#[test]
+fn modulo() {
+    assert_eq!(test_eval("10 % 3\n"), Object::Integer(1));
+    assert_eq!(test_eval("-7 % 3\n"), Object::Integer(-1));
+    assert_eq!(test_eval("7.5 % 2.0\n"), Object::Float(1.5));
+    assert_eq!(
+        test_eval("5 % 0\n"),
+        Object::Error("error modulo by 0".to_string())
+    );
+}
+
 #[test]
 fn test_if_expression_with_non_boolean_condition() {
     let input = "if (5) { 10\n } else { 20\n }\n";
@@ -370,3 +389,16 @@ fn eval_assigment() {
 
     assert_eq!(result, Object::Integer(4));
 }
+
+#[test]
+fn eval_index_errors() {
+    let tests = [
+        ("[1, 2][2]", "index out of bounds: 2"),
+        ("[1, 2][1.0]", "index operator not supported: ARRAY[FLOAT]"),
+        ("5[0]", "index operator not supported: INTEGER[INTEGER]"),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(test_eval(input), Object::Error(expected.to_string()));
+    }
+}
@@ -3,23 +3,47 @@ use crate::{
     interpreter::{evaluator::evaluator_impl::eval, parser::ast::Node},
 };
 
-use super::{evaluator::object::Environment, parser::parser_impl::Parser};
+use super::{
+    evaluator::object::Environment, parser::parser_impl::Parser, typechecker::check_program,
+};
 
-pub fn interpret_ast(input: String) {
+// Runs the script and returns its environment
+pub fn interpret_ast(input: String) -> Environment {
     let mut environment = Environment::new();
-    loop {
-        let lexer = Lexer::new(&input);
-        let mut parser = Parser::new(lexer);
+    let lexer = Lexer::new(&input);
+    let mut parser = Parser::new(lexer);
+
+    let program = parser.parse_program();
+
+    if !parser.errors.is_empty() {
+        print_parser_errors(parser.errors);
+        return environment;
+    }
 
-        let program = parser.parse_program();
+    println!("{}", eval(Node::Program(program), &mut environment));
+    environment
+}
 
-        if !parser.errors.is_empty() {
-            print_parser_errors(parser.errors);
-            continue;
-        }
+// Runs the type checker over the input and checks its type annotations,
+// printing the diagnostics. Returns true when the program can run, syntax
+// errors are left to the interpreters
+pub fn typecheck(input: &str) -> bool {
+    let mut parser = Parser::new(Lexer::new(input));
+    parser.check_annotations = true;
+    let program = parser.parse_program();
+    for error in &parser.annotation_errors {
+        println!("type error: {}", error);
+    }
+    if !parser.errors.is_empty() {
+        return parser.annotation_errors.is_empty();
+    }
 
-        println!("{}", eval(Node::Program(program), &mut environment));
+    let diagnostics = check_program(&program);
+    for diagnostic in &diagnostics {
+        println!("type error: {}", diagnostic.render(input));
     }
+
+    diagnostics.is_empty() && parser.annotation_errors.is_empty()
 }
 
 fn print_parser_errors(errors: Vec<String>) {
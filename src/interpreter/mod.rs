@@ -1,3 +1,4 @@
 pub mod evaluator;
 pub mod interpreter_impl;
 pub mod parser;
+pub mod typechecker;
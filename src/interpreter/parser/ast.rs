@@ -7,7 +7,7 @@ pub enum Node {
 }
 
 // Root node of the AST
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Program {
     pub statements: Vec<Statement>,
 }
@@ -51,12 +51,15 @@ pub enum Expression {
     Integer(IntegerLiteral),
     Float(FloatLiteral),
     Boolean(BooleanLiteral),
+    Null(NullLiteral),
     String(StringLiteral),
     Prefix(PrefixExpression),
     Infix(InfixExpression),
     If(IfExpression),
     Function(FunctionLiteral),
     Call(CallExpression),
+    Array(ArrayLiteral),
+    Index(IndexExpression),
 }
 
 impl Display for Expression {
@@ -66,12 +69,15 @@ impl Display for Expression {
             Expression::Integer(integer) => integer.to_string(),
             Expression::Float(float) => float.to_string(),
             Expression::Boolean(boolean) => boolean.to_string(),
+            Expression::Null(null) => null.to_string(),
             Expression::String(string) => string.to_string(),
             Expression::Prefix(prefix_expression) => prefix_expression.to_string(),
             Expression::Infix(infinx_expression) => infinx_expression.to_string(),
             Expression::If(if_expression) => if_expression.to_string(),
             Expression::Function(function_literal) => function_literal.to_string(),
             Expression::Call(call_expression) => call_expression.to_string(),
+            Expression::Array(array_literal) => array_literal.to_string(),
+            Expression::Index(index_expression) => index_expression.to_string(),
         };
 
         write!(f, "{}", expression_stm)
@@ -86,12 +92,15 @@ impl Expression {
             Expression::Integer(integer) => integer.get_lexeme(),
             Expression::Float(float) => float.get_lexeme(),
             Expression::Boolean(boolean) => boolean.get_lexeme(),
+            Expression::Null(null) => null.get_lexeme(),
             Expression::String(string) => string.get_lexeme(),
             Expression::Prefix(prefix_expression) => prefix_expression.get_lexeme(),
             Expression::Infix(infinx_expression) => infinx_expression.get_lexeme(),
             Expression::If(if_expression) => if_expression.get_lexeme(),
             Expression::Function(function_literal) => function_literal.get_lexeme(),
             Expression::Call(call_expression) => call_expression.get_lexeme(),
+            Expression::Array(array_literal) => array_literal.get_lexeme(),
+            Expression::Index(index_expression) => index_expression.get_lexeme(),
         }
     }
 }
@@ -189,6 +198,28 @@ impl BooleanLiteral {
     }
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct NullLiteral {
+    token: Token,
+}
+
+impl Display for NullLiteral {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.token.lexeme)
+    }
+}
+
+impl NullLiteral {
+    #[cfg(test)]
+    pub fn get_lexeme(&self) -> String {
+        self.token.lexeme.clone()
+    }
+
+    pub fn new(token: Token) -> NullLiteral {
+        NullLiteral { token }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct StringLiteral {
     token: Token,
@@ -404,6 +435,66 @@ impl CallExpression {
         }
     }
 }
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArrayLiteral {
+    // [ token
+    token: Token,
+    pub elements: Vec<Expression>,
+}
+
+impl Display for ArrayLiteral {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let elements = self
+            .elements
+            .iter()
+            .map(|element| element.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        write!(f, "[{}]", elements)
+    }
+}
+
+impl ArrayLiteral {
+    #[cfg(test)]
+    pub fn get_lexeme(&self) -> String {
+        self.token.lexeme.clone()
+    }
+
+    pub fn new(token: Token, elements: Vec<Expression>) -> ArrayLiteral {
+        ArrayLiteral { token, elements }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IndexExpression {
+    // [ token
+    token: Token,
+    pub left: Box<Expression>,
+    pub index: Box<Expression>,
+}
+
+impl Display for IndexExpression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}[{}])", self.left, self.index)
+    }
+}
+
+impl IndexExpression {
+    #[cfg(test)]
+    pub fn get_lexeme(&self) -> String {
+        self.token.lexeme.clone()
+    }
+
+    pub fn new(token: Token, left: Expression, index: Expression) -> IndexExpression {
+        IndexExpression {
+            token,
+            left: Box::new(left),
+            index: Box::new(index),
+        }
+    }
+}
+
 // A statement expresses some action, but does not generate a value
 #[derive(Debug, PartialEq, Clone)]
 pub enum Statement {
@@ -442,24 +533,39 @@ pub struct LetStatement {
     // LET token
     pub token: Token,
     pub identifier: Identifier,
+    // Optional type after the name, let x: number = 1
+    pub annotation: Option<Identifier>,
     pub value: Expression,
 }
 
 impl Display for LetStatement {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{} {} = {}",
-            self.token.lexeme, self.identifier, self.value
-        )
+        match &self.annotation {
+            Some(annotation) => write!(
+                f,
+                "{} {}: {} = {}",
+                self.token.lexeme, self.identifier, annotation, self.value
+            ),
+            None => write!(
+                f,
+                "{} {} = {}",
+                self.token.lexeme, self.identifier, self.value
+            ),
+        }
     }
 }
 
 impl LetStatement {
-    pub fn new(token: Token, identifier: Identifier, value: Expression) -> LetStatement {
+    pub fn new(
+        token: Token,
+        identifier: Identifier,
+        annotation: Option<Identifier>,
+        value: Expression,
+    ) -> LetStatement {
         LetStatement {
             token,
             identifier,
+            annotation,
             value,
         }
     }
@@ -505,12 +611,15 @@ impl Display for ExpressionStatement {
             Expression::Integer(integer) => integer.to_string(),
             Expression::Float(float) => float.to_string(),
             Expression::Boolean(boolean) => boolean.to_string(),
+            Expression::Null(null) => null.to_string(),
             Expression::String(string) => string.to_string(),
             Expression::Prefix(prefix_expression) => prefix_expression.to_string(),
             Expression::Infix(infix_expression) => infix_expression.to_string(),
             Expression::If(if_expression) => if_expression.to_string(),
             Expression::Function(function_literal) => function_literal.to_string(),
             Expression::Call(call_expression) => call_expression.to_string(),
+            Expression::Array(array_literal) => array_literal.to_string(),
+            Expression::Index(index_expression) => index_expression.to_string(),
         };
 
         write!(f, "{}", expression_str)
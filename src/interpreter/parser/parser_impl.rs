@@ -3,15 +3,16 @@ use crate::common::{
         lexer_impl::Lexer,
         token::{Token, TokenType},
     },
-    precedences::{create_precedences, Precedence},
+    precedences::{create_precedences, right_operand_precedence, Precedence},
 };
 
 use crate::interpreter::parser::ast::Identifier;
 
 use super::ast::{
-    BlockStatement, BooleanLiteral, CallExpression, Expression, ExpressionStatement, FloatLiteral,
-    FunctionLiteral, IfExpression, InfixExpression, IntegerLiteral, LetStatement, PrefixExpression,
-    Program, ReturnStatement, Statement, StringLiteral, WhileStatement,
+    ArrayLiteral, BlockStatement, BooleanLiteral, CallExpression, Expression, ExpressionStatement,
+    FloatLiteral, FunctionLiteral, IfExpression, IndexExpression, InfixExpression, IntegerLiteral,
+    LetStatement, NullLiteral, PrefixExpression, Program, ReturnStatement, Statement,
+    StringLiteral, WhileStatement,
 };
 use std::collections::HashMap;
 
@@ -24,21 +25,29 @@ const ERROR: Token = Token {
     kind: TokenType::Error,
     lexeme: String::new(),
     line: 0,
+    column: 0,
 };
 
+// Types accepted in annotations
+const TYPE_NAMES: [&str; 6] = ["number", "string", "boolean", "null", "function", "array"];
+
 #[derive(Debug)]
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
     current_token: Option<Token>,
     peek_token: Option<Token>,
     pub errors: Vec<String>,
+    // Check type annotations against the literal they are initialized with
+    pub check_annotations: bool,
+    // Annotations that don't match, kept apart from syntax errors
+    pub annotation_errors: Vec<String>,
     prefix_parse_fns: HashMap<TokenType, PrefixParseFn>,
     infix_parse_fns: HashMap<TokenType, InfixParseFn>,
     precedences: HashMap<TokenType, Precedence>,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(mut lexer: Lexer<'a>) -> Parser {
+    pub fn new(mut lexer: Lexer<'a>) -> Parser<'a> {
         let current_token = Some(lexer.next_token());
         let peek_token = Some(lexer.next_token());
 
@@ -47,6 +56,8 @@ impl<'a> Parser<'a> {
             current_token,
             peek_token,
             errors: vec![],
+            check_annotations: false,
+            annotation_errors: vec![],
             prefix_parse_fns: HashMap::new(),
             infix_parse_fns: HashMap::new(),
             precedences: create_precedences(),
@@ -72,6 +83,8 @@ impl<'a> Parser<'a> {
             .insert(TokenType::False, parse_boolean_literal);
         self.prefix_parse_fns
             .insert(TokenType::String, parse_string_literal);
+        self.prefix_parse_fns
+            .insert(TokenType::Null, parse_null_literal);
         self.prefix_parse_fns
             .insert(TokenType::Minus, parse_prefix_expression);
         self.prefix_parse_fns
@@ -82,6 +95,10 @@ impl<'a> Parser<'a> {
             .insert(TokenType::If, parse_if_expression);
         self.prefix_parse_fns
             .insert(TokenType::Function, parse_function_literal);
+        self.prefix_parse_fns
+            .insert(TokenType::LeftBracket, parse_array_literal);
+        self.prefix_parse_fns
+            .insert(TokenType::Error, parse_lexer_error);
     }
 
     fn register_infix_functions(&mut self) {
@@ -93,6 +110,10 @@ impl<'a> Parser<'a> {
             .insert(TokenType::Star, parse_infix_expression);
         self.infix_parse_fns
             .insert(TokenType::Slash, parse_infix_expression);
+        self.infix_parse_fns
+            .insert(TokenType::Percent, parse_infix_expression);
+        self.infix_parse_fns
+            .insert(TokenType::StarStar, parse_infix_expression);
         self.infix_parse_fns
             .insert(TokenType::EqualEqual, parse_infix_expression);
         self.infix_parse_fns
@@ -109,6 +130,8 @@ impl<'a> Parser<'a> {
             .insert(TokenType::Equal, parse_infix_expression);
         self.infix_parse_fns
             .insert(TokenType::LeftParen, parse_call_expression);
+        self.infix_parse_fns
+            .insert(TokenType::LeftBracket, parse_index_expression);
     }
 
     fn peek_error(&mut self, expected_token: TokenType) {
@@ -252,6 +275,15 @@ impl<'a> Parser<'a> {
         let token = self.current_token.take();
 
         let identifier = Identifier::new(token?);
+
+        let annotation = if self.peek_token_is(TokenType::Colon) {
+            // Consume identifier
+            self.next_token();
+            Some(self.parse_type_annotation()?)
+        } else {
+            None
+        };
+
         if !self.expect_peek(TokenType::Equal) {
             return None;
         }
@@ -261,11 +293,53 @@ impl<'a> Parser<'a> {
         // Parse right expression
         let value = self.parse_expression(Precedence::Lowest)?;
 
+        if self.check_annotations {
+            if let Some(annotation) = &annotation {
+                self.check_annotation(&identifier, annotation, &value);
+            }
+        }
+
         Some(Statement::Let(LetStatement::new(
-            let_token?, identifier, value,
+            let_token?, identifier, annotation, value,
         )))
     }
 
+    // <type_annotation> ::= : <identifier>
+    fn parse_type_annotation(&mut self) -> Option<Identifier> {
+        // null is a keyword, so it is accepted as a type name explicitly
+        if self.peek_token_is(TokenType::Null) {
+            self.next_token();
+        } else if !self.expect_peek(TokenType::Identifier) {
+            return None;
+        }
+
+        Some(Identifier::new(self.current_token.take()?))
+    }
+
+    fn check_annotation(
+        &mut self,
+        identifier: &Identifier,
+        annotation: &Identifier,
+        value: &Expression,
+    ) {
+        let line = annotation.token.line;
+        if !TYPE_NAMES.contains(&annotation.name.as_str()) {
+            self.annotation_errors
+                .push(format!("Line {}: unknown type: {}", line, annotation.name));
+            return;
+        }
+
+        // Only literals have a type known before running the program
+        if let Some(value_type) = literal_type(value) {
+            if value_type != annotation.name {
+                self.annotation_errors.push(format!(
+                    "Line {}: type mismatch: {} is declared as {} but initialized with {}",
+                    line, identifier, annotation, value_type
+                ));
+            }
+        }
+    }
+
     // <return_statement> ::= return <expression>
     fn parse_return_statement(&mut self) -> Option<Statement> {
         let token = self.current_token.take();
@@ -345,6 +419,19 @@ impl<'a> Parser<'a> {
 }
 
 // Prefix functions
+// Type of an expression when it is a literal
+fn literal_type(expression: &Expression) -> Option<&'static str> {
+    match expression {
+        Expression::Integer(_) | Expression::Float(_) => Some("number"),
+        Expression::String(_) => Some("string"),
+        Expression::Boolean(_) => Some("boolean"),
+        Expression::Function(_) => Some("function"),
+        Expression::Array(_) => Some("array"),
+        Expression::Null(_) => Some("null"),
+        _ => None,
+    }
+}
+
 fn parse_identifier(parser: &mut Parser<'_>) -> Option<Expression> {
     parser
         .current_token
@@ -352,6 +439,13 @@ fn parse_identifier(parser: &mut Parser<'_>) -> Option<Expression> {
         .map(|token| Expression::Identifier(Identifier::new(token)))
 }
 
+// Error tokens carry the lexer message as lexeme
+fn parse_lexer_error(parser: &mut Parser<'_>) -> Option<Expression> {
+    let token = parser.current_token.take()?;
+    parser.add_error(token.lexeme, token.line);
+    None
+}
+
 fn parse_integer_literal(parser: &mut Parser<'_>) -> Option<Expression> {
     let Ok(value) = parser
         .current_token
@@ -388,6 +482,12 @@ fn parse_float_literal(parser: &mut Parser<'_>) -> Option<Expression> {
     )))
 }
 
+fn parse_null_literal(parser: &mut Parser<'_>) -> Option<Expression> {
+    Some(Expression::Null(NullLiteral::new(
+        parser.current_token.take()?,
+    )))
+}
+
 fn parse_boolean_literal(parser: &mut Parser<'_>) -> Option<Expression> {
     let value = match parser
         .current_token
@@ -449,14 +549,24 @@ fn parse_if_expression(parser: &mut Parser<'_>) -> Option<Expression> {
     let consequence = parser.parse_block_statement();
     let mut alternative: Option<BlockStatement> = None;
 
-    if parser.peek_token_is(TokenType::Else) {
+    if parser.peek_token_is(TokenType::Elif) {
         // Consume right brace
         parser.next_token();
-        if !parser.expect_peek(TokenType::LeftBrace) {
-            return None;
-        }
+        alternative = Some(parse_else_if(parser)?);
+    } else if parser.peek_token_is(TokenType::Else) {
+        // Consume right brace
+        parser.next_token();
+        if parser.peek_token_is(TokenType::If) {
+            // Consume else
+            parser.next_token();
+            alternative = Some(parse_else_if(parser)?);
+        } else {
+            if !parser.expect_peek(TokenType::LeftBrace) {
+                return None;
+            }
 
-        alternative = Some(parser.parse_block_statement());
+            alternative = Some(parser.parse_block_statement());
+        }
     }
 
     Some(Expression::If(IfExpression::new(
@@ -467,6 +577,25 @@ fn parse_if_expression(parser: &mut Parser<'_>) -> Option<Expression> {
     )))
 }
 
+// `elif` and `else if` both become an else block holding a single if
+fn parse_else_if(parser: &mut Parser<'_>) -> Option<BlockStatement> {
+    let if_token = Token {
+        kind: TokenType::If,
+        lexeme: "if".to_string(),
+        ..parser.current_token.clone()?
+    };
+    parser.current_token = Some(if_token.clone());
+
+    let if_expression = parse_if_expression(parser)?;
+
+    let mut block = BlockStatement::new(if_token.clone());
+    block.add_statement(Statement::Expression(ExpressionStatement::new(
+        if_token,
+        if_expression,
+    )));
+    Some(block)
+}
+
 // <function_literal> ::= fn (<parameters>?) <block_statement>
 fn parse_function_literal(parser: &mut Parser<'_>) -> Option<Expression> {
     let token = parser.current_token.take();
@@ -528,7 +657,8 @@ fn parse_parameters(parser: &mut Parser<'_>) -> Option<Vec<Identifier>> {
 // <infix_expression> ::= <expression> <infix_operator> <expression>
 fn parse_infix_expression(parser: &mut Parser<'_>, left: Expression) -> Option<Expression> {
     let operator = parser.current_token.as_ref().map(|t| t.lexeme.clone());
-    let precedence = parser.current_precedence();
+    let precedence =
+        right_operand_precedence(parser.current_token_kind(), parser.current_precedence());
     let token = parser.current_token.take();
 
     parser.next_token();
@@ -564,8 +694,13 @@ fn parse_call_expression(parser: &mut Parser<'_>, left: Expression) -> Option<Ex
 
 // <arguments> ::= <expression> (, <expression> )*
 fn parse_arguments(parser: &mut Parser<'_>) -> Option<Vec<Expression>> {
+    parse_expression_list(parser, TokenType::RightParen)
+}
+
+// <expression_list> ::= <expression> (, <expression> )* <end>
+fn parse_expression_list(parser: &mut Parser<'_>, end: TokenType) -> Option<Vec<Expression>> {
     let mut arguments = vec![];
-    if parser.peek_token_is(TokenType::RightParen) {
+    if parser.peek_token_is(end) {
         parser.next_token();
         return Some(arguments);
     }
@@ -589,9 +724,32 @@ fn parse_arguments(parser: &mut Parser<'_>) -> Option<Vec<Expression>> {
         arguments.push(argument);
     }
 
-    if !parser.expect_peek(TokenType::RightParen) {
+    if !parser.expect_peek(end) {
         return None;
     }
 
     Some(arguments)
 }
+
+// <array_literal> ::= [ <expression_list>? ]
+fn parse_array_literal(parser: &mut Parser<'_>) -> Option<Expression> {
+    let token = parser.current_token.take();
+    let elements = parse_expression_list(parser, TokenType::RightBracket)?;
+
+    Some(Expression::Array(ArrayLiteral::new(token?, elements)))
+}
+
+// <index_expression> ::= <expression> [ <expression> ]
+fn parse_index_expression(parser: &mut Parser<'_>, left: Expression) -> Option<Expression> {
+    let token = parser.current_token.take();
+    // Consume [
+    parser.next_token();
+
+    let index = parser.parse_expression(Precedence::Lowest)?;
+
+    if !parser.expect_peek(TokenType::RightBracket) {
+        return None;
+    }
+
+    Some(Expression::Index(IndexExpression::new(token?, left, index)))
+}
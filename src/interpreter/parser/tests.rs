@@ -228,6 +228,9 @@ fn operator_precedence_parsing() {
         ("a + b - c", "((a + b) - c)"),
         ("a * b * c", "((a * b) * c)"),
         ("a * b / c", "((a * b) / c)"),
+        ("a ** b ** c", "(a ** (b ** c))"),
+        ("-a ** b * c", "((-(a ** b)) * c)"),
+        ("a ** -b", "(a ** (-b))"),
         ("a + b / c", "(a + (b / c))"),
         ("a + b * c + d / e - f", "(((a + (b * c)) + (d / e)) - f)"),
         ("3 + 4\n-5 * 5", "(3 + 4)\n((-5) * 5)"),
@@ -280,6 +283,20 @@ fn if_expression_without_alternative() {
     check_parse_expression_statement(input, expected);
 }
 
+#[test]
+fn elif_matches_else_if() {
+    let expected = "if (x < y) {\nx\n} else {\nif (x > y) {\ny\n} else {\n0\n}\n}";
+
+    check_parse_expression_statement(
+        "if (x < y) {\n x\n } elif (x > y) {\n y\n } else {\n 0\n }",
+        expected,
+    );
+    check_parse_expression_statement(
+        "if (x < y) {\n x\n } else if (x > y) {\n y\n } else {\n 0\n }",
+        expected,
+    );
+}
+
 #[test]
 fn function_literal() {
     let input = "fn(x,\n y) { return 1\n }\n";
@@ -342,3 +359,67 @@ fn parse_while_loop_with_if_and_assignment() {
     let expected = "while true {\nif (a == 5) {\nreturn 5\n}\n(a = (a + 1))\n}";
     check_parse_expression_statement(input, expected);
 }
+
+#[test]
+fn array_literal_and_index() {
+    let tests = vec![
+        ("[1, 2 * 2, 3 + 3]", "[1, (2 * 2), (3 + 3)]"),
+        ("[]", "[]"),
+        ("myArray[1 + 1]", "(myArray[(1 + 1)])"),
+        ("a * [1, 2][b * c] * d", "((a * ([1, 2][(b * c)])) * d)"),
+        ("add(a[0], b[-1])", "add((a[0]), (b[(-1)]))"),
+    ];
+
+    for (input, expected) in tests {
+        check_parse_expression_statement(input, expected);
+    }
+}
+
+#[test]
+fn let_statement_with_annotation() {
+    let lexer = Lexer::new("let x: number = 1\nlet y = 2\n");
+    let mut parser = Parser::new(lexer);
+    let program = parser.parse_program();
+    check_parser_errors(&parser);
+
+    match &program.statements[0] {
+        Statement::Let(let_stmt) => {
+            let annotation = let_stmt.annotation.as_ref().expect("annotation missing");
+            assert_eq!(annotation.name, "number");
+        }
+        stmt => panic!("stmt is not a LetStatement. Got={:?}", stmt),
+    }
+    match &program.statements[1] {
+        Statement::Let(let_stmt) => assert_eq!(let_stmt.annotation, None),
+        stmt => panic!("stmt is not a LetStatement. Got={:?}", stmt),
+    }
+    assert_eq!(program.to_string(), "let x: number = 1\nlet y = 2");
+}
+
+#[test]
+fn checked_annotations() {
+    let tests = [
+        ("let x: number = \"a\"", 1),
+        ("let x: string = \"a\"", 0),
+        ("let x: number = 1.5", 0),
+        ("let x: boolean = y", 0),
+        ("let x: text = 1", 1),
+        ("let x: number = null", 1),
+        ("let x: null = null", 0),
+    ];
+
+    for (input, errors) in tests {
+        let mut parser = Parser::new(Lexer::new(input));
+        parser.check_annotations = true;
+        parser.parse_program();
+
+        check_parser_errors(&parser);
+        assert_eq!(parser.annotation_errors.len(), errors, "input: {}", input);
+    }
+
+    // Annotations are ignored unless checking is enabled
+    let mut parser = Parser::new(Lexer::new("let x: number = \"a\""));
+    parser.parse_program();
+    check_parser_errors(&parser);
+    assert!(parser.annotation_errors.is_empty());
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+
+use crate::common::{diagnostic::Diagnostic, lexer::token::Token};
+
+use super::parser::ast::{BlockStatement, Expression, Program, Statement};
+
+// Types known before running the program, anything else is Unknown so only
+// certain errors are reported
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Number,
+    String,
+    Boolean,
+    Unknown,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let type_str = match self {
+            Type::Number => "number",
+            Type::String => "string",
+            Type::Boolean => "boolean",
+            Type::Unknown => "unknown",
+        };
+        write!(f, "{}", type_str)
+    }
+}
+
+// Checks the program for operators applied to values of the wrong type
+pub fn check_program(program: &Program) -> Vec<Diagnostic> {
+    let mut checker = TypeChecker {
+        diagnostics: vec![],
+    };
+    checker.check_statements(&program.statements);
+
+    checker.diagnostics
+}
+
+struct TypeChecker {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl TypeChecker {
+    fn check_statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.check_statement(statement);
+        }
+    }
+
+    fn check_block(&mut self, block: &BlockStatement) {
+        self.check_statements(&block.statements);
+    }
+
+    fn check_statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Let(let_statement) => {
+                self.infer(&let_statement.value);
+            }
+            Statement::Return(return_statement) => {
+                self.infer(&return_statement.value);
+            }
+            Statement::Expression(expression_statement) => {
+                self.infer(&expression_statement.expression);
+            }
+            Statement::While(while_statement) => {
+                self.infer(&while_statement.condition);
+                self.check_block(&while_statement.body);
+            }
+        }
+    }
+
+    fn infer(&mut self, expression: &Expression) -> Type {
+        match expression {
+            Expression::Integer(_) | Expression::Float(_) => Type::Number,
+            Expression::String(_) => Type::String,
+            Expression::Boolean(_) => Type::Boolean,
+            Expression::Null(_) => Type::Unknown,
+            Expression::Identifier(_) => Type::Unknown,
+            Expression::Prefix(prefix) => {
+                let right = self.infer(&prefix.right);
+                match prefix.operator.as_str() {
+                    "!" => Type::Boolean,
+                    "-" => {
+                        if right != Type::Number && right != Type::Unknown {
+                            self.report(&prefix.token, format!("cannot apply - to {}", right));
+                        }
+                        Type::Number
+                    }
+                    _ => Type::Unknown,
+                }
+            }
+            Expression::Infix(infix) => {
+                let left = self.infer(&infix.left);
+                let right = self.infer(&infix.right);
+                self.infer_infix(&infix.token, &infix.operator, left, right)
+            }
+            Expression::If(if_expression) => {
+                self.infer(&if_expression.condition);
+                self.check_block(&if_expression.consequence);
+                if let Some(alternative) = &if_expression.alternative {
+                    self.check_block(alternative);
+                }
+                Type::Unknown
+            }
+            Expression::Function(function) => {
+                self.check_block(&function.body);
+                Type::Unknown
+            }
+            Expression::Call(call) => {
+                self.infer(&call.function);
+                for argument in &call.arguments {
+                    self.infer(argument);
+                }
+                Type::Unknown
+            }
+            Expression::Array(array) => {
+                for element in &array.elements {
+                    self.infer(element);
+                }
+                Type::Unknown
+            }
+            Expression::Index(index) => {
+                self.infer(&index.left);
+                self.infer(&index.index);
+                Type::Unknown
+            }
+        }
+    }
+
+    fn infer_infix(&mut self, token: &Token, operator: &str, left: Type, right: Type) -> Type {
+        let known = left != Type::Unknown && right != Type::Unknown;
+        match operator {
+            "+" => {
+                if !known {
+                    return Type::Unknown;
+                }
+                match (left, right) {
+                    (Type::Number, Type::Number) => Type::Number,
+                    (Type::String, Type::String) => Type::String,
+                    _ => {
+                        self.report_operands(token, operator, left, right);
+                        Type::Unknown
+                    }
+                }
+            }
+            // Strings are ordered too
+            "<" | "<=" | ">" | ">=" if left == Type::String && right == Type::String => {
+                Type::Boolean
+            }
+            "-" | "*" | "/" | "%" | "**" | "<" | "<=" | ">" | ">=" => {
+                let is_number = |operand| operand == Type::Number || operand == Type::Unknown;
+                if !is_number(left) || !is_number(right) {
+                    self.report_operands(token, operator, left, right);
+                }
+
+                match operator {
+                    "-" | "*" | "/" | "%" | "**" => Type::Number,
+                    _ => Type::Boolean,
+                }
+            }
+            "==" | "!=" => Type::Boolean,
+            // Assignment takes the type of the value
+            "=" => right,
+            _ => Type::Unknown,
+        }
+    }
+
+    fn report_operands(&mut self, token: &Token, operator: &str, left: Type, right: Type) {
+        self.report(
+            token,
+            format!("cannot apply {} to {} and {}", operator, left, right),
+        );
+    }
+
+    fn report(&mut self, token: &Token, message: String) {
+        self.diagnostics
+            .push(Diagnostic::new(message, token.line, token.column));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::check_program;
+    use crate::{
+        common::{diagnostic::Diagnostic, lexer::lexer_impl::Lexer},
+        interpreter::parser::parser_impl::Parser,
+    };
+
+    fn check(input: &str) -> Vec<Diagnostic> {
+        let mut parser = Parser::new(Lexer::new(input));
+        let program = parser.parse_program();
+        assert!(
+            parser.errors.is_empty(),
+            "parser errors: {:?}",
+            parser.errors
+        );
+
+        check_program(&program)
+    }
+
+    #[test]
+    fn flags_mismatches() {
+        let tests = [
+            ("\"a\" - 1", "Line 1:5: cannot apply - to string and number"),
+            ("1 < \"b\"", "Line 1:3: cannot apply < to number and string"),
+            (
+                "let x = 1\nx + (true + 1)",
+                "Line 2:11: cannot apply + to boolean and number",
+            ),
+            ("-\"a\"", "Line 1:1: cannot apply - to string"),
+            (
+                "fn() { return \"a\" * 2\n }",
+                "Line 1:19: cannot apply * to string and number",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let diagnostics = check(input);
+            assert_eq!(diagnostics.len(), 1, "input: {}", input);
+            assert_eq!(diagnostics[0].to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn accepts_valid_and_unknown_operands() {
+        let tests = [
+            "1 + 2",
+            "\"a\" + \"b\"",
+            "1 + 2.5 * 3 > 4",
+            "let x = \"a\"\nx - 1",
+            "1 == \"a\"",
+            "!5",
+            "\"a\" < \"b\"",
+        ];
+
+        for input in tests {
+            assert_eq!(check(input), vec![], "input: {}", input);
+        }
+    }
+}
@@ -0,0 +1,6 @@
+pub mod common;
+pub mod interpreter;
+pub mod vm;
+
+#[cfg(test)]
+mod parity_tests;
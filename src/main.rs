@@ -1,17 +1,20 @@
 use clap::{arg, command, ArgAction};
-use interpreter::interpreter_impl::interpret_ast;
+use interpreter::interpreter::evaluator::object::Environment;
+use interpreter::interpreter::interpreter_impl::{interpret_ast, typecheck};
+use interpreter::vm::vm_impl::{
+    compile_and_run, disassemble, dump_bytecode_for, dump_symbols, eval_expression,
+    InterpretResult, RunOptions,
+};
 use repl::start_ast;
 use repl::start_vm;
-use std::env;
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
-use vm::vm_impl::compile_and_run;
+use std::process;
 
-mod common;
-mod interpreter;
 mod repl;
-mod vm;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 enum InterpreterType {
     VM,
@@ -24,6 +27,64 @@ fn main() {
             arg!(-a --ast "Use the AST interpreter instead of the default VM interpreter.")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            arg!(--"print-last" "Print the final value of the script after running it.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--typecheck "Check the script for type errors before running it.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"dump-symbols" "Print the globals and functions declared by the script instead of running it.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--werror "Treat compiler warnings, like unused variables, as errors.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--profile "Print how many times each opcode ran to stderr after running the script.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(-i --interactive "Start the REPL with the globals of the file after running it.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"dump-bytecode-for" <FUNCTION> "Print the bytecode of the named function instead of running the script.")
+                .required(false),
+        )
+        .arg(
+            arg!(--disassemble "Print the bytecode of the script and its functions instead of running it.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--sandbox "Run the script without the clock builtins and with limits on steps, call depth and stack size.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"max-steps" <STEPS> "Interrupt the script after running this many instructions.")
+                .required(false)
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            arg!(--"max-stack" <VALUES> "Fail with a stack overflow when the stack holds more than this many values.")
+                .required(false)
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            arg!(--trace "Print each instruction and the stack to stderr before running it.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--stats "Print the instructions, constants and approximate bytes of each compiled function to stderr.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"repl-eval" <EXPR> "Evaluate a single expression with the VM and print its value instead of reading a file.")
+                .required(false),
+        )
         .arg(
             arg!([file] "The source file to interpret.").value_parser(clap::value_parser!(PathBuf)),
         )
@@ -36,18 +97,71 @@ fn main() {
         InterpreterType::VM
     };
 
+    if let Some(expression) = matches.get_one::<String>("repl-eval") {
+        exit_with(eval_expression(expression.clone()));
+        return;
+    }
+
     // Determine if a file was provided
     if let Some(file) = matches.get_one::<PathBuf>("file") {
         // Read the file content
         let code = fs::read_to_string(file).expect("Failed to read the file");
+        if matches.get_flag("typecheck") && !typecheck(&code) {
+            exit_with(InterpretResult::CompileError);
+            return;
+        }
+        if matches.get_flag("dump-symbols") {
+            exit_with(dump_symbols(code));
+            return;
+        }
+        if matches.get_flag("disassemble") {
+            exit_with(disassemble(code));
+            return;
+        }
+        if let Some(function) = matches.get_one::<String>("dump-bytecode-for") {
+            exit_with(dump_bytecode_for(code, function));
+            return;
+        }
+        let interactive = matches.get_flag("interactive");
         match interpreter_type {
-            InterpreterType::VM => compile_and_run(code),
-            InterpreterType::AST => interpret_ast(code),
+            InterpreterType::VM => {
+                let options = RunOptions {
+                    print_last: matches.get_flag("print-last"),
+                    warnings_as_errors: matches.get_flag("werror"),
+                    profile: matches.get_flag("profile"),
+                    sandbox: matches.get_flag("sandbox"),
+                    max_steps: matches.get_one::<usize>("max-steps").copied(),
+                    max_stack: matches.get_one::<usize>("max-stack").copied(),
+                    trace: matches.get_flag("trace"),
+                    stats: matches.get_flag("stats"),
+                };
+                let (result, globals) = compile_and_run(code, &options);
+                if interactive {
+                    start_vm(globals);
+                    return;
+                }
+                exit_with(result);
+            }
+            InterpreterType::AST => {
+                let environment = interpret_ast(code);
+                if interactive {
+                    start_ast(environment);
+                }
+            }
         };
     } else {
         match interpreter_type {
-            InterpreterType::VM => start_vm(),
-            InterpreterType::AST => start_ast(),
+            InterpreterType::VM => start_vm(HashMap::new()),
+            InterpreterType::AST => start_ast(Environment::new()),
         }
     }
 }
+
+// Exit codes used by Crafting Interpreters
+fn exit_with(result: InterpretResult) {
+    match result {
+        InterpretResult::Ok => (),
+        InterpretResult::CompileError => process::exit(65),
+        InterpretResult::RuntimeError => process::exit(70),
+    }
+}
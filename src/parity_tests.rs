@@ -0,0 +1,123 @@
+// Runs the same programs through the AST interpreter and the VM, checking both
+// backends agree on the printed result
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, mem::take, rc::Rc};
+
+    use crate::{
+        common::lexer::lexer_impl::Lexer,
+        interpreter::{
+            evaluator::{
+                evaluator_impl::eval,
+                object::{Environment, Object},
+            },
+            parser::{ast::Node, parser_impl::Parser},
+        },
+        vm::{
+            compiler::Compiler,
+            object::FunctionType,
+            vm_impl::{InterpretResult, VirtualMachine},
+        },
+    };
+
+    fn run_ast(input: &str) -> String {
+        let mut parser = Parser::new(Lexer::new(input));
+        let program = parser.parse_program();
+        assert!(
+            parser.errors.is_empty(),
+            "parser errors: {:?}",
+            parser.errors
+        );
+
+        match eval(Node::Program(program), &mut Environment::new()) {
+            Object::Error(_) => "error".to_string(),
+            result => result.to_string(),
+        }
+    }
+
+    fn run_vm(input: &str) -> String {
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        assert_eq!(
+            compiler.compile(),
+            InterpretResult::Ok,
+            "compiler errors: {:?}",
+            compiler.errors
+        );
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        match vm.interpret() {
+            InterpretResult::Ok => vm.stack.last().map(|value| value.to_string()).unwrap(),
+            _ => "error".to_string(),
+        }
+    }
+
+    fn check_parity(input: &str, expected: &str) {
+        assert_eq!(
+            run_ast(input),
+            expected,
+            "AST interpreter, input: {}",
+            input
+        );
+        assert_eq!(run_vm(input), expected, "VM, input: {}", input);
+    }
+
+    #[test]
+    fn array_literals() {
+        check_parity("[]", "[]");
+        check_parity("[1, 2 * 3, \"a\"]", "[1, 6, a]");
+        check_parity("[[1, 2], [3]]", "[[1, 2], [3]]");
+    }
+
+    #[test]
+    fn array_indexing() {
+        check_parity("[1, 2, 3][0]", "1");
+        check_parity("[1, 2, 3][1 + 1]", "3");
+        check_parity("let a = [4, 5, 6]\na[1] * 2", "10");
+        check_parity("[[1, 2], [3]][0][1]", "2");
+    }
+
+    #[test]
+    fn modulo() {
+        check_parity("10 % 3", "1");
+        check_parity("2 + 10 % 4 * 3", "8");
+        check_parity("5 % 0", "error");
+    }
+
+    #[test]
+    fn integer_and_float_arithmetic() {
+        check_parity("2 + 3", "5");
+        check_parity("10 / 5", "2");
+        check_parity("10 / 4", "2.5");
+        check_parity("1.5 + 1.5", "3");
+    }
+
+    #[test]
+    fn power() {
+        check_parity("2 ** 10", "1024");
+        check_parity("2 ** 3 ** 2", "512");
+        check_parity("-2 ** 2", "-4");
+        check_parity("2 ** -1", "0.5");
+    }
+
+    #[test]
+    fn string_comparison() {
+        check_parity("\"apple\" < \"banana\"", "true");
+        check_parity("\"b\" > \"a\"", "true");
+        check_parity("\"b\" <= \"a\"", "false");
+        check_parity("\"a\" < 1", "error");
+    }
+
+    #[test]
+    fn negative_index() {
+        check_parity("[1, 2, 3][-1]", "3");
+        check_parity("[1, 2, 3][-3]", "1");
+    }
+
+    #[test]
+    fn index_out_of_bounds() {
+        check_parity("[1, 2, 3][3]", "error");
+        check_parity("[1, 2, 3][-4]", "error");
+        check_parity("[][0]", "error");
+    }
+}
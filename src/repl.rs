@@ -1,25 +1,54 @@
-use crate::{
-    common::lexer::lexer_impl::Lexer,
+use interpreter::{
+    common::lexer::{lexer_impl::Lexer, token::TokenType},
     interpreter::{
         evaluator::{evaluator_impl::eval, object::Environment},
         parser::{ast::Node, parser_impl::Parser},
     },
     vm::{
-        compiler::Compiler,
+        chunk::Value,
+        compiler::{Compiler, SymbolKind},
         object::FunctionType,
-        vm_impl::{InterpretResult, VirtualMachine},
+        vm_impl::{sorted_globals, InterpretResult, VirtualMachine},
     },
 };
 use std::{
     cell::RefCell,
-    io::{self, Write},
+    io::{self, IsTerminal, Write},
     mem::take,
     rc::Rc,
 };
 use std::{collections::HashMap, env};
 
 const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = "... ";
 const EXIT_COMMAND: &str = "exit";
+const RESET_COMMAND: &str = ".reset";
+// Global holding the value of the last expression echoed by the VM REPL
+const LAST_VALUE: &str = "_";
+// Terminals in bracketed paste mode wrap pasted text in these
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+
+// Asks the terminal to mark pasted text while the REPL runs, so a pasted
+// snippet is one submission however its lines end
+struct BracketedPaste;
+
+impl BracketedPaste {
+    fn enable() -> Option<BracketedPaste> {
+        if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+            return None;
+        }
+        print!("\x1b[?2004h");
+        Some(BracketedPaste)
+    }
+}
+
+impl Drop for BracketedPaste {
+    fn drop(&mut self) {
+        print!("\x1b[?2004l");
+        let _ = io::stdout().flush();
+    }
+}
 
 fn greetings() {
     let username = match env::var("USER") {
@@ -32,19 +61,13 @@ fn greetings() {
     println!("Hola, {}! Bienvenido al intérprete.", username);
 }
 
-pub fn start_ast() {
+// Starts the REPL with the given environment, a script's when running interactively
+pub fn start_ast(mut environment: Environment) {
     greetings();
+    let _paste = BracketedPaste::enable();
 
-    let mut environment = Environment::new();
-    loop {
-        let mut input = String::new();
-        print!("{}", PROMPT);
-        io::stdout().flush().unwrap();
-
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Error reading line");
-
+    // End of input
+    while let Some(input) = read_submission() {
         let lexer = Lexer::new(&input);
         let mut parser = Parser::new(lexer);
 
@@ -63,43 +86,131 @@ pub fn start_ast() {
     }
 }
 
-pub fn start_vm() {
+// Starts the REPL with the given globals, a script's when running interactively
+pub fn start_vm(mut globals: HashMap<Rc<str>, Value>) {
     greetings();
+    let _paste = BracketedPaste::enable();
 
-    // Empty globals
-    let mut globals = HashMap::new();
+    globals.entry(Rc::from(LAST_VALUE)).or_insert(Value::Null);
 
-    loop {
-        let mut input = String::new();
-        print!("{}", PROMPT);
-        io::stdout().flush().unwrap();
-
-        io::stdin()
-            .read_line(&mut input)
-            .expect("error reading line");
+    // End of input
+    while let Some(input) = read_submission() {
+        // Forgets every global of the session
+        if input.trim() == RESET_COMMAND {
+            globals = HashMap::from([(Rc::from(LAST_VALUE), Value::Null)]);
+            continue;
+        }
 
         let lexer = Lexer::new(&input);
-        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
-
-        if !compiler.compile_one_statement() {
-            print_parser_errors(compiler.errors);
+        let mut compiler = Compiler::new_with_symbols(
+            Rc::new(RefCell::new(lexer)),
+            FunctionType::Script,
+            session_symbols(&globals),
+        );
+        compiler.allow_global_redefinition = true;
+
+        if compiler.compile_submission() == InterpretResult::CompileError {
+            for error in &compiler.errors {
+                println!("{}", error.render(&input));
+            }
             continue;
         }
 
         let mut vm =
             VirtualMachine::new_with_globals(take(&mut compiler.function), globals.clone());
 
-        // Run the input
-        if vm.interpret() == InterpretResult::Ok {
-            // Update with current globals
-            globals = vm.globals;
-            if let Some(value) = vm.stack.last() {
-                println!("{}", value);
+        // Run the input, globals are only kept when the line succeeds
+        match vm.interpret() {
+            InterpretResult::Ok => {
+                globals = vm.globals;
+                // Slot 0 holds the script, only a final expression leaves its value above it
+                if let Some(value) = vm.stack.get(1) {
+                    println!("{}", value);
+                    globals.insert(Rc::from(LAST_VALUE), value.clone());
+                }
+            }
+            _ => match vm.runtime_error {
+                Some(message) => println!("runtime error: {}", message),
+                None => println!("runtime error"),
+            },
+        }
+    }
+}
+
+// Reads the next submission, which goes on over the following lines while it
+// has unclosed brackets or strings, so pasted functions arrive whole. Text
+// pasted in bracketed paste mode is a single submission. Returns None at the
+// end of input
+fn read_submission() -> Option<String> {
+    let mut input = String::new();
+    print!("{}", PROMPT);
+    loop {
+        io::stdout().flush().unwrap();
+        let read = io::stdin()
+            .read_line(&mut input)
+            .expect("error reading line");
+        if read == 0 {
+            return (!input.is_empty()).then_some(input);
+        }
+        if input.starts_with(PASTE_START) {
+            return Some(read_paste(input));
+        }
+        if !is_incomplete(&input) {
+            return Some(input);
+        }
+        print!("{}", CONTINUATION_PROMPT);
+    }
+}
+
+// Reads up to the end of the paste that input starts, without the markers
+fn read_paste(mut input: String) -> String {
+    while !input.contains(PASTE_END) {
+        let read = io::stdin()
+            .read_line(&mut input)
+            .expect("error reading line");
+        if read == 0 {
+            break;
+        }
+    }
+    input
+        .replacen(PASTE_START, "", 1)
+        .replacen(PASTE_END, "", 1)
+}
+
+fn is_incomplete(input: &str) -> bool {
+    let mut lexer = Lexer::new(input);
+    let mut depth = 0;
+    loop {
+        let token = lexer.next_token();
+        match token.kind {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth -= 1,
+            TokenType::Error
+                if token.lexeme == "unterminated string"
+                    || token.lexeme == "unterminated comment" =>
+            {
+                return true
             }
+            TokenType::EOF => return depth > 0,
+            _ => (),
         }
     }
 }
 
+// Globals defined by earlier lines, in a stable order
+fn session_symbols(globals: &HashMap<Rc<str>, Value>) -> Vec<(String, SymbolKind)> {
+    sorted_globals(globals)
+        .into_iter()
+        .map(|(name, value)| {
+            let kind = match value {
+                Value::Function(_) | Value::Closure(_) => SymbolKind::Function,
+                _ => SymbolKind::Variable,
+            };
+            (name.to_string(), kind)
+        })
+        .collect()
+}
+
 fn print_parser_errors(errors: Vec<String>) {
     for error in errors {
         println!("{}", error);
@@ -0,0 +1,81 @@
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    mem::take,
+    rc::Rc,
+};
+
+use crate::common::lexer::lexer_impl::Lexer;
+
+use super::{
+    compiler::Compiler,
+    object::{Function, FunctionType},
+    vm_impl::{InterpretResult, VirtualMachine},
+};
+
+// Compiled scripts by a hash of their source, for hosts running the same
+// scripts many times. Every run of a script shares its compiled function
+#[derive(Debug, Default)]
+pub struct CompileCache {
+    // Scripts whose sources share a hash, kept with their source to tell
+    // them apart
+    scripts: HashMap<u64, Vec<(String, Rc<Function>)>>,
+    compiles: usize,
+}
+
+impl CompileCache {
+    pub fn new() -> CompileCache {
+        CompileCache::default()
+    }
+
+    // Runs the source in the VM, compiling it only the first time. Scripts
+    // that don't compile aren't cached
+    pub fn run(&mut self, source: &str, vm: &mut VirtualMachine) -> InterpretResult {
+        let hash = hash_source(source);
+        let cached = self.scripts.get(&hash).and_then(|scripts| {
+            scripts
+                .iter()
+                .find(|(cached, _)| cached == source)
+                .map(|(_, function)| function.clone())
+        });
+        let function = match cached {
+            Some(function) => function,
+            None => {
+                let Some(function) = self.compile(source) else {
+                    return InterpretResult::CompileError;
+                };
+                let function = Rc::new(function);
+                self.scripts
+                    .entry(hash)
+                    .or_default()
+                    .push((source.to_string(), function.clone()));
+                function
+            }
+        };
+
+        vm.load_shared(function);
+        vm.interpret()
+    }
+
+    // Number of times a source was compiled
+    pub fn compiles(&self) -> usize {
+        self.compiles
+    }
+
+    fn compile(&mut self, source: &str) -> Option<Function> {
+        self.compiles += 1;
+        let lexer = Lexer::new(source);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        match compiler.compile() {
+            InterpretResult::CompileError => None,
+            _ => Some(take(&mut compiler.function)),
+        }
+    }
+}
+
+fn hash_source(source: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    hasher.finish()
+}
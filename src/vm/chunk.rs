@@ -1,6 +1,6 @@
-use std::fmt;
+use std::{cell::RefCell, cmp::Ordering, fmt, rc::Rc};
 
-use super::object::Function;
+use super::object::{Closure, Function, Generator, Map, NativeFunction, ValueIterator};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum OpCode {
@@ -29,38 +29,235 @@ pub enum OpCode {
     GetLocal(usize),
     SetLocal(usize),
     JumpIfFalse(usize),
+    // Jumps leaving the null on the stack, for null-safe access
+    JumpIfNull(usize),
     Jump(usize),
     Loop(usize),
+    Closure(usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    CloseUpvalue,
+    BuildArray(usize),
+    Index,
+    SetIndex,
+    Unpack(usize),
+    Yield,
+    BuildMap(usize),
+    HasEntry,
+    Entry,
+    UnpackMap(usize),
+    Modulo,
+    Power,
+    // Calls the global or builtin named by the constant with the values on
+    // top of the stack, the first one being the receiver of a method call
+    Invoke(usize, usize),
+}
+
+// Names of the opcodes by index
+pub const OPCODE_NAMES: [&str; OpCode::COUNT] = [
+    "Constant",
+    "Null",
+    "True",
+    "False",
+    "Not",
+    "Negate",
+    "Equal",
+    "NotEqual",
+    "Greater",
+    "GreaterEqual",
+    "Less",
+    "LessEqual",
+    "Add",
+    "Subtract",
+    "Multiply",
+    "Divide",
+    "Pop",
+    "DefineGlobal",
+    "GetGlobal",
+    "SetGlobal",
+    "GetLocal",
+    "SetLocal",
+    "Jump",
+    "JumpIfFalse",
+    "JumpIfNull",
+    "Loop",
+    "Call",
+    "Return",
+    "Closure",
+    "GetUpvalue",
+    "SetUpvalue",
+    "CloseUpvalue",
+    "BuildArray",
+    "Index",
+    "SetIndex",
+    "Unpack",
+    "Yield",
+    "BuildMap",
+    "HasEntry",
+    "Entry",
+    "UnpackMap",
+    "Modulo",
+    "Power",
+    "Invoke",
+];
+
+impl OpCode {
+    pub const COUNT: usize = 44;
+
+    // Distinct number for each opcode, for tables indexed by opcode. Without
+    // a wildcard so new opcodes have to be numbered
+    pub fn index(&self) -> usize {
+        match self {
+            OpCode::Constant(_) => 0,
+            OpCode::Null => 1,
+            OpCode::True => 2,
+            OpCode::False => 3,
+            OpCode::Not => 4,
+            OpCode::Negate => 5,
+            OpCode::Equal => 6,
+            OpCode::NotEqual => 7,
+            OpCode::Greater => 8,
+            OpCode::GreaterEqual => 9,
+            OpCode::Less => 10,
+            OpCode::LessEqual => 11,
+            OpCode::Add => 12,
+            OpCode::Subtract => 13,
+            OpCode::Multiply => 14,
+            OpCode::Divide => 15,
+            OpCode::Pop => 16,
+            OpCode::DefineGlobal(_) => 17,
+            OpCode::GetGlobal(_) => 18,
+            OpCode::SetGlobal(_) => 19,
+            OpCode::GetLocal(_) => 20,
+            OpCode::SetLocal(_) => 21,
+            OpCode::Jump(_) => 22,
+            OpCode::JumpIfFalse(_) => 23,
+            OpCode::JumpIfNull(_) => 24,
+            OpCode::Loop(_) => 25,
+            OpCode::Call(_) => 26,
+            OpCode::Return => 27,
+            OpCode::Closure(_) => 28,
+            OpCode::GetUpvalue(_) => 29,
+            OpCode::SetUpvalue(_) => 30,
+            OpCode::CloseUpvalue => 31,
+            OpCode::BuildArray(_) => 32,
+            OpCode::Index => 33,
+            OpCode::SetIndex => 34,
+            OpCode::Unpack(_) => 35,
+            OpCode::Yield => 36,
+            OpCode::BuildMap(_) => 37,
+            OpCode::HasEntry => 38,
+            OpCode::Entry => 39,
+            OpCode::UnpackMap(_) => 40,
+            OpCode::Modulo => 41,
+            OpCode::Power => 42,
+            OpCode::Invoke(_, _) => 43,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
+    Integer(i64),
     Number(f64),
     Boolean(bool),
-    String(String),
+    // Shared text, equal strings made by the VM point to the same one
+    String(Rc<str>),
     Function(Function),
+    Closure(Closure),
+    NativeFunction(NativeFunction),
+    Array(Vec<Value>),
+    Map(Map),
+    Generator(Rc<RefCell<Generator>>),
+    Iterator(Rc<RefCell<ValueIterator>>),
     Null,
 }
 
+impl Value {
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::Integer(_) | Value::Number(_))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Integer(_) => "integer",
+            Value::Number(_) => "number",
+            Value::Boolean(_) => "boolean",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::Closure(_) | Value::NativeFunction(_) => "function",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+            Value::Generator(_) => "generator",
+            Value::Iterator(_) => "iterator",
+            Value::Null => "null",
+        }
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Value::Integer(n) => write!(f, "{}", n),
             Value::Number(n) => write!(f, "{}", n),
             Value::Boolean(b) => write!(f, "{}", b),
             Value::String(s) => write!(f, "{}", s),
-            Value::Function(_) => write!(f, "function"),
+            Value::Function(_) | Value::Closure(_) => write!(f, "function"),
+            Value::NativeFunction(native) => write!(f, "<native {}>", native.name),
+            Value::Generator(generator) => {
+                write!(
+                    f,
+                    "<generator {}>",
+                    generator.borrow().closure.function.name
+                )
+            }
+            Value::Iterator(_) => write!(f, "<iterator>"),
+            Value::Array(elements) => {
+                let elements = elements
+                    .iter()
+                    .map(|element| element.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(f, "[{}]", elements)
+            }
+            Value::Map(map) => {
+                let entries = map
+                    .entries()
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(f, "{{{}}}", entries)
+            }
             Value::Null => write!(f, "null"),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Chunk {
     pub code: Vec<OpCode>,
     pub constants: Vec<Value>,
     lines: Vec<(usize, usize)>,
 }
 
+// Size of a chunk, for diagnostics
+#[derive(Debug, PartialEq)]
+pub struct ChunkSummary {
+    pub instructions: usize,
+    pub constants: usize,
+    pub bytes: usize,
+}
+
+impl fmt::Display for ChunkSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} instructions, {} constants, {} bytes",
+            self.instructions, self.constants, self.bytes
+        )
+    }
+}
+
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
@@ -83,6 +280,29 @@ impl Chunk {
         }
     }
 
+    pub fn remove_last(&mut self) -> Option<OpCode> {
+        let byte = self.code.pop()?;
+        if let Some((_, last_count)) = self.lines.last_mut() {
+            *last_count -= 1;
+            if *last_count == 0 {
+                self.lines.pop();
+            }
+        }
+        Some(byte)
+    }
+
+    // Source line of the instruction, decoding the run-length lines
+    pub fn get_line(&self, index: usize) -> Option<usize> {
+        let mut end = 0;
+        for (line, count) in &self.lines {
+            end += count;
+            if index < end {
+                return Some(*line);
+            }
+        }
+        None
+    }
+
     pub fn get(&self, index: usize) -> Option<&OpCode> {
         self.code.get(index)
     }
@@ -92,17 +312,168 @@ impl Chunk {
         self.constants.len() - 1
     }
 
-    pub fn get_constant(&mut self, index: usize) -> Option<&Value> {
+    pub fn get_constant(&self, index: usize) -> Option<&Value> {
         self.constants.get(index)
     }
+
+    // Approximate memory used by the instructions, constants and lines, plus
+    // the text of string constants. Functions defined in the chunk count as
+    // one constant, their own chunks aren't included
+    pub fn byte_size(&self) -> usize {
+        let text: usize = self
+            .constants
+            .iter()
+            .map(|constant| match constant {
+                Value::String(string) => string.len(),
+                _ => 0,
+            })
+            .sum();
+        self.code.len() * size_of::<OpCode>()
+            + self.constants.len() * size_of::<Value>()
+            + self.lines.len() * size_of::<(usize, usize)>()
+            + text
+    }
+
+    pub fn summary(&self) -> ChunkSummary {
+        ChunkSummary {
+            instructions: self.code.len(),
+            constants: self.constants.len(),
+            bytes: self.byte_size(),
+        }
+    }
+
+    // Same instructions and constants, whatever lines they come from. Floats
+    // compare by their bits, 0.0 and -0.0 aren't the same constant
+    pub fn same_code(&self, other: &Chunk) -> bool {
+        self.code == other.code
+            && self.constants.len() == other.constants.len()
+            && self
+                .constants
+                .iter()
+                .zip(&other.constants)
+                .all(|pair| match pair {
+                    (Value::Function(a), Value::Function(b)) => a.same_body(b),
+                    (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+                    (a, b) => a == b,
+                })
+    }
 }
 
+// Strict equality, values of different types are never equal. Integers and
+// floats are both numbers, compared by value so 1 == 1.0
+// Arrays are equal with the same elements in order and maps with the same
+// entries in any order. Nested values are compared through a worklist instead
+// of recursion, so comparing doesn't grow the stack with their depth. Cloning,
+// printing and dropping them still recurse
 pub fn value_equal(a: Value, b: Value) -> bool {
-    match (a, b) {
-        (Value::Boolean(a_bool), Value::Boolean(b_bool)) => a_bool == b_bool,
-        (Value::Number(a_number), Value::Number(b_number)) => a_number == b_number,
-        (Value::String(a_string), Value::String(b_string)) => a_string == b_string,
-        (Value::Null, Value::Null) => true,
-        (_, _) => false,
+    let mut pending = vec![(&a, &b)];
+    while let Some(pair) = pending.pop() {
+        let equal = match pair {
+            (Value::Boolean(a_bool), Value::Boolean(b_bool)) => a_bool == b_bool,
+            (Value::Integer(a_integer), Value::Integer(b_integer)) => a_integer == b_integer,
+            (a, b) if a.is_number() && b.is_number() => {
+                compare_numbers(a, b) == Some(Ordering::Equal)
+            }
+            // Interned strings are equal by address, any other by their text
+            (Value::String(a_string), Value::String(b_string)) => {
+                Rc::ptr_eq(a_string, b_string) || a_string == b_string
+            }
+            (Value::Null, Value::Null) => true,
+            (Value::Array(a_elements), Value::Array(b_elements)) => {
+                pending.extend(a_elements.iter().zip(b_elements));
+                a_elements.len() == b_elements.len()
+            }
+            (Value::Map(a_map), Value::Map(b_map)) => {
+                let mut same_keys = a_map.entries().len() == b_map.entries().len();
+                for (key, a_value) in a_map.entries() {
+                    match b_map.get(key) {
+                        Ok(Some(b_value)) => pending.push((a_value, b_value)),
+                        _ => {
+                            same_keys = false;
+                            break;
+                        }
+                    }
+                }
+                same_keys
+            }
+            (_, _) => false,
+        };
+        if !equal {
+            return false;
+        }
+    }
+    true
+}
+
+// Loose equality, values of different types are compared by their printed form
+pub fn value_loose_equal(a: Value, b: Value) -> bool {
+    if std::mem::discriminant(&a) == std::mem::discriminant(&b) || a.is_number() && b.is_number() {
+        value_equal(a, b)
+    } else {
+        a.to_string() == b.to_string()
+    }
+}
+
+// Operands of an arithmetic operation, integers are promoted to floats when
+// mixed with a float
+enum Operands {
+    Integers(i64, i64),
+    Floats(f64, f64),
+}
+
+fn operands(left: &Value, right: &Value) -> Option<Operands> {
+    let float = |value: &Value| match value {
+        Value::Integer(n) => Some(*n as f64),
+        Value::Number(n) => Some(*n),
+        _ => None,
+    };
+    match (left, right) {
+        (Value::Integer(left), Value::Integer(right)) => Some(Operands::Integers(*left, *right)),
+        _ => Some(Operands::Floats(float(left)?, float(right)?)),
+    }
+}
+
+// Result of an arithmetic instruction on two numbers, None when the operands
+// aren't numbers, the divisor is zero or an integer overflows. Integer
+// division stays an integer only when it's exact
+pub fn arithmetic(instruction: &OpCode, left: &Value, right: &Value) -> Option<Value> {
+    match (instruction, operands(left, right)?) {
+        (OpCode::Add, Operands::Integers(a, b)) => a.checked_add(b).map(Value::Integer),
+        (OpCode::Subtract, Operands::Integers(a, b)) => a.checked_sub(b).map(Value::Integer),
+        (OpCode::Multiply, Operands::Integers(a, b)) => a.checked_mul(b).map(Value::Integer),
+        (OpCode::Divide, Operands::Integers(a, b)) => match a.checked_rem(b)? {
+            0 => a.checked_div(b).map(Value::Integer),
+            _ => Some(Value::Number(a as f64 / b as f64)),
+        },
+        (OpCode::Modulo, Operands::Integers(a, b)) => a.checked_rem(b).map(Value::Integer),
+        // Negative exponents give floats
+        (OpCode::Power, Operands::Integers(a, b)) => match u32::try_from(b) {
+            Ok(exponent) => a.checked_pow(exponent).map(Value::Integer),
+            Err(_) => Some(Value::Number((a as f64).powf(b as f64))),
+        },
+        (OpCode::Add, Operands::Floats(a, b)) => Some(Value::Number(a + b)),
+        (OpCode::Subtract, Operands::Floats(a, b)) => Some(Value::Number(a - b)),
+        (OpCode::Multiply, Operands::Floats(a, b)) => Some(Value::Number(a * b)),
+        (OpCode::Divide | OpCode::Modulo, Operands::Floats(_, 0.0)) => None,
+        (OpCode::Divide, Operands::Floats(a, b)) => Some(Value::Number(a / b)),
+        (OpCode::Modulo, Operands::Floats(a, b)) => Some(Value::Number(a % b)),
+        (OpCode::Power, Operands::Floats(a, b)) => Some(Value::Number(a.powf(b))),
+        _ => None,
+    }
+}
+
+pub fn negate(value: &Value) -> Option<Value> {
+    match value {
+        Value::Integer(n) => n.checked_neg().map(Value::Integer),
+        Value::Number(n) => Some(Value::Number(-n)),
+        _ => None,
+    }
+}
+
+// Order of two numbers, None when either isn't a number or is NaN
+pub fn compare_numbers(left: &Value, right: &Value) -> Option<Ordering> {
+    match operands(left, right)? {
+        Operands::Integers(a, b) => Some(a.cmp(&b)),
+        Operands::Floats(a, b) => a.partial_cmp(&b),
     }
 }
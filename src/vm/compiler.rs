@@ -1,36 +1,101 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, fmt, mem::take, rc::Rc};
 
 use crate::common::{
+    diagnostic::Diagnostic,
     lexer::{
         lexer_impl::Lexer,
         token::{Token, TokenType},
     },
-    precedences::{create_precedences, Precedence},
+    precedences::{create_precedences, right_operand_precedence, Precedence},
 };
 
 use super::{
-    chunk::{Chunk, OpCode, Value},
-    object::{Function, FunctionType},
+    chunk::{arithmetic, negate, Chunk, OpCode, Value},
+    natives::builtins,
+    object::{Function, FunctionType, UpvalueRef},
     vm_impl::InterpretResult,
 };
-use crate::vm::scope::Scope;
+use crate::vm::{scope::Scope, verifier::verify_stack_effect};
 
 // Function types for prefix and infix parsing
 type PrefixParseFn = fn(&mut Compiler);
 type InfixParseFn = fn(&mut Compiler);
 
+// Kind of a top level declaration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Variable,
+    Function,
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolKind::Variable => write!(f, "variable"),
+            SymbolKind::Function => write!(f, "function"),
+        }
+    }
+}
+
+// Innermost loop being compiled
+#[derive(Debug)]
+struct LoopContext {
+    // Target of continue
+    start: usize,
+    // Scope depth of the loop, locals deeper than it belong to the body
+    depth: i32,
+    // Jumps of the break statements, patched to the end of the loop
+    breaks: Vec<usize>,
+}
+
+#[derive(Debug)]
+enum LastExpression {
+    // Position of the Pop discarding the value
+    Popped(usize),
+    // Pure value that wasn't emitted, it would go at this position
+    Elided(usize, OpCode),
+}
+
+// Variables of a function that encloses the one being compiled
+#[derive(Debug)]
+struct EnclosingFunction {
+    scope: Scope,
+    upvalues: Vec<UpvalueRef>,
+}
+
 #[derive(Debug)]
 pub struct Compiler<'a> {
     lexer: Rc<RefCell<Lexer<'a>>>,
     current_token: Option<Token>,
     peek_token: Option<Token>,
-    pub errors: Vec<String>,
+    pub errors: Vec<Diagnostic>,
+    // Diagnostics that don't stop compilation unless promoted
+    pub warnings: Vec<Diagnostic>,
+    pub warnings_as_errors: bool,
+    // Let a top level let or fn replace a global defined before
+    pub allow_global_redefinition: bool,
+    // Globals declared by the script in declaration order
+    pub symbols: Vec<(String, SymbolKind)>,
+    // Names of globals used by the script, checked against the symbols when
+    // compiling against a known symbol table
+    global_references: Vec<Token>,
+    check_undefined_globals: bool,
     prefix_parse_fns: HashMap<TokenType, PrefixParseFn>,
     infix_parse_fns: HashMap<TokenType, InfixParseFn>,
     precedences: HashMap<TokenType, Precedence>,
     current_scope: Scope,
     function_type: FunctionType,
     pub function: Function,
+    loops: Vec<LoopContext>,
+    // Last top level expression statement, a script returns its value
+    last_expression: Option<LastExpression>,
+    // Enclosing functions from the outermost to the innermost
+    enclosing: Vec<EnclosingFunction>,
+    // Names read inside blocks that weren't locals yet, with the depth of the
+    // innermost open block containing the read
+    outer_reads: Vec<(Token, i32)>,
+    // Start of the left operand of the infix expression being compiled
+    operand_start: usize,
 }
 
 impl<'a> Compiler<'a> {
@@ -40,12 +105,23 @@ impl<'a> Compiler<'a> {
             current_token: None,
             peek_token: None,
             errors: vec![],
+            warnings: vec![],
+            warnings_as_errors: false,
+            allow_global_redefinition: false,
+            symbols: vec![],
+            global_references: vec![],
+            check_undefined_globals: false,
             prefix_parse_fns: HashMap::new(),
             infix_parse_fns: HashMap::new(),
             precedences: create_precedences(),
             current_scope: Scope::new(),
             function_type,
             function: Function::new(),
+            loops: vec![],
+            last_expression: None,
+            enclosing: vec![],
+            outer_reads: vec![],
+            operand_start: 0,
         };
 
         if let FunctionType::Function(name) = &compiler.function_type {
@@ -57,6 +133,7 @@ impl<'a> Compiler<'a> {
             kind: TokenType::Default,
             lexeme: String::new(),
             line: 0,
+            column: 0,
         });
 
         compiler.register_prefix_functions();
@@ -65,6 +142,20 @@ impl<'a> Compiler<'a> {
         compiler
     }
 
+    // Compiles against the globals of a session, like the REPL's, so using a
+    // global that is neither known, declared by the script nor a builtin is an
+    // error
+    pub fn new_with_symbols(
+        lexer: Rc<RefCell<Lexer<'a>>>,
+        function_type: FunctionType,
+        symbols: Vec<(String, SymbolKind)>,
+    ) -> Compiler<'a> {
+        let mut compiler = Compiler::new(lexer, function_type);
+        compiler.symbols = symbols;
+        compiler.check_undefined_globals = true;
+        compiler
+    }
+
     fn register_prefix_functions(&mut self) {
         self.prefix_parse_fns
             .insert(TokenType::Identifier, identifier);
@@ -78,6 +169,15 @@ impl<'a> Compiler<'a> {
             .insert(TokenType::Minus, prefix_expression);
         self.prefix_parse_fns
             .insert(TokenType::Bang, prefix_expression);
+        self.prefix_parse_fns
+            .insert(TokenType::Function, function_literal);
+        self.prefix_parse_fns
+            .insert(TokenType::LeftBracket, array_literal);
+        self.prefix_parse_fns
+            .insert(TokenType::LeftBrace, map_literal);
+        self.prefix_parse_fns
+            .insert(TokenType::LeftParen, grouped_expression);
+        self.prefix_parse_fns.insert(TokenType::Error, lexer_error);
     }
 
     fn register_infix_functions(&mut self) {
@@ -89,6 +189,10 @@ impl<'a> Compiler<'a> {
             .insert(TokenType::Star, infix_expression);
         self.infix_parse_fns
             .insert(TokenType::Slash, infix_expression);
+        self.infix_parse_fns
+            .insert(TokenType::Percent, infix_expression);
+        self.infix_parse_fns
+            .insert(TokenType::StarStar, infix_expression);
         self.infix_parse_fns
             .insert(TokenType::EqualEqual, infix_expression);
         self.infix_parse_fns
@@ -103,6 +207,18 @@ impl<'a> Compiler<'a> {
             .insert(TokenType::LessEqual, infix_expression);
         self.infix_parse_fns
             .insert(TokenType::LeftParen, call_expression);
+        self.infix_parse_fns
+            .insert(TokenType::LeftBracket, index_expression);
+        self.infix_parse_fns
+            .insert(TokenType::Question, optional_index_expression);
+        self.infix_parse_fns
+            .insert(TokenType::QuestionDot, optional_index_expression);
+        self.infix_parse_fns
+            .insert(TokenType::Dot, member_expression);
+        self.infix_parse_fns
+            .insert(TokenType::QuestionQuestion, coalesce_expression);
+        self.infix_parse_fns.insert(TokenType::And, and_expression);
+        self.infix_parse_fns.insert(TokenType::Or, or_expression);
     }
 
     // Consumes a token, updating current and peek token
@@ -140,6 +256,14 @@ impl<'a> Compiler<'a> {
         self.current_token.as_ref().map(|t| t.line).unwrap_or(0)
     }
 
+    // Line and column of the current token
+    fn current_token_position(&self) -> (u32, u32) {
+        self.current_token
+            .as_ref()
+            .map(|t| (t.line, t.column))
+            .unwrap_or((0, 0))
+    }
+
     fn current_token_lexeme(&self) -> String {
         self.current_token
             .as_ref()
@@ -162,7 +286,10 @@ impl<'a> Compiler<'a> {
         );
         self.add_error(
             message,
-            self.peek_token.as_ref().map(|t| t.line).unwrap_or(0),
+            self.peek_token
+                .as_ref()
+                .map(|t| (t.line, t.column))
+                .unwrap_or((0, 0)),
         );
     }
 
@@ -176,11 +303,32 @@ impl<'a> Compiler<'a> {
                 .map(|t| t.lexeme.clone())
                 .unwrap_or_default()
         );
-        self.add_error(error, self.peek_token.as_ref().map(|t| t.line).unwrap_or(0));
+        self.add_error(error, self.current_token_position());
+    }
+
+    fn add_error(&mut self, message: String, (line, column): (u32, u32)) {
+        self.errors.push(Diagnostic::new(message, line, column));
     }
 
-    fn add_error(&mut self, message: String, line: u32) {
-        self.errors.push(format!("Line {}: {}", line, message));
+    fn check_global_references(&mut self) {
+        if !self.check_undefined_globals {
+            return;
+        }
+
+        let builtins = builtins();
+        for reference in take(&mut self.global_references) {
+            let is_defined = builtins.contains_key(&reference.lexeme)
+                || self
+                    .symbols
+                    .iter()
+                    .any(|(symbol, _)| *symbol == reference.lexeme);
+            if !is_defined {
+                self.add_error(
+                    format!("Undefined variable {}", reference.lexeme),
+                    (reference.line, reference.column),
+                );
+            }
+        }
     }
 
     pub fn current_chunk(&mut self) -> &mut Chunk {
@@ -201,8 +349,93 @@ impl<'a> Compiler<'a> {
     }
 
     fn end_compiler(&mut self) -> InterpretResult {
-        // Implicit return
-        self.emit_return();
+        // Implicit return, a script ending in an expression statement returns its value
+        let code_len = self.current_chunk().code.len();
+        let is_script = matches!(self.function_type, FunctionType::Script);
+        match self.last_expression.take() {
+            Some(LastExpression::Popped(index)) if is_script && index + 1 == code_len => {
+                self.current_chunk().remove_last();
+                self.emit_bytecode(OpCode::Return);
+            }
+            Some(LastExpression::Elided(index, value)) if is_script && index == code_len => {
+                self.emit_bytecode(value);
+                self.emit_bytecode(OpCode::Return);
+            }
+            _ => self.emit_return(),
+        }
+
+        if is_script {
+            self.check_global_references();
+        }
+        self.finish()
+    }
+
+    // Compiles a single expression as a script returning its value
+    pub fn compile_expression(&mut self) -> InterpretResult {
+        // Initialize current and peek token
+        self.next_token();
+        self.next_token();
+
+        // An expression has no function to return from
+        if self.current_token_is(TokenType::Return) {
+            self.current_error("Can't return outside a function, found");
+            return self.finish();
+        }
+
+        self.expression(Precedence::Lowest);
+        if !self.peek_token_is(TokenType::EOF) {
+            self.peek_error(TokenType::EOF);
+        }
+        self.emit_bytecode(OpCode::Return);
+
+        self.check_global_references();
+        self.finish()
+    }
+
+    // Compiles the statements of a REPL submission. A final expression
+    // statement returns its value to be echoed, otherwise the script ends
+    // leaving nothing above its slot
+    pub fn compile_submission(&mut self) -> InterpretResult {
+        // Initialize current and peek token
+        self.next_token();
+        self.next_token();
+
+        // Errors after the first one would only repeat it
+        while !self.current_token_is(TokenType::EOF) && self.errors.is_empty() {
+            self.statement();
+            self.next_token();
+        }
+
+        let code_len = self.current_chunk().code.len();
+        match self.last_expression.take() {
+            Some(LastExpression::Popped(index)) if index + 1 == code_len => {
+                self.current_chunk().remove_last();
+                self.emit_bytecode(OpCode::Return);
+            }
+            Some(LastExpression::Elided(index, value)) if index == code_len => {
+                self.emit_bytecode(value);
+                self.emit_bytecode(OpCode::Return);
+            }
+            _ => (),
+        }
+
+        self.check_global_references();
+        self.finish()
+    }
+
+    // Checks shared by every way of ending a compilation
+    fn finish(&mut self) -> InterpretResult {
+        if self.warnings_as_errors {
+            self.errors.append(&mut self.warnings);
+        }
+
+        // Internal check of the emitted bytecode
+        if self.errors.is_empty() {
+            if let Err(message) = verify_stack_effect(self.current_chunk()) {
+                let position = self.current_token_position();
+                self.add_error(format!("Internal compiler error: {}", message), position);
+            }
+        }
 
         // Check compilation errors
         if self.errors.is_empty() {
@@ -217,20 +450,44 @@ impl<'a> Compiler<'a> {
             TokenType::Let => self.let_statement(),
             TokenType::Function => self.function_declaration(),
             TokenType::Return => self.return_statement(),
+            TokenType::Yield => self.yield_statement(),
             TokenType::LeftBrace => self.block(),
             TokenType::If => self.if_statement(),
             TokenType::NewLine => (),
             TokenType::While => self.while_statement(),
+            TokenType::For => self.for_statement(),
+            TokenType::Continue => self.continue_statement(),
+            TokenType::Break => self.break_statement(),
             _ => self.expression_statement(),
         }
     }
 
     fn let_statement(&mut self) {
+        if self.peek_token_is(TokenType::LeftBracket) {
+            self.let_destructuring();
+            return;
+        }
+        if self.peek_token_is(TokenType::LeftBrace) {
+            self.let_map_destructuring();
+            return;
+        }
+
         if !self.expect_peek(TokenType::Identifier) {
             return;
         }
 
-        let index = self.prepare_variable();
+        let index = self.prepare_variable(SymbolKind::Variable);
+
+        // Type annotations are skipped, --typecheck checks them before compiling
+        if self.peek_token_is(TokenType::Colon) {
+            // Consume identifier
+            self.next_token();
+            if self.peek_token_is(TokenType::Null) {
+                self.next_token();
+            } else if !self.expect_peek(TokenType::Identifier) {
+                return;
+            }
+        }
 
         if self.peek_token_is(TokenType::Equal) {
             // Consume identifier
@@ -248,16 +505,139 @@ impl<'a> Compiler<'a> {
         self.finalize_variable(index);
     }
 
-    fn prepare_variable(&mut self) -> Option<usize> {
-        let lexeme = self.current_token_lexeme();
-        let index = if self.current_scope.depth == 0 {
-            Some(self.current_chunk().add_constant(Value::String(lexeme)))
+    // let [<identifier>, <identifier>, ...] = <expression>
+    fn let_destructuring(&mut self) {
+        // Consume let
+        self.next_token();
+
+        let mut names = vec![];
+        loop {
+            if !self.expect_peek(TokenType::Identifier) {
+                return;
+            }
+            if let Some(token) = self.current_token.clone() {
+                names.push(token);
+            }
+
+            if !self.peek_token_is(TokenType::Comma) {
+                break;
+            }
+            // Consume identifier
+            self.next_token();
+        }
+
+        if !self.expect_peek(TokenType::RightBracket) || !self.expect_peek(TokenType::Equal) {
+            return;
+        }
+        // Consume =
+        self.next_token();
+
+        // Names are declared after the value, so it still sees outer variables
+        self.expression(Precedence::Lowest);
+        self.parse_end_statement();
+        self.emit_bytecode(OpCode::Unpack(names.len()));
+
+        self.define_unpacked(names);
+    }
+
+    // let {<key>, <key>: <identifier>, ...} = <expression>
+    fn let_map_destructuring(&mut self) {
+        // Consume let
+        self.next_token();
+
+        let mut keys = vec![];
+        let mut names = vec![];
+        loop {
+            if !self.expect_peek(TokenType::Identifier) {
+                return;
+            }
+            let Some(key) = self.current_token.clone() else {
+                return;
+            };
+            if self.peek_token_is(TokenType::Colon) {
+                // Consume key
+                self.next_token();
+                if !self.expect_peek(TokenType::Identifier) {
+                    return;
+                }
+            }
+            if let Some(name) = self.current_token.clone() {
+                names.push(name);
+            }
+            keys.push(key.lexeme);
+
+            if !self.peek_token_is(TokenType::Comma) {
+                break;
+            }
+            // Consume identifier
+            self.next_token();
+        }
+
+        if !self.expect_peek(TokenType::RightBrace) || !self.expect_peek(TokenType::Equal) {
+            return;
+        }
+        // Consume =
+        self.next_token();
+
+        self.expression(Precedence::Lowest);
+        self.parse_end_statement();
+        for key in keys {
+            let constant = self.current_chunk().add_constant(Value::String(key.into()));
+            self.emit_bytecode(OpCode::Constant(constant));
+        }
+        self.emit_bytecode(OpCode::UnpackMap(names.len()));
+
+        self.define_unpacked(names);
+    }
+
+    // Defines the variables of the values left in order on the stack
+    fn define_unpacked(&mut self, names: Vec<Token>) {
+        if self.current_scope.depth == 0 {
+            let indices: Vec<usize> = names
+                .into_iter()
+                .map(|name| self.declare_global(name, SymbolKind::Variable))
+                .collect();
+            // Elements are on the stack in order, so the last one is defined first
+            for index in indices.into_iter().rev() {
+                self.emit_bytecode(OpCode::DefineGlobal(index));
+            }
+        } else {
+            // Elements already sit in the slots of the new locals
+            for name in names {
+                self.declare_local(name);
+                self.remove_uninitialized_mark();
+            }
+        }
+    }
+
+    fn prepare_variable(&mut self, kind: SymbolKind) -> Option<usize> {
+        if self.current_scope.depth == 0 {
+            let name = self.current_token.clone()?;
+            Some(self.declare_global(name, kind))
         } else {
             self.declare_local_variable();
             None
-        };
+        }
+    }
+
+    // Returns the constant with the name of the global. Scripts can define each
+    // global once, redefining is allowed when enabled for the REPL
+    fn declare_global(&mut self, name: Token, kind: SymbolKind) -> usize {
+        let is_defined = self
+            .symbols
+            .iter()
+            .any(|(symbol, _)| *symbol == name.lexeme);
+        if !is_defined {
+            self.symbols.push((name.lexeme.clone(), kind));
+        } else if !self.allow_global_redefinition {
+            self.add_error(
+                format!("Global {} is already defined", name.lexeme),
+                (name.line, name.column),
+            );
+        }
 
-        index
+        self.current_chunk()
+            .add_constant(Value::String(name.lexeme.into()))
     }
 
     fn finalize_variable(&mut self, index: Option<usize>) {
@@ -275,59 +655,124 @@ impl<'a> Compiler<'a> {
     }
 
     fn declare_local_variable(&mut self) {
+        if let Some(token) = self.current_token.take() {
+            self.declare_local(token);
+        };
+    }
+
+    fn declare_local(&mut self, name: Token) {
         let has_existing_variable = self.current_scope.locals.iter().rev().any(|local| {
-            local.depth == self.current_scope.depth
-                && local.name.lexeme == self.current_token_lexeme()
+            local.depth == self.current_scope.depth && local.name.lexeme == name.lexeme
         });
 
         if has_existing_variable {
             self.add_error(
                 "Already a variable with this name in this scope.".to_string(),
-                self.current_token_line(),
+                (name.line, name.column),
             );
         }
 
-        if let Some(token) = self.current_token.take() {
-            self.current_scope.add_local(token);
-        };
+        // Variables are block scoped, reading one earlier in its block is an error
+        let depth = self.current_scope.depth;
+        let early_reads: Vec<(u32, u32)> = self
+            .outer_reads
+            .iter()
+            .filter(|(read, read_depth)| read.lexeme == name.lexeme && *read_depth >= depth)
+            .map(|(read, _)| (read.line, read.column))
+            .collect();
+        self.outer_reads
+            .retain(|(read, read_depth)| read.lexeme != name.lexeme || *read_depth < depth);
+        for position in early_reads {
+            self.add_error(
+                format!("Can't use {} before its declaration", name.lexeme),
+                position,
+            );
+        }
+
+        self.current_scope.add_local(name);
     }
 
     fn function_declaration(&mut self) {
+        // Anonymous function used as an expression statement
+        if self.peek_token_is(TokenType::LeftParen) {
+            self.expression_statement();
+            return;
+        }
+
+        let is_generator = self.peek_token_is(TokenType::Star);
+        if is_generator {
+            // Consume fn, leaving *
+            self.next_token();
+        }
+
         self.expect_peek(TokenType::Identifier);
 
-        let define_function_index = self.prepare_variable();
+        // Declaring a local consumes the name token
+        let lexeme = self.current_token_lexeme();
+        let define_function_index = self.prepare_variable(SymbolKind::Function);
 
         if define_function_index.is_none() {
             self.remove_uninitialized_mark();
         }
 
+        self.function(FunctionType::Function(lexeme), is_generator);
+
+        self.finalize_variable(define_function_index);
+    }
+
+    // Compiles the parameters and body of a function, leaving it on the stack
+    fn function(&mut self, function_type: FunctionType, is_generator: bool) {
         // New compiler for the function
-        let lexeme = self.current_token_lexeme();
-        let mut compiler = Compiler::new(self.lexer.clone(), FunctionType::Function(lexeme));
+        let mut compiler = Compiler::new(self.lexer.clone(), function_type);
+        compiler.function.is_generator = is_generator;
         // Initialize current and peek token
         compiler.current_token = self.current_token.take();
         compiler.peek_token = self.peek_token.take();
+        // Lend the variables of this function so the new one can capture them
+        compiler.enclosing = take(&mut self.enclosing);
+        compiler.enclosing.push(EnclosingFunction {
+            scope: take(&mut self.current_scope),
+            upvalues: take(&mut self.function.upvalues),
+        });
 
         compiler.parse_parameters();
 
-        if !compiler.expect_peek(TokenType::LeftBrace) {
-            return;
+        if compiler.expect_peek(TokenType::LeftBrace) {
+            compiler.block();
         }
 
-        compiler.block();
-
         compiler.end_compiler();
 
-        let execute_function_index = self
-            .current_chunk()
-            .add_constant(Value::Function(compiler.function));
-        self.emit_bytecode(OpCode::Constant(execute_function_index));
-
         // Initialize current and peek token
         self.current_token = compiler.current_token.take();
         self.peek_token = compiler.peek_token.take();
-
-        self.finalize_variable(define_function_index);
+        self.errors.append(&mut compiler.errors);
+        self.warnings.append(&mut compiler.warnings);
+        // Globals used by the body aren't checked, they only have to exist
+        // once the function is called
+        if let Some(enclosing) = compiler.enclosing.pop() {
+            self.current_scope = enclosing.scope;
+            self.function.upvalues = enclosing.upvalues;
+        }
+        self.enclosing = take(&mut compiler.enclosing);
+
+        // Functions capturing nothing don't depend on where they are created,
+        // so identical ones share a constant. Errors in them report the lines
+        // of the first one
+        let function = take(&mut compiler.function);
+        let shared = function.upvalues.is_empty().then(|| {
+            self.current_chunk()
+                .constants
+                .iter()
+                .position(|constant| {
+                    matches!(constant, Value::Function(existing) if existing.same_body(&function))
+                })
+        });
+        let index = match shared.flatten() {
+            Some(index) => index,
+            None => self.current_chunk().add_constant(Value::Function(function)),
+        };
+        self.emit_bytecode(OpCode::Closure(index));
     }
 
     fn parse_parameters(&mut self) {
@@ -366,7 +811,7 @@ impl<'a> Compiler<'a> {
     }
 
     fn parse_parameter(&mut self) {
-        let index = self.prepare_variable();
+        let index = self.prepare_variable(SymbolKind::Variable);
 
         if index.is_none() {
             self.remove_uninitialized_mark();
@@ -374,6 +819,13 @@ impl<'a> Compiler<'a> {
     }
 
     fn return_statement(&mut self) {
+        // Bare return
+        if self.peek_token_is(TokenType::NewLine) || self.peek_token_is(TokenType::EOF) {
+            self.emit_return();
+            self.next_token();
+            return;
+        }
+
         // Consume return
         self.next_token();
 
@@ -383,6 +835,28 @@ impl<'a> Compiler<'a> {
         self.parse_end_statement();
     }
 
+    // Suspends the generator handing the value to next, bare yields give null
+    fn yield_statement(&mut self) {
+        if !self.function.is_generator {
+            self.current_error("Can't yield outside a generator, found");
+        }
+
+        if self.peek_token_is(TokenType::NewLine) || self.peek_token_is(TokenType::EOF) {
+            self.emit_bytecode(OpCode::Null);
+            self.emit_bytecode(OpCode::Yield);
+            self.next_token();
+            return;
+        }
+
+        // Consume yield
+        self.next_token();
+
+        self.expression(Precedence::Lowest);
+
+        self.emit_bytecode(OpCode::Yield);
+        self.parse_end_statement();
+    }
+
     fn block(&mut self) {
         self.expect_peek(TokenType::NewLine);
         // Consume new line
@@ -398,19 +872,72 @@ impl<'a> Compiler<'a> {
             self.next_token();
         }
 
-        let counter = self.current_scope.end_scope();
-        for _ in 0..counter {
+        self.end_scope();
+    }
+
+    fn end_scope(&mut self) {
+        for local in self.current_scope.end_scope() {
+            self.emit_pop_local(local.is_captured);
+
+            // Names starting with _ are unused on purpose
+            if !local.is_used && !local.is_captured && !local.name.lexeme.starts_with('_') {
+                self.warnings.push(Diagnostic::new(
+                    format!("unused variable {}", local.name.lexeme),
+                    local.name.line,
+                    local.name.column,
+                ));
+            }
+        }
+
+        // Reads in the closed block now belong to the enclosing one, top level
+        // variables are globals so they can be declared later
+        let depth = self.current_scope.depth;
+        if depth == 0 {
+            self.outer_reads.clear();
+        } else {
+            for (_, read_depth) in self.outer_reads.iter_mut() {
+                *read_depth = (*read_depth).min(depth);
+            }
+        }
+    }
+
+    // Captured variables are moved off the stack into their closures
+    fn emit_pop_local(&mut self, is_captured: bool) {
+        if is_captured {
+            self.emit_bytecode(OpCode::CloseUpvalue);
+        } else {
             self.emit_bytecode(OpCode::Pop);
         }
     }
 
+    // Finds a variable of an enclosing function, adding it to the upvalues of
+    // every function in between
+    fn resolve_upvalue(&mut self, name: &Token) -> Option<usize> {
+        resolve_upvalue(&mut self.enclosing, &mut self.function.upvalues, name)
+    }
+
     fn if_statement(&mut self) {
         // Consume 'if'
         self.next_token();
 
         // Parse the condition
+        let condition_start = self.current_chunk().code.len();
         self.expression(Precedence::Lowest);
 
+        // A constant condition only needs the branch it takes
+        let constant = match self.current_chunk().code[condition_start..] {
+            [OpCode::True] => Some(true),
+            [OpCode::False] => Some(false),
+            _ => None,
+        };
+        if let Some(condition) = constant {
+            self.current_chunk().remove_last();
+            // Consume condition
+            self.next_token();
+            self.constant_if_statement(condition);
+            return;
+        }
+
         // Emit the conditional jump
         let then_jump = self.current_chunk().code.len();
         self.emit_bytecode(OpCode::JumpIfFalse(0));
@@ -432,11 +959,19 @@ impl<'a> Compiler<'a> {
 
         self.emit_bytecode(OpCode::Pop);
 
-        if self.peek_token_is(TokenType::Else) {
+        if self.peek_token_is(TokenType::Elif) {
+            // Consume right brace, elif is compiled like if
+            self.next_token();
+            self.if_statement();
+        } else if self.peek_token_is(TokenType::Else) {
             // Consume else
             self.next_token();
 
-            if self.expect_peek(TokenType::LeftBrace) {
+            if self.peek_token_is(TokenType::If) {
+                // Consume else
+                self.next_token();
+                self.if_statement();
+            } else if self.expect_peek(TokenType::LeftBrace) {
                 self.block();
             }
         }
@@ -445,6 +980,63 @@ impl<'a> Compiler<'a> {
         self.patch_jump(else_jump);
     }
 
+    // Compiles both branches to report their errors, keeping the code of the
+    // taken one only
+    fn constant_if_statement(&mut self, condition: bool) {
+        self.branch(condition, Self::statement);
+
+        if self.peek_token_is(TokenType::Elif) {
+            // Consume right brace, elif is compiled like if
+            self.next_token();
+            self.branch(!condition, Self::if_statement);
+        } else if self.peek_token_is(TokenType::Else) {
+            // Consume else
+            self.next_token();
+
+            if self.peek_token_is(TokenType::If) {
+                // Consume else
+                self.next_token();
+                self.branch(!condition, Self::if_statement);
+            } else if self.expect_peek(TokenType::LeftBrace) {
+                self.branch(!condition, Self::block);
+            }
+        }
+    }
+
+    // Dead branches are compiled and then dropped with their constants and
+    // breaks, warning that they never run
+    fn branch(&mut self, is_taken: bool, compile: fn(&mut Self)) {
+        if is_taken {
+            compile(self);
+            return;
+        }
+
+        let (line, column) = self.current_token_position();
+        let code_len = self.current_chunk().code.len();
+        let constants_len = self.current_chunk().constants.len();
+        let breaks_len = self
+            .loops
+            .last()
+            .map(|loop_context| loop_context.breaks.len());
+        let last_expression = self.last_expression.take();
+
+        compile(self);
+
+        while self.current_chunk().code.len() > code_len {
+            self.current_chunk().remove_last();
+        }
+        self.current_chunk().constants.truncate(constants_len);
+        if let (Some(loop_context), Some(breaks_len)) = (self.loops.last_mut(), breaks_len) {
+            loop_context.breaks.truncate(breaks_len);
+        }
+        self.last_expression = last_expression;
+        self.warnings.push(Diagnostic::new(
+            "unreachable code".to_string(),
+            line,
+            column,
+        ));
+    }
+
     fn patch_jump(&mut self, jump_offset: usize) {
         // Calcula el valor del salto, ajustado por los bytes de la instrucción de salto en sí.
         let jump = self.current_chunk().code.len() - jump_offset - 1;
@@ -453,15 +1045,24 @@ impl<'a> Compiler<'a> {
             *target = jump;
         } else if let OpCode::Jump(ref mut target) = self.current_chunk().code[jump_offset] {
             *target = jump;
+        } else if let OpCode::JumpIfNull(ref mut target) = self.current_chunk().code[jump_offset] {
+            *target = jump;
         } else {
             panic!("Expected a jump instruction at the given offset.");
         }
     }
 
     fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+
         // Consume while
         self.next_token();
 
+        if self.current_token_is(TokenType::Let) {
+            self.while_let_statement(loop_start);
+            return;
+        }
+
         // Consume condition
         self.expression(Precedence::Lowest);
 
@@ -474,24 +1075,299 @@ impl<'a> Compiler<'a> {
         self.next_token();
 
         // Consume loop body
-        self.statement();
+        let breaks = self.loop_body(loop_start, self.current_scope.depth);
 
-        let last = self.current_chunk().code.len() - 1;
-        self.emit_bytecode(OpCode::Loop(last));
-        self.emit_bytecode(OpCode::Pop);
+        self.emit_loop(loop_start);
 
         // Patch the jump to point to the code after the while loop
         self.patch_jump(while_jump);
+        self.emit_bytecode(OpCode::Pop);
+        self.patch_breaks(breaks);
     }
 
-    fn parse_end_statement(&mut self) {
-        if !self.peek_token_is(TokenType::NewLine) && !self.peek_token_is(TokenType::EOF) {
-            self.peek_error(TokenType::NewLine);
-        } else {
-            self.next_token();
+    // while let <identifier> = <expression> <statement>, runs until the value is null
+    fn while_let_statement(&mut self, loop_start: usize) {
+        if !self.expect_peek(TokenType::Identifier) {
+            return;
         }
-    }
-
+        let Some(name) = self.current_token.clone() else {
+            return;
+        };
+        if !self.expect_peek(TokenType::Equal) {
+            return;
+        }
+        // Consume =
+        self.next_token();
+
+        // The value is bound each iteration, so continue drops it with the body
+        self.current_scope.begin_scope();
+        self.expression(Precedence::Lowest);
+        self.declare_local(name);
+        self.remove_uninitialized_mark();
+        let slot = self.current_scope.locals.len() - 1;
+
+        self.emit_bytecode(OpCode::GetLocal(slot));
+        self.emit_bytecode(OpCode::Null);
+        self.emit_bytecode(OpCode::NotEqual);
+        let exit_jump = self.current_chunk().code.len();
+        self.emit_bytecode(OpCode::JumpIfFalse(0));
+        self.emit_bytecode(OpCode::Pop);
+
+        self.next_token();
+
+        let breaks = self.loop_body(loop_start, self.current_scope.depth - 1);
+        self.end_scope();
+        self.emit_loop(loop_start);
+
+        // Leaving with a null drops the condition and the null value
+        self.patch_jump(exit_jump);
+        self.emit_bytecode(OpCode::Pop);
+        self.emit_bytecode(OpCode::Pop);
+        self.patch_breaks(breaks);
+    }
+
+    // for (<initializer>; <condition>; <increment>) <statement>
+    fn for_statement(&mut self) {
+        if !self.expect_peek(TokenType::LeftParen) {
+            return;
+        }
+        // Consume (
+        self.next_token();
+
+        if self.current_token_is(TokenType::Identifier) && self.peek_token_is(TokenType::Comma) {
+            self.for_in_statement();
+            return;
+        }
+
+        // The initializer variable lives in its own scope
+        self.current_scope.begin_scope();
+
+        match self.current_token_kind() {
+            TokenType::Semicolon => (),
+            TokenType::Let => self.let_statement(),
+            _ => self.expression_statement(),
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        // Consume ;
+        self.next_token();
+
+        let mut exit_jump = None;
+        if !self.current_token_is(TokenType::Semicolon) {
+            self.expression(Precedence::Lowest);
+            if !self.expect_peek(TokenType::Semicolon) {
+                return;
+            }
+
+            exit_jump = Some(self.current_chunk().code.len());
+            self.emit_bytecode(OpCode::JumpIfFalse(0));
+            self.emit_bytecode(OpCode::Pop);
+        }
+        // Consume ;
+        self.next_token();
+
+        // The increment is emitted before the body, so jump over it on the
+        // first iteration and make it the target of the body's loop back
+        if !self.current_token_is(TokenType::RightParen) {
+            let body_jump = self.current_chunk().code.len();
+            self.emit_bytecode(OpCode::Jump(0));
+
+            let increment_start = self.current_chunk().code.len();
+            self.expression(Precedence::Lowest);
+            self.emit_bytecode(OpCode::Pop);
+            if !self.expect_peek(TokenType::RightParen) {
+                return;
+            }
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+        // Consume )
+        self.next_token();
+
+        // Consume loop body, continue goes to the increment
+        let breaks = self.loop_body(loop_start, self.current_scope.depth);
+
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_bytecode(OpCode::Pop);
+        }
+        self.patch_breaks(breaks);
+
+        self.end_scope();
+    }
+
+    // for (<key>, <value> in <expression>) <statement>
+    // Walks the entries of a map in insertion order, or the indices and
+    // elements of an array
+    fn for_in_statement(&mut self) {
+        let Some(key) = self.current_token.clone() else {
+            return;
+        };
+        // Consume key
+        self.next_token();
+        if !self.expect_peek(TokenType::Identifier) {
+            return;
+        }
+        let Some(value) = self.current_token.clone() else {
+            return;
+        };
+        if !self.expect_peek(TokenType::In) {
+            return;
+        }
+        // Consume in
+        self.next_token();
+
+        // The collection and the position are kept in hidden locals
+        self.current_scope.begin_scope();
+        self.expression(Precedence::Lowest);
+        let collection_slot = self.add_hidden_local(&key, "for collection");
+        let constant = self.current_chunk().add_constant(Value::Integer(0));
+        self.emit_bytecode(OpCode::Constant(constant));
+        let position_slot = self.add_hidden_local(&key, "for position");
+
+        if !self.expect_peek(TokenType::RightParen) {
+            return;
+        }
+        // Consume )
+        self.next_token();
+
+        let loop_start = self.current_chunk().code.len();
+        self.emit_bytecode(OpCode::GetLocal(collection_slot));
+        self.emit_bytecode(OpCode::GetLocal(position_slot));
+        self.emit_bytecode(OpCode::HasEntry);
+        let exit_jump = self.current_chunk().code.len();
+        self.emit_bytecode(OpCode::JumpIfFalse(0));
+        self.emit_bytecode(OpCode::Pop);
+
+        // The entry is bound before the body, so continue only has to drop it
+        self.current_scope.begin_scope();
+        self.emit_bytecode(OpCode::GetLocal(collection_slot));
+        self.emit_bytecode(OpCode::GetLocal(position_slot));
+        self.emit_bytecode(OpCode::Entry);
+        for name in [key, value] {
+            self.declare_local(name);
+            self.remove_uninitialized_mark();
+        }
+
+        self.emit_bytecode(OpCode::GetLocal(position_slot));
+        let constant = self.current_chunk().add_constant(Value::Integer(1));
+        self.emit_bytecode(OpCode::Constant(constant));
+        self.emit_bytecode(OpCode::Add);
+        self.emit_bytecode(OpCode::SetLocal(position_slot));
+        self.emit_bytecode(OpCode::Pop);
+
+        let breaks = self.loop_body(loop_start, self.current_scope.depth - 1);
+        self.end_scope();
+
+        self.emit_loop(loop_start);
+        self.patch_jump(exit_jump);
+        self.emit_bytecode(OpCode::Pop);
+        self.patch_breaks(breaks);
+
+        self.end_scope();
+    }
+
+    // Adds a local the program can't name, returning its slot
+    fn add_hidden_local(&mut self, position: &Token, name: &str) -> usize {
+        let token = Token {
+            lexeme: name.to_string(),
+            ..position.clone()
+        };
+        self.current_scope.add_local(token);
+        self.remove_uninitialized_mark();
+        if let Some(local) = self.current_scope.locals.last_mut() {
+            local.is_used = true;
+        }
+        self.current_scope.locals.len() - 1
+    }
+
+    // Compiles the body of a loop, returning the jumps of its breaks
+    fn loop_body(&mut self, start: usize, depth: i32) -> Vec<usize> {
+        self.loops.push(LoopContext {
+            start,
+            depth,
+            breaks: vec![],
+        });
+        self.statement();
+        self.loops
+            .pop()
+            .map(|loop_context| loop_context.breaks)
+            .unwrap_or_default()
+    }
+
+    // Breaks land after the loop has dropped its condition
+    fn patch_breaks(&mut self, breaks: Vec<usize>) {
+        for jump in breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn continue_statement(&mut self) {
+        let Some(loop_context) = self.loops.last() else {
+            self.add_error(
+                "Can't use continue outside of a loop".to_string(),
+                self.current_token_position(),
+            );
+            return;
+        };
+        let loop_start = loop_context.start;
+
+        self.discard_loop_locals(loop_context.depth);
+        self.emit_loop(loop_start);
+        self.parse_end_statement();
+    }
+
+    fn break_statement(&mut self) {
+        let Some(loop_context) = self.loops.last() else {
+            self.add_error(
+                "Can't use break outside of a loop".to_string(),
+                self.current_token_position(),
+            );
+            return;
+        };
+
+        self.discard_loop_locals(loop_context.depth);
+        let jump = self.current_chunk().code.len();
+        self.emit_bytecode(OpCode::Jump(0));
+        if let Some(loop_context) = self.loops.last_mut() {
+            loop_context.breaks.push(jump);
+        }
+        self.parse_end_statement();
+    }
+
+    // Discards the locals declared inside the loop body
+    fn discard_loop_locals(&mut self, depth: i32) {
+        let locals: Vec<bool> = self
+            .current_scope
+            .locals_deeper_than(depth)
+            .map(|local| local.is_captured)
+            .collect();
+        for is_captured in locals {
+            self.emit_pop_local(is_captured);
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        // Jump back from the instruction after the loop to its start
+        let offset = self.current_chunk().code.len() + 1 - loop_start;
+        self.emit_bytecode(OpCode::Loop(offset));
+    }
+
+    fn parse_end_statement(&mut self) {
+        if !self.peek_token_is(TokenType::NewLine)
+            && !self.peek_token_is(TokenType::Semicolon)
+            && !self.peek_token_is(TokenType::EOF)
+        {
+            self.peek_error(TokenType::NewLine);
+        } else {
+            self.next_token();
+        }
+    }
+
     pub fn emit_bytecode(&mut self, byte: OpCode) {
         let line = self.current_token_line() as usize;
         self.current_chunk().write(byte, line);
@@ -504,12 +1380,29 @@ impl<'a> Compiler<'a> {
     }
 
     fn expression_statement(&mut self) {
+        let start = self.current_chunk().code.len();
         self.expression(Precedence::Lowest);
         self.parse_end_statement();
+
+        // A discarded value without side effects doesn't need to be pushed
+        let code_len = self.current_chunk().code.len();
+        if code_len == start + 1 && self.current_chunk().get(start).is_some_and(is_pure) {
+            if let Some(value) = self.current_chunk().remove_last() {
+                if self.current_scope.depth == 0 {
+                    self.last_expression = Some(LastExpression::Elided(start, value));
+                }
+            }
+            return;
+        }
+
+        if self.current_scope.depth == 0 {
+            self.last_expression = Some(LastExpression::Popped(code_len));
+        }
         self.emit_bytecode(OpCode::Pop);
     }
 
     fn expression(&mut self, precedence: Precedence) {
+        let start = self.current_chunk().code.len();
         let Some(prefix_fn) = self.prefix_parse_fns.get(&self.current_token_kind()) else {
             self.current_error("Unknow prefix operator ");
             return;
@@ -520,15 +1413,74 @@ impl<'a> Compiler<'a> {
         while precedence < self.peek_precedence() {
             // Consume token
             self.next_token();
+            // Assignments are compiled by variables, any other target ends up here
+            if self.current_token_is(TokenType::Equal)
+                || compound_operator(self.current_token_kind()).is_some()
+            {
+                self.add_error(
+                    "Invalid assignment target".to_string(),
+                    self.current_token_position(),
+                );
+                // Compiles the value anyway, so the rest of the statement
+                // doesn't report more errors
+                self.next_token();
+                self.expression(Precedence::Assigment);
+                return;
+            }
             let Some(infix_fn) = self.infix_parse_fns.get(&self.current_token_kind()) else {
                 self.current_error("Unknow infix operator");
                 return;
             };
 
+            self.operand_start = start;
             infix_fn(self);
         }
     }
 
+    // Replaces the number constants from `start` on with their folded value.
+    // Each operand is a whole expression compiled to one instruction, so no
+    // jump lands between them. Operations failing at runtime and results that
+    // aren't finite are left to the VM
+    fn fold_numbers(&mut self, start: usize, fold: impl Fn(&[Value]) -> Option<Value>) -> bool {
+        let chunk = self.current_chunk();
+        let mut indices = vec![];
+        let mut numbers = vec![];
+        for instruction in &chunk.code[start..] {
+            let OpCode::Constant(index) = instruction else {
+                return false;
+            };
+            match chunk.constants.get(*index) {
+                Some(number) if number.is_number() => numbers.push(number.clone()),
+                _ => return false,
+            }
+            indices.push(*index);
+        }
+        if numbers.is_empty() {
+            return false;
+        }
+        let value = match fold(&numbers) {
+            Some(Value::Number(number)) if !number.is_finite() => return false,
+            Some(value) => value,
+            None => return false,
+        };
+
+        for _ in &indices {
+            chunk.remove_last();
+        }
+        // Drop the operands from the pool when nothing was added after them
+        let first_operand = chunk.constants.len().saturating_sub(indices.len());
+        if indices
+            .iter()
+            .copied()
+            .eq(first_operand..chunk.constants.len())
+        {
+            chunk.constants.truncate(first_operand);
+        }
+        let index = chunk.add_constant(value);
+        self.emit_bytecode(OpCode::Constant(index));
+        true
+    }
+
     fn current_precedence(&self) -> Precedence {
         if let Some(token) = &self.current_token {
             *self
@@ -553,14 +1505,17 @@ impl<'a> Compiler<'a> {
 
     // Debug functions
 
+    #[cfg(test)]
     pub fn compile_one_statement(&mut self) -> bool {
         // Initialize current and peek token
         self.next_token();
         self.next_token();
         self.one_statement();
+        self.check_global_references();
         self.errors.is_empty()
     }
 
+    #[cfg(test)]
     fn one_statement(&mut self) {
         match self.current_token_kind() {
             TokenType::Let => self.let_statement(),
@@ -571,23 +1526,80 @@ impl<'a> Compiler<'a> {
     }
 }
 
+// Pushes a value without side effects. Globals are excluded, reading an
+// undefined one is a runtime error
+fn is_pure(instruction: &OpCode) -> bool {
+    matches!(
+        instruction,
+        OpCode::Constant(_)
+            | OpCode::Null
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal(_)
+            | OpCode::GetUpvalue(_)
+    )
+}
+
+fn resolve_upvalue(
+    enclosing: &mut [EnclosingFunction],
+    upvalues: &mut Vec<UpvalueRef>,
+    name: &Token,
+) -> Option<usize> {
+    let (parent, outer) = enclosing.split_last_mut()?;
+
+    if let Some(index) = parent.scope.resolve_local(name) {
+        parent.scope.locals[index].is_captured = true;
+        return Some(add_upvalue(upvalues, true, index));
+    }
+
+    let index = resolve_upvalue(outer, &mut parent.upvalues, name)?;
+    Some(add_upvalue(upvalues, false, index))
+}
+
+fn add_upvalue(upvalues: &mut Vec<UpvalueRef>, is_local: bool, index: usize) -> usize {
+    let upvalue = UpvalueRef { is_local, index };
+    if let Some(position) = upvalues.iter().position(|existing| *existing == upvalue) {
+        return position;
+    }
+
+    upvalues.push(upvalue);
+    upvalues.len() - 1
+}
+
 // Prefix parsing functions
 
 fn identifier(compiler: &mut Compiler) {
     let get_op;
     let set_op;
 
-    let Some(token) = compiler.current_token.take() else {
+    let Some(token) = compiler.current_token.clone() else {
         return;
     };
 
+    if compiler.current_scope.is_uninitialized(&token) {
+        compiler.add_error(
+            "Can't read local variable in its own initializer".to_string(),
+            (token.line, token.column),
+        );
+    }
+
+    if compiler.current_scope.depth > 0 && compiler.current_scope.resolve_local(&token).is_none() {
+        let depth = compiler.current_scope.depth;
+        compiler.outer_reads.push((token.clone(), depth));
+    }
+
     if let Some(position) = compiler.current_scope.resolve_local(&token) {
+        compiler.current_scope.locals[position].is_used = true;
         get_op = OpCode::GetLocal(position);
         set_op = OpCode::SetLocal(position);
+    } else if let Some(position) = compiler.resolve_upvalue(&token) {
+        get_op = OpCode::GetUpvalue(position);
+        set_op = OpCode::SetUpvalue(position);
     } else {
+        compiler.global_references.push(token.clone());
         let index = compiler
             .current_chunk()
-            .add_constant(Value::String(token.lexeme));
+            .add_constant(Value::String(token.lexeme.into()));
         get_op = OpCode::GetGlobal(index);
         set_op = OpCode::SetGlobal(index);
     }
@@ -601,18 +1613,110 @@ fn identifier(compiler: &mut Compiler) {
 
         compiler.expression(Precedence::Assigment);
         compiler.emit_bytecode(set_op);
+    } else if let Some(operator) = compiler
+        .peek_token
+        .as_ref()
+        .and_then(|token| compound_operator(token.kind))
+    {
+        // a += b compiles as a = a + b
+        // Consume Identifier and the operator
+        compiler.next_token();
+        compiler.next_token();
+
+        compiler.emit_bytecode(get_op);
+        compiler.expression(Precedence::Assigment);
+        compiler.emit_bytecode(operator);
+        compiler.emit_bytecode(set_op);
+    } else if compiler.peek_token_is(TokenType::LeftBracket) {
+        compiler.emit_bytecode(get_op.clone());
+        // Consume Identifier
+        compiler.next_token();
+
+        let index_start = compiler.current_chunk().code.len();
+        if !index_operand(compiler) {
+            return;
+        }
+
+        if compiler.peek_token_is(TokenType::Equal) {
+            // Consume ] and =
+            compiler.next_token();
+            compiler.next_token();
+
+            compiler.expression(Precedence::Assigment);
+            // Like plain assignment the expression yields the assigned value,
+            // the updated array is stored back into the variable
+            compiler.emit_bytecode(OpCode::SetIndex);
+            compiler.emit_bytecode(set_op);
+            compiler.emit_bytecode(OpCode::Pop);
+        } else if let Some(operator) = compiler
+            .peek_token
+            .as_ref()
+            .and_then(|token| compound_operator(token.kind))
+        {
+            // a[i] += b compiles as a[i] = a[i] + b, reading a and i again,
+            // so the index can't have side effects
+            // Consume ]
+            compiler.next_token();
+            let index = match &compiler.current_chunk().code[index_start..] {
+                [index] if is_pure(index) || matches!(index, OpCode::GetGlobal(_)) => {
+                    Some(index.clone())
+                }
+                _ => None,
+            };
+            if index.is_none() {
+                compiler.add_error(
+                    "Compound assignment needs a constant or variable index".to_string(),
+                    compiler.current_token_position(),
+                );
+            }
+            // Consume the operator
+            compiler.next_token();
+            let Some(index) = index else {
+                compiler.expression(Precedence::Assigment);
+                return;
+            };
+
+            compiler.emit_bytecode(get_op);
+            compiler.emit_bytecode(index);
+            compiler.emit_bytecode(OpCode::Index);
+            compiler.expression(Precedence::Assigment);
+            compiler.emit_bytecode(operator);
+            compiler.emit_bytecode(OpCode::SetIndex);
+            compiler.emit_bytecode(set_op);
+            compiler.emit_bytecode(OpCode::Pop);
+        } else {
+            compiler.emit_bytecode(OpCode::Index);
+        }
     } else {
         compiler.emit_bytecode(get_op);
     }
 }
 
+// Arithmetic of a compound assignment operator like +=
+fn compound_operator(kind: TokenType) -> Option<OpCode> {
+    match kind {
+        TokenType::PlusEqual => Some(OpCode::Add),
+        TokenType::MinusEqual => Some(OpCode::Subtract),
+        TokenType::StarEqual => Some(OpCode::Multiply),
+        TokenType::SlashEqual => Some(OpCode::Divide),
+        _ => None,
+    }
+}
+
+// Error tokens carry the lexer message as lexeme
+fn lexer_error(compiler: &mut Compiler) {
+    let message = compiler.current_token_lexeme();
+    let position = compiler.current_token_position();
+    compiler.add_error(message, position);
+}
+
+// Literals with a decimal point are floats
 fn number(compiler: &mut Compiler) {
-    let value = Value::Number(
-        compiler
-            .current_token_lexeme()
-            .parse()
-            .expect("Not a valid number"),
-    );
+    let lexeme = compiler.current_token_lexeme();
+    let value = match compiler.current_token_kind() {
+        TokenType::Integer => Value::Integer(lexeme.parse().expect("Not a valid integer")),
+        _ => Value::Number(lexeme.parse().expect("Not a valid number")),
+    };
     let index = compiler.current_chunk().add_constant(value);
     compiler.emit_bytecode(OpCode::Constant(index));
 }
@@ -621,7 +1725,9 @@ fn literal(compiler: &mut Compiler) {
     match compiler.current_token_kind() {
         TokenType::String => {
             let lexeme = compiler.current_token_lexeme();
-            let index = compiler.current_chunk().add_constant(Value::String(lexeme));
+            let index = compiler
+                .current_chunk()
+                .add_constant(Value::String(lexeme.into()));
             compiler.emit_bytecode(OpCode::Constant(index));
         }
         TokenType::True => compiler.emit_bytecode(OpCode::True),
@@ -635,30 +1741,74 @@ fn prefix_expression(compiler: &mut Compiler) {
     let operator = compiler.current_token_kind();
     // Consume current token
     compiler.next_token();
+    let start = compiler.current_chunk().code.len();
     compiler.expression(Precedence::Prefix);
+    let is_single = compiler.current_chunk().code.len() == start + 1;
 
     match operator {
+        // -5 is a prefix minus on 5, folded into a single constant
+        TokenType::Minus
+            if is_single && compiler.fold_numbers(start, |operand| negate(&operand[0])) => {}
         TokenType::Minus => compiler.emit_bytecode(OpCode::Negate),
         TokenType::Bang => compiler.emit_bytecode(OpCode::Not),
         _ => compiler.current_error("Unknow prefix operator"),
     }
 }
 
+// (<expression>)
+fn grouped_expression(compiler: &mut Compiler) {
+    // Consume left parenthesis
+    compiler.next_token();
+    compiler.expression(Precedence::Lowest);
+    compiler.expect_peek(TokenType::RightParen);
+}
+
+// fn (<parameters>?) <block>
+fn function_literal(compiler: &mut Compiler) {
+    compiler.function(FunctionType::Function(String::new()), false);
+}
+
 // Infix parsing functions
 
 fn infix_expression(compiler: &mut Compiler) {
     let operator = compiler.current_token_kind();
-    let precedence = compiler.current_precedence();
+    let precedence = right_operand_precedence(operator, compiler.current_precedence());
+    let left_start = compiler.operand_start;
     // Consume current token
     compiler.next_token();
 
+    let right_start = compiler.current_chunk().code.len();
     compiler.expression(precedence);
 
+    // Arithmetic on two number constants
+    let instruction = match operator {
+        TokenType::Plus => Some(OpCode::Add),
+        TokenType::Minus => Some(OpCode::Subtract),
+        TokenType::Star => Some(OpCode::Multiply),
+        TokenType::Slash => Some(OpCode::Divide),
+        TokenType::Percent => Some(OpCode::Modulo),
+        TokenType::StarStar => Some(OpCode::Power),
+        _ => None,
+    };
+    let code_len = compiler.current_chunk().code.len();
+    if let Some(instruction) = instruction {
+        if right_start == left_start + 1
+            && code_len == right_start + 1
+            && compiler.fold_numbers(left_start, |operands| {
+                arithmetic(&instruction, &operands[0], &operands[1])
+            })
+        {
+            return;
+        }
+    }
+
     match operator {
         TokenType::Plus => compiler.emit_bytecode(OpCode::Add),
         TokenType::Minus => compiler.emit_bytecode(OpCode::Subtract),
         TokenType::Star => compiler.emit_bytecode(OpCode::Multiply),
         TokenType::Slash => compiler.emit_bytecode(OpCode::Divide),
+        TokenType::Percent => compiler.emit_bytecode(OpCode::Modulo),
+        TokenType::StarStar => compiler.emit_bytecode(OpCode::Power),
         TokenType::EqualEqual => compiler.emit_bytecode(OpCode::Equal),
         TokenType::BangEqual => compiler.emit_bytecode(OpCode::NotEqual),
         TokenType::Less => compiler.emit_bytecode(OpCode::Less),
@@ -669,13 +1819,95 @@ fn infix_expression(compiler: &mut Compiler) {
     }
 }
 
+// a ?? b only evaluates b when a is null
+fn coalesce_expression(compiler: &mut Compiler) {
+    let null_jump = compiler.current_chunk().code.len();
+    compiler.emit_bytecode(OpCode::JumpIfNull(0));
+
+    let end_jump = compiler.current_chunk().code.len();
+    compiler.emit_bytecode(OpCode::Jump(0));
+
+    compiler.patch_jump(null_jump);
+    compiler.emit_bytecode(OpCode::Pop);
+
+    // Consume ??
+    compiler.next_token();
+    compiler.expression(Precedence::Coalesce);
+
+    compiler.patch_jump(end_jump);
+}
+
+// a && b leaves a when it's falsey without evaluating b
+fn and_expression(compiler: &mut Compiler) {
+    let end_jump = compiler.current_chunk().code.len();
+    compiler.emit_bytecode(OpCode::JumpIfFalse(0));
+    compiler.emit_bytecode(OpCode::Pop);
+
+    // Consume &&
+    compiler.next_token();
+    compiler.expression(Precedence::And);
+
+    compiler.patch_jump(end_jump);
+}
+
+// a || b leaves a when it's truthy without evaluating b
+fn or_expression(compiler: &mut Compiler) {
+    let else_jump = compiler.current_chunk().code.len();
+    compiler.emit_bytecode(OpCode::JumpIfFalse(0));
+
+    let end_jump = compiler.current_chunk().code.len();
+    compiler.emit_bytecode(OpCode::Jump(0));
+
+    compiler.patch_jump(else_jump);
+    compiler.emit_bytecode(OpCode::Pop);
+
+    // Consume ||
+    compiler.next_token();
+    compiler.expression(Precedence::Or);
+
+    compiler.patch_jump(end_jump);
+}
+
 fn call_expression(compiler: &mut Compiler) {
+    if let Some(arguments) = argument_list(compiler) {
+        compiler.emit_bytecode(OpCode::Call(arguments));
+    }
+}
+
+// Compiles value.name(arguments) as name(value, arguments) and value.name as
+// value["name"], with the value already on the stack
+fn member_expression(compiler: &mut Compiler) {
+    if !compiler.expect_peek(TokenType::Identifier) {
+        return;
+    }
+    let Some(token) = compiler.current_token.clone() else {
+        return;
+    };
+    let index = compiler
+        .current_chunk()
+        .add_constant(Value::String(token.lexeme.as_str().into()));
+
+    if !compiler.peek_token_is(TokenType::LeftParen) {
+        compiler.emit_bytecode(OpCode::Constant(index));
+        compiler.emit_bytecode(OpCode::Index);
+        return;
+    }
+    compiler.global_references.push(token);
+    // Consume name
+    compiler.next_token();
+    if let Some(arguments) = argument_list(compiler) {
+        compiler.emit_bytecode(OpCode::Invoke(index, arguments + 1));
+    }
+}
+
+// Compiles the arguments between parens, leaving the right paren as current
+// token. Returns how many there are, none when they don't parse
+fn argument_list(compiler: &mut Compiler) -> Option<usize> {
     let mut arguments = 0;
     if compiler.peek_token_is(TokenType::RightParen) {
-        compiler.emit_bytecode(OpCode::Call(0));
         //Consume left paren
         compiler.next_token();
-        return;
+        return Some(0);
     }
 
     //Consume left paren
@@ -686,7 +1918,7 @@ fn call_expression(compiler: &mut Compiler) {
 
     while !compiler.peek_token_is(TokenType::RightParen) {
         if !compiler.expect_peek(TokenType::Comma) {
-            return;
+            return None;
         }
         // Consume comma
         compiler.next_token();
@@ -695,5 +1927,125 @@ fn call_expression(compiler: &mut Compiler) {
         arguments += 1;
     }
     compiler.next_token();
-    compiler.emit_bytecode(OpCode::Call(arguments));
+    Some(arguments)
+}
+
+fn array_literal(compiler: &mut Compiler) {
+    let mut elements = 0;
+    if compiler.peek_token_is(TokenType::RightBracket) {
+        compiler.emit_bytecode(OpCode::BuildArray(0));
+        //Consume right bracket
+        compiler.next_token();
+        return;
+    }
+
+    //Consume left bracket
+    compiler.next_token();
+
+    elements += 1;
+    compiler.expression(Precedence::Lowest);
+
+    while !compiler.peek_token_is(TokenType::RightBracket) {
+        if !compiler.expect_peek(TokenType::Comma) {
+            return;
+        }
+        // Consume comma
+        compiler.next_token();
+
+        compiler.expression(Precedence::Lowest);
+        elements += 1;
+    }
+    compiler.next_token();
+    compiler.emit_bytecode(OpCode::BuildArray(elements));
+}
+
+// {<expression>: <expression>, ...}
+fn map_literal(compiler: &mut Compiler) {
+    let mut entries = 0;
+    if compiler.peek_token_is(TokenType::RightBrace) {
+        compiler.emit_bytecode(OpCode::BuildMap(0));
+        //Consume right brace
+        compiler.next_token();
+        return;
+    }
+
+    loop {
+        // Consume left brace or comma
+        compiler.next_token();
+        compiler.expression(Precedence::Lowest);
+        if !compiler.expect_peek(TokenType::Colon) {
+            return;
+        }
+        // Consume colon
+        compiler.next_token();
+        compiler.expression(Precedence::Lowest);
+        entries += 1;
+
+        if !compiler.peek_token_is(TokenType::Comma) {
+            break;
+        }
+        compiler.next_token();
+    }
+
+    if !compiler.expect_peek(TokenType::RightBrace) {
+        return;
+    }
+    compiler.emit_bytecode(OpCode::BuildMap(entries));
+}
+
+fn index_expression(compiler: &mut Compiler) {
+    if index_operand(compiler) {
+        compiler.emit_bytecode(OpCode::Index);
+    }
+}
+
+// a?[i], a?.[i] and a?.name skip the rest of the chain when a is null, which
+// becomes the result, so null?["x"][0] is null
+fn optional_index_expression(compiler: &mut Compiler) {
+    let null_jump = compiler.current_chunk().code.len();
+    compiler.emit_bytecode(OpCode::JumpIfNull(0));
+
+    if compiler.current_token_is(TokenType::QuestionDot)
+        && compiler.peek_token_is(TokenType::Identifier)
+    {
+        member_expression(compiler);
+    } else if compiler.expect_peek(TokenType::LeftBracket) {
+        index_expression(compiler);
+    } else {
+        return;
+    }
+
+    while let Some(postfix) = compiler
+        .peek_token
+        .as_ref()
+        .filter(|token| is_postfix(token.kind))
+        .and_then(|token| compiler.infix_parse_fns.get(&token.kind).copied())
+    {
+        compiler.next_token();
+        postfix(compiler);
+    }
+
+    compiler.patch_jump(null_jump);
+}
+
+// Operators continuing a chain of indexing and calls
+fn is_postfix(kind: TokenType) -> bool {
+    matches!(
+        kind,
+        TokenType::LeftBracket
+            | TokenType::LeftParen
+            | TokenType::Dot
+            | TokenType::Question
+            | TokenType::QuestionDot
+    )
+}
+
+// Compiles the index between brackets, leaving the right bracket as current token
+fn index_operand(compiler: &mut Compiler) -> bool {
+    //Consume left bracket
+    compiler.next_token();
+
+    compiler.expression(Precedence::Lowest);
+
+    compiler.expect_peek(TokenType::RightBracket)
 }
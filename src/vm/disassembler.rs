@@ -0,0 +1,89 @@
+use super::{
+    chunk::{Chunk, OpCode, Value},
+    object::Function,
+};
+
+impl Chunk {
+    // Lists the instructions of the chunk with their source line, the line is
+    // shown as | when it's the same as the previous instruction. Jumps show
+    // the offset they land on
+    pub fn disassemble(&self, name: &str) -> String {
+        let mut output = format!("== {} ==\n", name);
+
+        for (offset, instruction) in self.code.iter().enumerate() {
+            let line = self.get_line(offset);
+            let line = if offset > 0 && line == self.get_line(offset - 1) {
+                "   |".to_string()
+            } else {
+                format!("{:4}", line.unwrap_or(0))
+            };
+
+            output.push_str(&format!("{:04} {} {:?}", offset, line, instruction));
+            if let Some(constant) = self.referenced_constant(instruction) {
+                output.push_str(&format!(" '{}'", constant));
+            }
+            if let Some(target) = jump_destination(offset, instruction) {
+                output.push_str(&format!(" -> {:04}", target));
+            }
+            output.push('\n');
+        }
+
+        output
+    }
+
+    fn referenced_constant(&self, instruction: &OpCode) -> Option<&Value> {
+        match instruction {
+            OpCode::Constant(index)
+            | OpCode::Closure(index)
+            | OpCode::DefineGlobal(index)
+            | OpCode::GetGlobal(index)
+            | OpCode::SetGlobal(index)
+            | OpCode::Invoke(index, _) => self.get_constant(*index),
+            _ => None,
+        }
+    }
+}
+
+// Jump operands are relative to the next instruction
+fn jump_destination(offset: usize, instruction: &OpCode) -> Option<usize> {
+    match instruction {
+        OpCode::Jump(jump) | OpCode::JumpIfFalse(jump) | OpCode::JumpIfNull(jump) => {
+            (offset + 1).checked_add(*jump)
+        }
+        OpCode::Loop(jump) => (offset + 1).checked_sub(*jump),
+        _ => None,
+    }
+}
+
+// Lists the chunk followed by the functions defined in it, nested ones
+// included, in the order they appear
+pub fn disassemble_all(chunk: &Chunk, name: &str) -> String {
+    let mut output = chunk.disassemble(name);
+    for constant in &chunk.constants {
+        if let Value::Function(function) = constant {
+            output.push_str(&disassemble_all(&function.chunk, &function.name));
+        }
+    }
+    output
+}
+
+// Summary of the chunk followed by the functions defined in it, one line each
+// in the same order as disassemble_all
+pub fn summarize_all(chunk: &Chunk, name: &str) -> String {
+    let mut output = format!("{}: {}\n", name, chunk.summary());
+    for constant in &chunk.constants {
+        if let Value::Function(function) = constant {
+            output.push_str(&summarize_all(&function.chunk, &function.name));
+        }
+    }
+    output
+}
+
+// Searches the functions defined in the chunk, including nested ones
+pub fn find_function<'c>(chunk: &'c Chunk, name: &str) -> Option<&'c Function> {
+    chunk.constants.iter().find_map(|constant| match constant {
+        Value::Function(function) if function.name == name => Some(function),
+        Value::Function(function) => find_function(&function.chunk, name),
+        _ => None,
+    })
+}
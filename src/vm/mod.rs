@@ -1,7 +1,11 @@
+pub mod cache;
 pub mod chunk;
 pub mod compiler;
+pub mod disassembler;
+pub mod natives;
 pub mod object;
 pub mod scope;
+pub mod verifier;
 pub mod vm_impl;
 
 #[cfg(test)]
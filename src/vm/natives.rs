@@ -0,0 +1,641 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    io::Write,
+    rc::Rc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    chunk::Value,
+    object::{IteratorSource, NativeFn, NativeFunction, ValueIterator},
+    vm_impl::{is_falsey, VirtualMachine},
+};
+
+// Functions available to every program, user globals with the same name take precedence
+pub fn builtins() -> HashMap<String, Value> {
+    let natives: [(&'static str, usize, usize, NativeFn); 31] = [
+        ("len", 1, 1, len),
+        ("format", 1, usize::MAX, format),
+        ("pad_left", 2, 3, pad_left),
+        ("pad_right", 2, 3, pad_right),
+        ("clamp", 3, 3, clamp),
+        ("sign", 1, 1, sign),
+        ("random", 0, 0, random),
+        ("random_int", 2, 2, random_int),
+        ("seed", 1, 1, seed),
+        ("trim_start", 1, 1, trim_start),
+        ("trim_end", 1, 1, trim_end),
+        ("upper", 1, 1, upper),
+        ("lower", 1, 1, lower),
+        ("replace", 3, 3, replace),
+        ("starts_with", 2, 2, starts_with),
+        ("ends_with", 2, 2, ends_with),
+        ("parse_int", 1, 2, parse_int),
+        ("ord", 1, 1, ord),
+        ("chr", 1, 1, chr),
+        ("clock", 0, 0, clock),
+        ("time_ns", 0, 0, time_ns),
+        ("iter", 1, 1, iter),
+        ("next", 1, 1, next),
+        ("has_next", 1, 1, has_next),
+        ("all", 1, 1, all),
+        ("any", 1, 1, any),
+        ("map", 2, 2, map),
+        ("print", 1, 1, print),
+        ("println", 1, 1, println),
+        ("debug", 1, 1, debug),
+        ("type", 1, 1, type_of),
+    ];
+
+    natives
+        .into_iter()
+        .map(|(name, min_arity, max_arity, function)| {
+            let native = NativeFunction {
+                name,
+                min_arity,
+                max_arity,
+                function,
+            };
+            (name.to_string(), Value::NativeFunction(native))
+        })
+        .collect()
+}
+
+// Xorshift generator, good enough for scripts and reproducible through seed
+#[derive(Debug)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        // Xorshift gets stuck on a zero state
+        Random { state: seed.max(1) }
+    }
+
+    pub fn from_time() -> Random {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos() as u64);
+        Random::new(nanos)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    // Uniform in [0, 1) using the 53 bits a f64 can represent
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Source of time_ns, hosts can replace the steady clock to control time
+pub enum MonotonicClock {
+    Steady(Instant),
+    Custom(Box<dyn FnMut() -> u64>),
+}
+
+impl MonotonicClock {
+    fn now_ns(&mut self) -> u64 {
+        match self {
+            MonotonicClock::Steady(start) => start.elapsed().as_nanos() as u64,
+            MonotonicClock::Custom(clock) => clock(),
+        }
+    }
+}
+
+impl fmt::Debug for MonotonicClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MonotonicClock::Steady(start) => write!(f, "Steady({:?})", start),
+            MonotonicClock::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+fn expect_string<'v>(name: &str, value: &'v Value) -> Result<&'v str, String> {
+    match value {
+        Value::String(string) => Ok(string),
+        _ => Err(format!(
+            "{} expected a string but got {}",
+            name,
+            value.type_name()
+        )),
+    }
+}
+
+fn expect_number(name: &str, value: &Value) -> Result<f64, String> {
+    match value {
+        Value::Integer(integer) => Ok(*integer as f64),
+        Value::Number(number) => Ok(*number),
+        _ => Err(format!(
+            "{} expected a number but got {}",
+            name,
+            value.type_name()
+        )),
+    }
+}
+
+// Widest padding the builtins produce, so a script can't ask for more memory
+// than the host has
+const MAX_WIDTH: usize = 10_000;
+
+fn expect_count(name: &str, value: &Value) -> Result<usize, String> {
+    match value {
+        Value::Integer(integer) if *integer >= 0 => Ok(*integer as usize),
+        Value::Number(number) if *number >= 0.0 && number.fract() == 0.0 => Ok(*number as usize),
+        _ => Err(format!(
+            "{} expected a non-negative integer but got {}",
+            name, value
+        )),
+    }
+}
+
+// Returns the string and the fill needed to reach the width
+fn padding(name: &str, arguments: &[Value]) -> Result<(String, String), String> {
+    let string = expect_string(name, &arguments[0])?;
+    let width = expect_count(name, &arguments[1])?;
+    if width > MAX_WIDTH {
+        return Err(format!("{} width can't be more than {}", name, MAX_WIDTH));
+    }
+
+    let fill = match arguments.get(2) {
+        Some(fill) => {
+            let fill = expect_string(name, fill)?;
+            let mut chars = fill.chars();
+            match (chars.next(), chars.next()) {
+                (Some(fill), None) => fill,
+                _ => return Err(format!("{} expected a single character fill", name)),
+            }
+        }
+        None => ' ',
+    };
+
+    // Width is counted in chars, not bytes
+    let missing = width.saturating_sub(string.chars().count());
+    Ok((string.to_string(), fill.to_string().repeat(missing)))
+}
+
+fn pad_left(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let (string, fill) = padding("pad_left", arguments)?;
+    Ok(Value::String((fill + &string).into()))
+}
+
+fn pad_right(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let (string, fill) = padding("pad_right", arguments)?;
+    Ok(Value::String((string + &fill).into()))
+}
+
+// Replaces each {} in the template with the next argument. A placeholder
+// may carry a spec like {:>5}, {:.2} or {:08.2}, and {{ and }} are literal braces
+fn format(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let template = expect_string("format", &arguments[0])?;
+    let mut values = arguments[1..].iter();
+    let mut result = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let Some(end) = rest.find('}') else {
+                    return Err("format has an unclosed placeholder".to_string());
+                };
+                let placeholder = &rest[..end];
+                chars = rest[end + 1..].chars();
+
+                let spec = match placeholder.strip_prefix(':') {
+                    Some(spec) => FormatSpec::parse(spec)?,
+                    None if placeholder.is_empty() => FormatSpec::default(),
+                    None => return Err(format!("format got an invalid spec {{{}}}", placeholder)),
+                };
+                let Some(value) = values.next() else {
+                    return Err("format has more placeholders than arguments".to_string());
+                };
+                result.push_str(&spec.apply(value)?);
+            }
+            '}' => return Err("format has an unmatched }".to_string()),
+            c => result.push(c),
+        }
+    }
+
+    if values.next().is_some() {
+        return Err("format has more arguments than placeholders".to_string());
+    }
+    Ok(Value::String(result.into()))
+}
+
+#[derive(Debug, Default)]
+struct FormatSpec {
+    align: Option<char>,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl FormatSpec {
+    // [align][0][width][.precision], where align is one of < > ^. Width and
+    // precision go up to MAX_WIDTH
+    fn parse(spec: &str) -> Result<FormatSpec, String> {
+        let invalid = || format!("format got an invalid spec {{:{}}}", spec);
+        let mut rest = spec;
+        let mut format_spec = FormatSpec::default();
+
+        if let Some(align @ ('<' | '>' | '^')) = rest.chars().next() {
+            format_spec.align = Some(align);
+            rest = &rest[1..];
+        }
+        if let Some(after) = rest.strip_prefix('0') {
+            format_spec.zero = true;
+            rest = after;
+        }
+
+        let (width, precision) = match rest.split_once('.') {
+            Some((width, precision)) => (width, Some(precision)),
+            None => (rest, None),
+        };
+        if !width.is_empty() {
+            format_spec.width = width.parse().map_err(|_| invalid())?;
+        }
+        if let Some(precision) = precision {
+            if precision.is_empty() {
+                return Err(invalid());
+            }
+            format_spec.precision = Some(precision.parse().map_err(|_| invalid())?);
+        }
+        if format_spec.width > MAX_WIDTH || format_spec.precision > Some(MAX_WIDTH) {
+            return Err(invalid());
+        }
+        Ok(format_spec)
+    }
+
+    // Numbers align right and strings left unless the spec says otherwise,
+    // zero padding goes after the sign
+    fn apply(&self, value: &Value) -> Result<String, String> {
+        let is_number = value.is_number();
+        let text = match (self.precision, value) {
+            (Some(precision), Value::Integer(integer)) => {
+                format!("{:.*}", precision, *integer as f64)
+            }
+            (Some(precision), Value::Number(number)) => format!("{:.*}", precision, number),
+            (Some(_), value) => {
+                return Err(format!(
+                    "format precision expected a number but got {}",
+                    value.type_name()
+                ))
+            }
+            (None, value) => value.to_string(),
+        };
+
+        let missing = self.width.saturating_sub(text.chars().count());
+        if self.zero && is_number {
+            let (sign, digits) = match text.strip_prefix('-') {
+                Some(digits) => ("-", digits),
+                None => ("", text.as_str()),
+            };
+            return Ok(format!("{}{}{}", sign, "0".repeat(missing), digits));
+        }
+
+        let align = self.align.unwrap_or(if is_number { '>' } else { '<' });
+        let (before, after) = match align {
+            '>' => (missing, 0),
+            '^' => (missing / 2, missing - missing / 2),
+            _ => (0, missing),
+        };
+        Ok(format!(
+            "{}{}{}",
+            " ".repeat(before),
+            text,
+            " ".repeat(after)
+        ))
+    }
+}
+
+// Strings are measured in chars
+fn len(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let len = match &arguments[0] {
+        Value::String(string) => string.chars().count(),
+        Value::Array(elements) => elements.len(),
+        value => {
+            return Err(format!(
+                "len expected a string or an array but got {}",
+                value.type_name()
+            ))
+        }
+    };
+    Ok(Value::Integer(len as i64))
+}
+
+// Integers stay integers when every argument is one
+#[allow(clippy::neg_cmp_op_on_partial_ord)]
+fn clamp(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let value = expect_number("clamp", &arguments[0])?;
+    let low = expect_number("clamp", &arguments[1])?;
+    let high = expect_number("clamp", &arguments[2])?;
+
+    // Written negated so a NaN bound fails here instead of panicking in clamp
+    if !(low <= high) {
+        return Err(format!(
+            "clamp expected lo <= hi but got {} > {}",
+            low, high
+        ));
+    }
+    if let [Value::Integer(value), Value::Integer(low), Value::Integer(high)] = arguments {
+        return Ok(Value::Integer(*value.clamp(low, high)));
+    }
+    Ok(Value::Number(value.clamp(low, high)))
+}
+
+fn sign(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let value = expect_number("sign", &arguments[0])?;
+
+    // Unlike f64::signum zero has no sign
+    let sign = if value > 0.0 {
+        1
+    } else if value < 0.0 {
+        -1
+    } else {
+        0
+    };
+    Ok(Value::Integer(sign))
+}
+
+fn random(vm: &mut VirtualMachine, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(vm.random.next_f64()))
+}
+
+fn expect_integer(name: &str, value: &Value) -> Result<i64, String> {
+    match value {
+        Value::Integer(integer) => Ok(*integer),
+        Value::Number(number) if number.fract() == 0.0 => Ok(*number as i64),
+        _ => Err(format!("{} expected an integer but got {}", name, value)),
+    }
+}
+
+// Both bounds are included
+fn random_int(vm: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let low = expect_integer("random_int", &arguments[0])?;
+    let high = expect_integer("random_int", &arguments[1])?;
+
+    if low > high {
+        return Err(format!(
+            "random_int expected lo <= hi but got {} > {}",
+            low, high
+        ));
+    }
+    let range = high.abs_diff(low).saturating_add(1);
+    let offset = vm.random.next_u64() % range;
+    Ok(Value::Integer(low.wrapping_add_unsigned(offset)))
+}
+
+fn seed(vm: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let seed = expect_integer("seed", &arguments[0])?;
+    vm.random = Random::new(seed as u64);
+    Ok(Value::Null)
+}
+
+fn trim_start(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let string = expect_string("trim_start", &arguments[0])?;
+    Ok(Value::String(string.trim_start().into()))
+}
+
+fn trim_end(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let string = expect_string("trim_end", &arguments[0])?;
+    Ok(Value::String(string.trim_end().into()))
+}
+
+fn upper(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let string = expect_string("upper", &arguments[0])?;
+    Ok(Value::String(string.to_uppercase().into()))
+}
+
+fn lower(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let string = expect_string("lower", &arguments[0])?;
+    Ok(Value::String(string.to_lowercase().into()))
+}
+
+fn replace(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let string = expect_string("replace", &arguments[0])?;
+    let from = expect_string("replace", &arguments[1])?;
+    let to = expect_string("replace", &arguments[2])?;
+
+    // An empty pattern would match between every char
+    if from.is_empty() {
+        return Err("replace expected a non-empty pattern".to_string());
+    }
+    Ok(Value::String(string.replace(from, to).into()))
+}
+
+fn starts_with(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let string = expect_string("starts_with", &arguments[0])?;
+    let prefix = expect_string("starts_with", &arguments[1])?;
+    Ok(Value::Boolean(string.starts_with(prefix)))
+}
+
+fn ends_with(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let string = expect_string("ends_with", &arguments[0])?;
+    let suffix = expect_string("ends_with", &arguments[1])?;
+    Ok(Value::Boolean(string.ends_with(suffix)))
+}
+
+// The base defaults to 10
+fn parse_int(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let string = expect_string("parse_int", &arguments[0])?;
+    let base = match arguments.get(1) {
+        Some(base) => expect_integer("parse_int", base)?,
+        None => 10,
+    };
+
+    if !(2..=36).contains(&base) {
+        return Err(format!(
+            "parse_int expected a base between 2 and 36 but got {}",
+            base
+        ));
+    }
+    match i64::from_str_radix(string, base as u32) {
+        Ok(number) => Ok(Value::Integer(number)),
+        Err(_) => Err(format!("parse_int can't parse {} in base {}", string, base)),
+    }
+}
+
+fn ord(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let string = expect_string("ord", &arguments[0])?;
+    let mut chars = string.chars();
+    match (chars.next(), chars.next()) {
+        (Some(char), None) => Ok(Value::Integer(char as i64)),
+        _ => Err(format!(
+            "ord expected a single character but got {}",
+            string
+        )),
+    }
+}
+
+fn chr(_: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+    let code = expect_integer("chr", &arguments[0])?;
+    // Surrogates and values past the Unicode range aren't chars
+    match u32::try_from(code).ok().and_then(char::from_u32) {
+        Some(char) => Ok(Value::String(char.to_string().into())),
+        None => Err(format!("chr expected a valid code point but got {}", code)),
+    }
+}
+
+// Seconds since the Unix epoch, affected by changes to the system clock
+fn clock(_: &mut VirtualMachine, _: &[Value]) -> Result<Value, String> {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |duration| duration.as_secs_f64());
+    Ok(Value::Number(seconds))
+}
+
+// Nanoseconds from a clock that never goes back, for measuring durations
+fn time_ns(vm: &mut VirtualMachine, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Integer(vm.monotonic_clock.now_ns() as i64))
+}
+
+// Iterators of an iterator are the iterator itself, so iter can wrap any
+// iterable argument
+fn iter(_: &mut VirtualMachine, args: &[Value]) -> Result<Value, String> {
+    let source = match &args[0] {
+        Value::Array(elements) => IteratorSource::Array(elements.clone()),
+        Value::String(string) => IteratorSource::String(string.chars().collect()),
+        Value::Map(map) => IteratorSource::Map(map.clone()),
+        Value::Generator(generator) => IteratorSource::Generator(generator.clone()),
+        Value::Iterator(_) => return Ok(args[0].clone()),
+        v => return Err(format!("{} is not iterable", v.type_name())),
+    };
+    Ok(Value::Iterator(Rc::new(RefCell::new(ValueIterator::new(
+        source,
+    )))))
+}
+
+// Resumes a generator, null once it has finished
+fn next(vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Generator(generator) => vm.resume(generator.clone()),
+        Value::Iterator(iterator) => Ok(vm.next_item(iterator)?.unwrap_or(Value::Null)),
+        v => Err(format!(
+            "next expected a generator or an iterator but got {}",
+            v.type_name()
+        )),
+    }
+}
+
+fn has_next(vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Iterator(iterator) => Ok(Value::Boolean(vm.has_next(iterator)?)),
+        v => Err(format!(
+            "has_next expected an iterator but got {}",
+            v.type_name()
+        )),
+    }
+}
+
+fn all(vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Boolean(!find_truthiness(
+        vm, "all", &args[0], false,
+    )?))
+}
+
+fn any(vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Boolean(find_truthiness(vm, "any", &args[0], true)?))
+}
+
+// Array with the results of calling the function on each element of the array
+// or iterator, in order
+fn map(vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, String> {
+    let mut results = Vec::new();
+    match &args[0] {
+        Value::Array(elements) => {
+            for element in elements {
+                results.push(vm.call_value(args[1].clone(), std::slice::from_ref(element))?);
+            }
+        }
+        Value::Iterator(iterator) => {
+            while let Some(item) = vm.next_item(iterator)? {
+                results.push(vm.call_value(args[1].clone(), &[item])?);
+            }
+        }
+        v => {
+            return Err(format!(
+                "map expected an array or an iterator but got {}",
+                v.type_name()
+            ))
+        }
+    }
+    Ok(Value::Array(results))
+}
+
+// Whether an element of the array or iterator is truthy, or falsey when
+// `truthy` is false. Stops at the first one, leaving the rest of an iterator
+// unread
+fn find_truthiness(
+    vm: &mut VirtualMachine,
+    name: &str,
+    collection: &Value,
+    truthy: bool,
+) -> Result<bool, String> {
+    match collection {
+        Value::Array(elements) => Ok(elements.iter().any(|element| is_falsey(element) != truthy)),
+        Value::Iterator(iterator) => {
+            while let Some(item) = vm.next_item(iterator)? {
+                if is_falsey(&item) != truthy {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        v => Err(format!(
+            "{} expected an array or an iterator but got {}",
+            name,
+            v.type_name()
+        )),
+    }
+}
+
+// Writes the value to stdout, the only way a script run from a file prints
+fn print(vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, String> {
+    let writer = &mut vm.output_writer.0;
+    write!(writer, "{}", args[0]).map_err(|error| error.to_string())?;
+    writer.flush().map_err(|error| error.to_string())?;
+    Ok(Value::Null)
+}
+
+fn println(vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, String> {
+    let writer = &mut vm.output_writer.0;
+    writeln!(writer, "{}", args[0]).map_err(|error| error.to_string())?;
+    writer.flush().map_err(|error| error.to_string())?;
+    Ok(Value::Null)
+}
+
+// Writes the value, its type and the line of the call to the error writer,
+// returning the value so it can wrap any expression
+fn debug(vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, String> {
+    let line = vm.current_line().unwrap_or_default();
+    writeln!(
+        vm.error_writer.0,
+        "[line {}] {}: {}",
+        line,
+        args[0],
+        args[0].type_name()
+    )
+    .map_err(|error| error.to_string())?;
+    Ok(args[0].clone())
+}
+
+fn type_of(_: &mut VirtualMachine, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::String(args[0].type_name().into()))
+}
@@ -1,4 +1,13 @@
-use super::chunk::Chunk;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use super::{
+    chunk::{Chunk, Value},
+    vm_impl::VirtualMachine,
+};
 
 #[derive(Debug)]
 pub enum FunctionType {
@@ -6,20 +15,36 @@ pub enum FunctionType {
     Function(String),
 }
 
+// Where a closure finds a captured variable when it is created
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpvalueRef {
+    // A local of the enclosing function, or one of its upvalues
+    pub is_local: bool,
+    pub index: usize,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Function {
     pub arity: usize,
     pub chunk: Chunk,
     pub name: String,
+    pub upvalues: Vec<UpvalueRef>,
+    // Declared with fn*, calling it creates a generator instead of running it
+    pub is_generator: bool,
 }
 
 impl Function {
     pub fn new() -> Self {
-        Function {
-            arity: 0,
-            chunk: Chunk::new(),
-            name: String::new(),
-        }
+        Self::default()
+    }
+
+    // Both behave the same when called, they may come from different lines
+    pub fn same_body(&self, other: &Function) -> bool {
+        self.arity == other.arity
+            && self.name == other.name
+            && self.upvalues == other.upvalues
+            && self.is_generator == other.is_generator
+            && self.chunk.same_code(&other.chunk)
     }
 }
 
@@ -29,6 +54,250 @@ impl Default for Function {
             arity: 0,
             chunk: Chunk::new(),
             name: String::new(),
+            upvalues: vec![],
+            is_generator: false,
+        }
+    }
+}
+
+// Captured variable, it points to a stack slot while the variable is alive
+// and holds the value once the slot is popped
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl Closure {
+    pub fn new(function: Rc<Function>) -> Self {
+        Closure {
+            function,
+            upvalues: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeneratorState {
+    Suspended,
+    Running,
+    Done,
+}
+
+// Call of a generator function, suspended at its start or at a yield
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generator {
+    pub closure: Closure,
+    pub pc: usize,
+    // Slots of the frame, from the callee to its last temporary
+    pub slots: Vec<Value>,
+    // Locals captured by closures, by slot in the frame. They're closed while
+    // suspended and open again on resume, so both sides keep sharing them
+    pub captured: Vec<(usize, Rc<RefCell<Upvalue>>)>,
+    pub state: GeneratorState,
+}
+
+// Walk over a collection, shared by its copies so advancing one advances
+// all of them. Items of maps are [key, value] arrays
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueIterator {
+    source: IteratorSource,
+    position: usize,
+    // Item read ahead by has_next, a generator only knows if it has another
+    // item by running up to it
+    pub peeked: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IteratorSource {
+    Array(Vec<Value>),
+    String(Vec<char>),
+    Map(Map),
+    Generator(Rc<RefCell<Generator>>),
+}
+
+impl ValueIterator {
+    pub fn new(source: IteratorSource) -> ValueIterator {
+        ValueIterator {
+            source,
+            position: 0,
+            peeked: None,
+        }
+    }
+
+    pub fn generator(&self) -> Option<Rc<RefCell<Generator>>> {
+        match &self.source {
+            IteratorSource::Generator(generator) => Some(generator.clone()),
+            _ => None,
+        }
+    }
+
+    // Next item of a collection, None once it's exhausted or for generators,
+    // which are resumed by the VM
+    pub fn advance(&mut self) -> Option<Value> {
+        let item = match &self.source {
+            IteratorSource::Array(elements) => elements.get(self.position).cloned(),
+            IteratorSource::String(chars) => chars
+                .get(self.position)
+                .map(|c| Value::String(c.to_string().into())),
+            IteratorSource::Map(map) => map
+                .entries()
+                .get(self.position)
+                .map(|(key, value)| Value::Array(vec![key.clone(), value.clone()])),
+            IteratorSource::Generator(_) => None,
+        };
+        if item.is_some() {
+            self.position += 1;
         }
+        item
+    }
+}
+
+// Map keeping its entries in insertion order, keys are found through a hash
+// index. Integers are exact keys and whole floats share them, other floats
+// are compared by their bits, so 0 and -0 are different keys and NaN can be
+// a key
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    entries: Vec<(Value, Value)>,
+    positions: HashMap<MapKey, usize>,
+}
+
+// Hashable form of the values allowed as keys
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum MapKey {
+    Integer(i64),
+    // Bits of a float with no equal integer
+    Number(u64),
+    String(Rc<str>),
+    Boolean(bool),
+    Null,
+}
+
+impl MapKey {
+    fn new(key: &Value) -> Result<MapKey, String> {
+        match key {
+            Value::Integer(n) => Ok(MapKey::Integer(*n)),
+            // Whole floats share the keys of the equal integers, as 1 == 1.0.
+            // -0 keeps its own key
+            Value::Number(n)
+                if n.fract() == 0.0
+                    && (-9223372036854775808.0..9223372036854775808.0).contains(n)
+                    && !(*n == 0.0 && n.is_sign_negative()) =>
+            {
+                Ok(MapKey::Integer(*n as i64))
+            }
+            Value::Number(n) => Ok(MapKey::Number(n.to_bits())),
+            Value::String(s) => Ok(MapKey::String(s.clone())),
+            Value::Boolean(b) => Ok(MapKey::Boolean(*b)),
+            Value::Null => Ok(MapKey::Null),
+            v => Err(format!("{} can't be a map key", v.type_name())),
+        }
+    }
+}
+
+impl Map {
+    pub fn new() -> Map {
+        Map::default()
+    }
+
+    pub fn get(&self, key: &Value) -> Result<Option<&Value>, String> {
+        let position = self.positions.get(&MapKey::new(key)?);
+        Ok(position.map(|position| &self.entries[*position].1))
+    }
+
+    // Replaces the value of an existing key, keeping its position, or appends it
+    pub fn insert(&mut self, key: Value, value: Value) -> Result<(), String> {
+        match self.positions.get(&MapKey::new(&key)?) {
+            Some(position) => self.entries[*position].1 = value,
+            None => {
+                self.positions
+                    .insert(MapKey::new(&key)?, self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[(Value, Value)] {
+        &self.entries
+    }
+}
+
+// Maps with the same entries in the same order are equal
+impl PartialEq for Map {
+    fn eq(&self, other: &Map) -> bool {
+        self.entries == other.entries
+    }
+}
+
+// Smallest pool worth pruning
+const INTERNER_MIN_PRUNE: usize = 256;
+
+// Pool of the strings made while running, so equal strings share one
+// allocation and compare by address. Strings only the pool still holds are
+// dropped each time it doubles
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<Rc<str>>,
+    prune_at: usize,
+}
+
+impl Interner {
+    pub fn intern(&mut self, string: &str) -> Rc<str> {
+        if let Some(interned) = self.strings.get(string) {
+            return interned.clone();
+        }
+
+        if self.strings.len() >= self.prune_at {
+            self.strings
+                .retain(|interned| Rc::strong_count(interned) > 1);
+            self.prune_at = (self.strings.len() * 2).max(INTERNER_MIN_PRUNE);
+        }
+        let interned: Rc<str> = Rc::from(string);
+        self.strings.insert(interned.clone());
+        interned
+    }
+
+    // Interns the string constants of the chunk and the functions in it
+    pub fn intern_constants(&mut self, chunk: &mut Chunk) {
+        for constant in &mut chunk.constants {
+            match constant {
+                Value::String(string) => *string = self.intern(string),
+                Value::Function(function) => self.intern_constants(&mut function.chunk),
+                _ => (),
+            }
+        }
+    }
+
+    // Strings in the pool, live or not yet pruned
+    #[cfg(test)]
+    pub fn count(&self) -> usize {
+        self.strings.len()
+    }
+}
+
+pub type NativeFn = fn(&mut VirtualMachine, &[Value]) -> Result<Value, String>;
+
+// Function implemented by the host
+#[derive(Debug, Clone)]
+pub struct NativeFunction {
+    pub name: &'static str,
+    // Optional parameters go last, so the arity is a range
+    pub min_arity: usize,
+    pub max_arity: usize,
+    pub function: NativeFn,
+}
+
+// Natives are identified by their name, function pointers can't be compared reliably
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
     }
 }
@@ -4,9 +4,13 @@ use crate::common::lexer::token::Token;
 pub struct Local {
     pub name: Token,
     pub depth: i32,
+    // Captured by a closure, so it has to be closed instead of popped
+    pub is_captured: bool,
+    // Read or assigned after its declaration
+    pub is_used: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Scope {
     pub locals: Vec<Local>,
     pub depth: i32,
@@ -24,27 +28,38 @@ impl Scope {
         self.depth += 1;
     }
 
-    pub fn end_scope(&mut self) -> u32 {
+    // Removes the variables out of the current scope, returning them from
+    // the last declared to the first
+    pub fn end_scope(&mut self) -> Vec<Local> {
         self.depth -= 1;
 
-        // Remove variables that are out of the current scope
-        let mut counter = 0;
-        self.locals.retain(|local| {
-            if local.depth > self.depth {
-                counter += 1;
-                false
-            } else {
-                true
-            }
-        });
-
-        counter
+        let first_removed = self
+            .locals
+            .iter()
+            .position(|local| local.depth > self.depth)
+            .unwrap_or(self.locals.len());
+
+        self.locals
+            .split_off(first_removed)
+            .into_iter()
+            .rev()
+            .collect()
+    }
+
+    // Variables deeper than `depth`, from the last declared to the first
+    pub fn locals_deeper_than(&self, depth: i32) -> impl Iterator<Item = &Local> {
+        self.locals
+            .iter()
+            .rev()
+            .take_while(move |local| local.depth > depth)
     }
 
     pub fn add_local(&mut self, name: Token) {
         let local = Local {
             name,
             depth: -1, // Mark uninitialized
+            is_captured: false,
+            is_used: false,
         };
         self.locals.push(local);
     }
@@ -57,4 +72,14 @@ impl Scope {
             .position(|local| local.name.lexeme == *name.lexeme && local.depth > -1)
             .map(|rev_index| self.locals.len() - 1 - rev_index)
     }
+
+    // The innermost variable with the name is declared but its value isn't
+    // compiled yet
+    pub fn is_uninitialized(&self, name: &Token) -> bool {
+        self.locals
+            .iter()
+            .rev()
+            .find(|local| local.name.lexeme == name.lexeme)
+            .is_some_and(|local| local.depth == -1)
+    }
 }
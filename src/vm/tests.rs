@@ -1,13 +1,22 @@
 #[cfg(test)]
 mod test {
-    use std::{cell::RefCell, mem::take, rc::Rc};
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        io::{self, Write},
+        mem::take,
+        rc::Rc,
+        time::{Duration, Instant},
+    };
 
     use crate::{
         common::lexer::lexer_impl::Lexer,
         vm::{
-            chunk::{OpCode, Value},
+            cache::CompileCache,
+            chunk::{Chunk, OpCode, Value, OPCODE_NAMES},
             compiler::Compiler,
-            object::FunctionType,
+            object::{Function, FunctionType, Map, NativeFunction, UpvalueRef},
+            verifier::verify_stack_effect,
             vm_impl::{InterpretResult, VirtualMachine},
         },
     };
@@ -43,6 +52,24 @@ mod test {
         assert_eq!(vm.stack.get(1), Some(&Value::Number(result)));
     }
 
+    fn test_integer(input: &str, result: i64) {
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile_one_statement();
+
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(
+            vm.interpret(),
+            InterpretResult::Ok,
+            "VM should run without errors"
+        );
+
+        assert_eq!(vm.stack.get(1), Some(&Value::Integer(result)));
+    }
+
     fn test_bool(input: &str, result: bool) {
         let lexer = Lexer::new(input);
         let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
@@ -79,12 +106,12 @@ mod test {
         );
         println!("{}", input);
 
-        assert_eq!(vm.stack.get(1), Some(&Value::String(result)));
+        assert_eq!(vm.stack.get(1), Some(&Value::String(result.into())));
     }
 
     #[test]
     fn constant() {
-        test_number("1", 1.0);
+        test_integer("1", 1);
     }
 
     #[test]
@@ -124,22 +151,113 @@ mod test {
 
     #[test]
     fn add() {
-        test_number("10+5", 15.0);
+        test_integer("10+5", 15);
     }
 
     #[test]
     fn subtract() {
-        test_number("10-5", 5.0);
+        test_integer("10-5", 5);
     }
 
     #[test]
     fn multiply() {
-        test_number("10*5", 50.0);
+        test_integer("10*5", 50);
     }
 
     #[test]
     fn divide() {
-        test_number("10/5", 2.0);
+        test_integer("10/5", 2);
+    }
+
+    #[test]
+    fn modulo() {
+        test_integer("10 % 3", 1);
+        test_integer("-7 % 3", -1);
+        test_number("7.5 % 2", 1.5);
+        test_integer("2 + 10 % 4 * 3", 8);
+    }
+
+    #[test]
+    fn power() {
+        test_integer("2 ** 10", 1024);
+        test_integer("2 ** 3 ** 2", 512);
+        test_integer("-2 ** 2", -4);
+        test_integer("2 * 3 ** 2", 18);
+        test_number("2 ** -1", 0.5);
+        test_number("4.0 ** 0.5", 2.0);
+
+        // Variables keep the operation at runtime
+        let vm = run_globals("let two = 2\nlet three = 3\nlet result = two ** three ** two");
+        assert_eq!(vm.globals.get("result"), Some(&Value::Integer(512)));
+
+        let lexer = Lexer::new("let big = 10\nbig ** 19");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn modulo_by_zero() {
+        let lexer = Lexer::new("let zero = 0\n5 % zero");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+
+        let lexer = Lexer::new("5 % 0");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        assert!(compiler.current_chunk().code.contains(&OpCode::Modulo));
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn integers_and_floats() {
+        let input = "let sum = 2 + 3
+        let mixed = 2 + 0.5
+        let whole_float = 1.5 * 2
+        let exact = 10 / 5
+        let inexact = 10 / 4
+        let remainder = 7.5 % 2
+        let equal = 1 == 1.0
+        let less = 1 < 1.5
+        let compared = 2 >= 2.0";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Integer(5)));
+        assert_eq!(vm.globals.get("mixed"), Some(&Value::Number(2.5)));
+        assert_eq!(vm.globals.get("whole_float"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.globals.get("exact"), Some(&Value::Integer(2)));
+        assert_eq!(vm.globals.get("inexact"), Some(&Value::Number(2.5)));
+        assert_eq!(vm.globals.get("remainder"), Some(&Value::Number(1.5)));
+        for name in ["equal", "less", "compared"] {
+            assert_eq!(
+                vm.globals.get(name),
+                Some(&Value::Boolean(true)),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let lexer = Lexer::new("let big = 9223372036854775807\nbig + 1");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
     }
 
     #[test]
@@ -205,6 +323,31 @@ mod test {
         }
     }
 
+    #[test]
+    fn string_comparison() {
+        let tests = [
+            ("\"apple\" < \"banana\"", true),
+            ("\"b\" > \"a\"", true),
+            ("\"a\" >= \"a\"", true),
+            ("\"ab\" <= \"a\"", false),
+            ("\"Z\" < \"a\"", true),
+        ];
+
+        for (input, result) in tests {
+            test_bool(input, result);
+        }
+
+        for input in ["\"1\" < 2", "1 >= \"1\""] {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError, "{}", input);
+        }
+    }
+
     #[test]
     fn string_literal() {
         test_string("\"hola\"", "hola".to_string());
@@ -234,7 +377,7 @@ mod test {
             "VM should run without errors"
         );
 
-        assert_eq!(vm.globals.get("a"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(1)));
     }
 
     #[test]
@@ -256,7 +399,7 @@ mod test {
             "VM should run without errors"
         );
 
-        assert_eq!(vm.globals.get("b"), Some(&Value::Number(4.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Integer(4)));
     }
 
     #[test]
@@ -278,7 +421,7 @@ mod test {
             "VM should run without errors"
         );
 
-        assert_eq!(vm.globals.get("a"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(3)));
     }
 
     #[test]
@@ -300,7 +443,7 @@ mod test {
 
         assert_eq!(
             compiler.current_chunk().constants.first(),
-            Some(&Value::Number(14.0))
+            Some(&Value::Integer(14))
         );
 
         let mut vm = VirtualMachine::new(take(&mut compiler.function));
@@ -332,8 +475,7 @@ mod test {
             vec![
                 OpCode::Constant(0),
                 OpCode::GetLocal(1),
-                OpCode::GetLocal(2),
-                OpCode::Pop,
+                // The unused read of b is elided
                 OpCode::Pop,
                 OpCode::Pop,
                 OpCode::Null,
@@ -400,7 +542,7 @@ mod test {
             if true {
                 a = 2
             }",
-                &Value::Number(2.0),
+                &Value::Integer(2),
             ),
             // Caso 2: if false
             (
@@ -409,7 +551,7 @@ mod test {
             if false {
                 a = 2
             }",
-                &Value::Number(1.0),
+                &Value::Integer(1),
             ),
             // Caso 3: if true else
             (
@@ -420,7 +562,7 @@ mod test {
             } else {
                 a = 3
             }",
-                &Value::Number(2.0),
+                &Value::Integer(2),
             ),
             // Caso 4: if false else
             (
@@ -431,7 +573,7 @@ mod test {
             } else {
                 a = 3
             }",
-                &Value::Number(3.0),
+                &Value::Integer(3),
             ),
         ];
 
@@ -479,7 +621,7 @@ mod test {
             "VM should run without errors"
         );
 
-        assert_eq!(vm.globals.get("a"), Some(&Value::Number(10.0)));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(10)));
     }
 
     #[test]
@@ -502,6 +644,47 @@ mod test {
         );
     }
 
+    #[test]
+    fn top_level_return_ends_script() {
+        let input = "let a = 1
+        return 5
+        a = 2";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        compiler.compile();
+
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(
+            vm.interpret(),
+            InterpretResult::Ok,
+            "VM should run without errors"
+        );
+
+        assert_eq!(vm.stack.last(), Some(&Value::Integer(5)));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(1)));
+    }
+
+    #[test]
+    fn return_outside_function() {
+        let lexer = Lexer::new("return 5");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        assert_eq!(
+            compiler.compile_expression(),
+            InterpretResult::CompileError,
+            "Compiler should reject return outside a function"
+        );
+        assert_eq!(
+            compiler.errors[0].message,
+            "Can't return outside a function, found return with lexeme return"
+        );
+    }
+
     #[test]
     fn function_declaration_and_call_without_arguments() {
         let input = "fn add() {
@@ -524,7 +707,7 @@ mod test {
             "VM should run without errors"
         );
 
-        assert_eq!(vm.globals.get("result"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.globals.get("result"), Some(&Value::Integer(1)));
     }
 
     #[test]
@@ -549,6 +732,3135 @@ mod test {
             "VM should run without errors"
         );
 
-        assert_eq!(vm.globals.get("result"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.globals.get("result"), Some(&Value::Integer(3)));
+    }
+
+    #[test]
+    fn lambda_assigned_to_variable() {
+        let input = "let add_one = fn(x) {
+            return x + 1
+        }
+        let result = add_one(2)";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        compiler.compile();
+
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(
+            vm.interpret(),
+            InterpretResult::Ok,
+            "VM should run without errors"
+        );
+
+        assert_eq!(vm.globals.get("result"), Some(&Value::Integer(3)));
+    }
+
+    #[test]
+    fn lambda_passed_as_argument() {
+        let input = "fn apply(f, x) {
+            return f(x)
+        }
+        let result = apply(fn(x) {
+            return x * 2
+        }, 5)";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        compiler.compile();
+
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(
+            vm.interpret(),
+            InterpretResult::Ok,
+            "VM should run without errors"
+        );
+
+        assert_eq!(vm.globals.get("result"), Some(&Value::Integer(10)));
+    }
+
+    #[test]
+    fn lambda_passed_to_map() {
+        let input = "let offset = 10
+        let doubled = map([1, 2, 3], fn(x) {
+            return x * 2
+        })
+        let shifted = map(iter([1, 2]), fn(x) {
+            return x + offset
+        })
+        let empty = map([], fn(x) {
+            return x
+        })";
+
+        let vm = run_globals(input);
+
+        assert_eq!(
+            vm.globals.get("doubled"),
+            Some(&Value::Array(vec![
+                Value::Integer(2),
+                Value::Integer(4),
+                Value::Integer(6)
+            ]))
+        );
+        assert_eq!(
+            vm.globals.get("shifted"),
+            Some(&Value::Array(vec![Value::Integer(11), Value::Integer(12)]))
+        );
+        assert_eq!(vm.globals.get("empty"), Some(&Value::Array(vec![])));
+    }
+
+    #[test]
+    fn stack_effect_balanced_function() {
+        let input = "fn max(a, b) {
+            if a > b {
+                return a
+            }
+            let c = b
+            return c
+        }
+        let result = max(1, 2)";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        compiler.compile();
+
+        check_compiler_errors(&compiler);
+
+        assert_eq!(verify_stack_effect(compiler.current_chunk()), Ok(()));
+    }
+
+    #[test]
+    fn stack_effect_corrupted_emission() {
+        let mut chunk = Chunk::new();
+        let index = chunk.add_constant(Value::Integer(1));
+        chunk.write(OpCode::Constant(index), 1);
+        chunk.write(OpCode::Pop, 1);
+        // Extra pop without a matching push
+        chunk.write(OpCode::Pop, 1);
+        chunk.write(OpCode::Null, 1);
+        chunk.write(OpCode::Return, 1);
+
+        assert!(verify_stack_effect(&chunk).is_err());
+
+        // Branches that leave different depths
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::True, 1);
+        chunk.write(OpCode::JumpIfFalse(1), 1);
+        chunk.write(OpCode::Null, 1);
+        chunk.write(OpCode::Pop, 1);
+
+        assert!(verify_stack_effect(&chunk).is_err());
+    }
+
+    #[test]
+    fn loop_before_chunk_start() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Null, 1);
+        chunk.write(OpCode::Loop(5), 1);
+
+        assert!(verify_stack_effect(&chunk).is_err());
+
+        let mut vm = VirtualMachine::new(Function {
+            chunk,
+            ..Default::default()
+        });
+
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(vm.runtime_error, Some("invalid loop target".to_string()));
+    }
+
+    #[test]
+    fn jump_past_chunk_end() {
+        for jump in [OpCode::Jump(3), OpCode::JumpIfFalse(3)] {
+            let mut chunk = Chunk::new();
+            chunk.write(OpCode::False, 1);
+            chunk.write(jump, 1);
+            chunk.write(OpCode::Return, 1);
+
+            assert!(verify_stack_effect(&chunk).is_err());
+
+            let mut vm = VirtualMachine::new(Function {
+                chunk,
+                ..Default::default()
+            });
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some("invalid jump target".to_string()));
+        }
+    }
+
+    #[test]
+    fn watchdog_interrupts_infinite_loop() {
+        let input = "let a = 0
+        while true {
+            a = a + 1
+        }";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        compiler.compile();
+
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        let mut calls = 0;
+        vm.set_watchdog(
+            10,
+            Box::new(move || {
+                calls += 1;
+                calls < 5
+            }),
+        );
+
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(vm.runtime_error, Some("interrupted".to_string()));
+        assert!(matches!(vm.globals.get("a"), Some(Value::Integer(n)) if *n > 0));
+    }
+
+    #[test]
+    fn equality_modes() {
+        let tests = [
+            ("1 == \"1\"", false, true),
+            ("null == false", false, false),
+            ("null == \"null\"", false, true),
+            ("1 != \"1\"", true, false),
+            ("1 == 1", true, true),
+        ];
+
+        for (input, strict, loose) in tests {
+            for (loose_equality, result) in [(false, strict), (true, loose)] {
+                let lexer = Lexer::new(input);
+                let mut compiler =
+                    Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+                compiler.compile_one_statement();
+
+                check_compiler_errors(&compiler);
+
+                let mut vm = VirtualMachine::new(take(&mut compiler.function));
+                vm.loose_equality = loose_equality;
+
+                assert_eq!(
+                    vm.interpret(),
+                    InterpretResult::Ok,
+                    "VM should run without errors"
+                );
+
+                assert_eq!(
+                    vm.stack.get(1),
+                    Some(&Value::Boolean(result)),
+                    "input: {} loose: {}",
+                    input,
+                    loose_equality
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn compound_equality() {
+        let tests = [
+            ("[1, 2] == [1, 2]", true),
+            ("[1, 2] == [1, 3]", false),
+            ("[1, 2] == [1, 2, 3]", false),
+            ("[1, [2, \"a\"]] == [1.0, [2, \"a\"]]", true),
+            ("[] == []", true),
+            ("({\"a\": 1} == {\"a\": 1})", true),
+            ("({\"a\": 1, \"b\": [2]} == {\"b\": [2], \"a\": 1})", true),
+            ("({\"a\": 1} == {\"a\": 2})", false),
+            ("({\"a\": 1} == {\"b\": 1})", false),
+            ("({\"a\": 1} == {\"a\": 1, \"b\": 2})", false),
+            ("[1] == {0: 1}", false),
+            ("[1, 2] != [1, 2]", false),
+        ];
+
+        for (input, expected) in tests {
+            test_bool(input, expected);
+        }
+    }
+
+    #[test]
+    fn concatenation_reuses_interned_strings() {
+        let input = "let a = \"ab\"
+        let first = a + \"c\"
+        let last = null
+        let grown = \"\"
+        let i = 0
+        while i < 2000 {
+            last = a + \"c\"
+            grown = grown + \"x\"
+            i = i + 1
+        }
+        let same = first == last";
+
+        let vm = run_globals(input);
+
+        let (Some(Value::String(first)), Some(Value::String(last))) =
+            (vm.globals.get("first"), vm.globals.get("last"))
+        else {
+            panic!("expected strings");
+        };
+        assert!(Rc::ptr_eq(first, last));
+        assert_eq!(vm.globals.get("same"), Some(&Value::Boolean(true)));
+        // Only the live prefixes of grown stay, dead ones are pruned
+        assert!(matches!(vm.globals.get("grown"), Some(Value::String(s)) if s.len() == 2000));
+        assert!(vm.strings.count() < 600, "{}", vm.strings.count());
+
+        // Strings made outside the VM aren't interned but compare by text
+        let vm = run_globals("let s = \"abc\"");
+        assert_eq!(vm.globals.get("s"), Some(&Value::String("abc".into())));
+    }
+
+    #[test]
+    fn globals_are_keyed_by_interned_names() {
+        let vm = run_globals("let name = \"name\"");
+
+        let Some((key, Value::String(value))) = vm.globals.get_key_value("name") else {
+            panic!("expected a string global");
+        };
+        assert!(Rc::ptr_eq(key, value));
+    }
+
+    #[test]
+    fn deeply_nested_equality() {
+        let input = "let a = []
+        let b = []
+        let i = 0
+        while i < 1000 {
+          a = [a]
+          b = [b]
+          i += 1
+        }
+        let equal = a == b";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("equal"), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn for_continue_runs_increment() {
+        let input = "let sum = 0
+        let odd = true
+        for (let i = 0; i < 10; i = i + 1) {
+            let value = i
+            odd = !odd
+            if !odd {
+                continue
+            }
+            sum = sum + value
+        }";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        compiler.compile();
+
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(
+            vm.interpret(),
+            InterpretResult::Ok,
+            "VM should run without errors"
+        );
+
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Integer(25)));
+    }
+
+    #[test]
+    fn continue_outside_loop() {
+        let lexer = Lexer::new("continue");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        assert_eq!(compiler.compile(), InterpretResult::CompileError);
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        for input in ["break", "while true {\n  fn f() {\n    break\n  }\n}"] {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+            assert_eq!(compiler.compile(), InterpretResult::CompileError);
+            assert!(compiler.errors[0]
+                .to_string()
+                .ends_with("Can't use break outside of a loop"));
+        }
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let input = "let i = 0
+        while true {
+            let next = i + 1
+            if next > 5 {
+                break
+            }
+            i = next
+        }
+        let skipped_sum = 0
+        for (let j = 0; j < 10; j = j + 1) {
+            if j == 3 {
+                continue
+            }
+            skipped_sum = skipped_sum + j
+        }
+        let first_big = null
+        for (k, v in [3, 8, 1, 9]) {
+            if v > 5 {
+                first_big = k
+                break
+            }
+        }";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("i"), Some(&Value::Integer(5)));
+        assert_eq!(vm.globals.get("skipped_sum"), Some(&Value::Integer(42)));
+        assert_eq!(vm.globals.get("first_big"), Some(&Value::Integer(1)));
+    }
+
+    #[test]
+    fn index_out_of_bounds_reports_error() {
+        let lexer = Lexer::new("let a = [1, 2]\na[5]");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        compiler.compile();
+
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(vm.runtime_error, Some("index out of bounds: 5".to_string()));
+    }
+
+    #[test]
+    fn array_literals_and_indexing() {
+        let input = "let empty = []
+        let nested = [1, [2, 3], \"four\"]
+        let first = nested[0]
+        let inner = nested[1][1]
+        let last = nested[-1]";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("empty"), Some(&Value::Array(vec![])));
+        assert_eq!(vm.globals.get("first"), Some(&Value::Integer(1)));
+        assert_eq!(vm.globals.get("inner"), Some(&Value::Integer(3)));
+        assert_eq!(vm.globals.get("last"), Some(&Value::String("four".into())));
+    }
+
+    #[test]
+    fn non_integer_index_reports_error() {
+        let lexer = Lexer::new(
+            "let a = [1, 2]
+a[0.5]",
+        );
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(
+            vm.runtime_error,
+            Some("index out of bounds: 0.5".to_string())
+        );
+    }
+
+    #[test]
+    fn compile_error_position() {
+        let input = "let a = 1\n  a + 1 = 2";
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        assert_eq!(compiler.compile(), InterpretResult::CompileError);
+
+        let error = &compiler.errors[0];
+        assert_eq!((error.line, error.column), (2, 9));
+        assert_eq!(
+            error.render(input),
+            "Line 2:9: Invalid assignment target\n  a + 1 = 2\n        ^"
+        );
+    }
+
+    #[test]
+    fn compound_assignment() {
+        let input = "let a = 5; a += 3
+        let doubled = a *= 2
+        let b = 10
+        b -= 4
+        b /= 4
+        let s = \"a\"
+        s += \"b\"
+        fn counter() {
+            let count = 0
+            fn increment() {
+                count += 1
+                return count
+            }
+            increment()
+            count *= 10
+            return increment()
+        }
+        let counted = counter()
+        let values = [1, 2]
+        let i = 1
+        values[0] += 5
+        let set = values[i] *= 10
+        let m = {\"x\": 2}
+        m[\"x\"] *= 3
+        fn local() {
+            let items = [4]
+            items[0] -= 1
+            return items
+        }
+        let items = local()";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(16)));
+        assert_eq!(vm.globals.get("doubled"), Some(&Value::Integer(16)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Number(1.5)));
+        assert_eq!(vm.globals.get("s"), Some(&Value::String("ab".into())));
+        assert_eq!(vm.globals.get("counted"), Some(&Value::Integer(11)));
+        assert_eq!(
+            vm.globals.get("values"),
+            Some(&Value::Array(vec![Value::Integer(6), Value::Integer(20)]))
+        );
+        assert_eq!(vm.globals.get("set"), Some(&Value::Integer(20)));
+        assert_eq!(
+            vm.globals.get("m").map(|m| m.to_string()),
+            Some("{x: 6}".to_string())
+        );
+        assert_eq!(
+            vm.globals.get("items"),
+            Some(&Value::Array(vec![Value::Integer(3)]))
+        );
+    }
+
+    #[test]
+    fn compound_assignment_needs_variable() {
+        let tests = [
+            ("let a = [[1]]\na[0][0] += 1", "Invalid assignment target"),
+            ("let a = 1\n(a) -= 1", "Invalid assignment target"),
+            ("1 *= 2", "Invalid assignment target"),
+            (
+                "let a = [1]\na[len(a) - 1] += 1",
+                "Compound assignment needs a constant or variable index",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+            assert_eq!(compiler.compile(), InterpretResult::CompileError);
+            // The rest of the statement doesn't report more errors
+            assert_eq!(compiler.errors.len(), 1, "{input}");
+            assert!(
+                compiler.errors[0].to_string().contains(expected),
+                "{}: {}",
+                input,
+                compiler.errors[0]
+            );
+        }
+    }
+
+    #[test]
+    fn let_with_annotation() {
+        let lexer = Lexer::new("let x: number = 4\nlet y: string = \"a\"");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        compiler.compile();
+
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(vm.globals.get("x"), Some(&Value::Integer(4)));
+    }
+
+    fn run_globals(input: &str) -> VirtualMachine {
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        compiler.compile();
+
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(
+            vm.interpret(),
+            InterpretResult::Ok,
+            "VM should run without errors"
+        );
+        vm
+    }
+
+    #[test]
+    fn loop_closures_keep_their_values() {
+        let input = "let first = null
+        let second = null
+        let third = null
+        for (let i = 0; i < 3; i = i + 1) {
+            let captured = i * 10
+            fn get() {
+                return captured
+            }
+            if i == 0 {
+                first = get
+            }
+            if i == 1 {
+                second = get
+            }
+            if i == 2 {
+                third = get
+            }
+        }
+        let a = first()
+        let b = second()
+        let c = third()";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Integer(10)));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Integer(20)));
+    }
+
+    #[test]
+    fn closures_share_closed_variable() {
+        let input = "fn make_counter() {
+            let count = 0
+            fn increment() {
+                count = count + 1
+                return count
+            }
+            return increment
+        }
+        let counter = make_counter()
+        let other = make_counter()
+        counter()
+        counter()
+        other()
+        let result = counter() * 10 + other()";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("result"), Some(&Value::Integer(32)));
+    }
+
+    #[test]
+    fn nested_closures_capture_through_functions() {
+        let input = "fn outer() {
+            let x = 1
+            fn middle() {
+                fn inner() {
+                    return x + 1
+                }
+                return inner
+            }
+            x = 5
+            return middle()
+        }
+        let result = outer()()";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("result"), Some(&Value::Integer(6)));
+    }
+
+    // Deterministic xorshift generator, so failing fuzz cases can be replayed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, bound: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+
+        // Mostly small operands with some at the edge of usize
+        fn operand(&mut self) -> usize {
+            if self.below(8) == 0 {
+                usize::MAX - self.below(3) as usize
+            } else {
+                self.below(6) as usize
+            }
+        }
+    }
+
+    fn random_opcode(rng: &mut Rng) -> OpCode {
+        match rng.below(45) {
+            0 => OpCode::Constant(rng.operand()),
+            1 => OpCode::Null,
+            2 => OpCode::True,
+            3 => OpCode::False,
+            4 => OpCode::Not,
+            5 => OpCode::Equal,
+            6 => OpCode::Greater,
+            7 => OpCode::Less,
+            8 => OpCode::LessEqual,
+            9 => OpCode::GreaterEqual,
+            10 => OpCode::NotEqual,
+            11 => OpCode::Negate,
+            12 => OpCode::Add,
+            13 => OpCode::Subtract,
+            14 => OpCode::Multiply,
+            15 => OpCode::Divide,
+            16 => OpCode::Return,
+            17 => OpCode::Pop,
+            18 => OpCode::Call(rng.operand()),
+            19 => OpCode::DefineGlobal(rng.operand()),
+            20 => OpCode::GetGlobal(rng.operand()),
+            21 => OpCode::SetGlobal(rng.operand()),
+            22 => OpCode::GetLocal(rng.operand()),
+            23 => OpCode::SetLocal(rng.operand()),
+            24 => OpCode::JumpIfFalse(rng.operand()),
+            25 => OpCode::Jump(rng.operand()),
+            26 => OpCode::Loop(rng.operand()),
+            27 => OpCode::Closure(rng.operand()),
+            28 => OpCode::GetUpvalue(rng.operand()),
+            29 => OpCode::SetUpvalue(rng.operand()),
+            30 => OpCode::CloseUpvalue,
+            31 => OpCode::BuildArray(rng.operand()),
+            32 => OpCode::Index,
+            33 => OpCode::Unpack(rng.operand()),
+            34 => OpCode::SetIndex,
+            35 => OpCode::JumpIfNull(rng.operand()),
+            36 => OpCode::Yield,
+            37 => OpCode::BuildMap(rng.operand()),
+            38 => OpCode::HasEntry,
+            39 => OpCode::Entry,
+            40 => OpCode::UnpackMap(rng.operand()),
+            41 => OpCode::Modulo,
+            42 => OpCode::Power,
+            43 => OpCode::Invoke(rng.operand(), rng.operand()),
+            _ => OpCode::Constant(rng.below(4) as usize),
+        }
+    }
+
+    fn random_chunk(rng: &mut Rng, nesting: u32) -> Chunk {
+        let mut chunk = Chunk::new();
+
+        for _ in 0..rng.below(5) {
+            let constant = match rng.below(5) {
+                0 => Value::Number(rng.below(5) as f64 - 2.0),
+                1 => Value::String("x".into()),
+                2 => Value::Boolean(rng.below(2) == 0),
+                3 if nesting > 0 => Value::Function(Function {
+                    arity: rng.below(3) as usize,
+                    chunk: random_chunk(rng, nesting - 1),
+                    name: String::new(),
+                    upvalues: (0..rng.below(3))
+                        .map(|_| UpvalueRef {
+                            is_local: rng.below(2) == 0,
+                            index: rng.operand(),
+                        })
+                        .collect(),
+                    is_generator: rng.below(2) == 0,
+                }),
+                _ => Value::Array(vec![Value::Null]),
+            };
+            chunk.add_constant(constant);
+        }
+
+        for _ in 0..rng.below(30) {
+            chunk.write(random_opcode(rng), 1);
+        }
+
+        chunk
+    }
+
+    #[test]
+    fn random_chunks_never_panic() {
+        for seed in 1..=3000 {
+            let mut rng = Rng(seed);
+            let chunk = random_chunk(&mut rng, 2);
+            // Validation may reject the chunk, the VM must stay safe regardless
+            let _ = verify_stack_effect(&chunk);
+
+            let mut vm = VirtualMachine::new(Function {
+                chunk,
+                ..Function::new()
+            });
+            let mut budget = 100;
+            vm.set_watchdog(
+                100,
+                Box::new(move || {
+                    budget -= 1;
+                    budget > 0
+                }),
+            );
+
+            let result = vm.interpret();
+            assert!(
+                matches!(result, InterpretResult::Ok | InterpretResult::RuntimeError),
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn padding_builtins() {
+        let input = "let zeros = pad_left(\"7\", 3, \"0\")
+        let dots = pad_right(\"hi\", 5, \".\")
+        let spaces = pad_left(\"ñu\", 4)
+        let long = pad_right(\"hello\", 2, \"-\")";
+
+        let vm = run_globals(input);
+
+        let string = |value: &str| Some(Value::String(value.into()));
+        assert_eq!(vm.globals.get("zeros").cloned(), string("007"));
+        assert_eq!(vm.globals.get("dots").cloned(), string("hi..."));
+        assert_eq!(vm.globals.get("spaces").cloned(), string("  ñu"));
+        assert_eq!(vm.globals.get("long").cloned(), string("hello"));
+    }
+
+    #[test]
+    fn padding_builtin_errors() {
+        let tests = [
+            (
+                "pad_left(\"7\")",
+                "pad_left expected 2 to 3 arguments but got 1",
+            ),
+            (
+                "pad_left(\"7\", 3, \"ab\")",
+                "pad_left expected a single character fill",
+            ),
+            (
+                "pad_right(7, 3)",
+                "pad_right expected a string but got integer",
+            ),
+            (
+                "pad_left(\"a\", 9223372036854775807)",
+                "pad_left width can't be more than 10000",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn format_builtin() {
+        let tests = [
+            ("format(\"{:.2}\", 3.14159)", "3.14"),
+            ("format(\"{:>4}\", \"x\")", "   x"),
+            ("format(\"{:<3}|\", \"x\")", "x  |"),
+            ("format(\"{:^5}\", \"ab\")", " ab  "),
+            ("format(\"{:08.2}\", -3.14159)", "-0003.14"),
+            ("format(\"{:5}\", 42)", "   42"),
+            ("format(\"{:.1}\", 2)", "2.0"),
+            ("format(\"{} + {} = {}\", 1, 2.5, true)", "1 + 2.5 = true"),
+            ("format(\"{{}}\")", "{}"),
+        ];
+
+        for (input, expected) in tests {
+            test_string(input, expected.to_string());
+        }
+    }
+
+    #[test]
+    fn format_builtin_errors() {
+        let tests = [
+            ("format(\"{:x}\", 1)", "format got an invalid spec {:x}"),
+            ("format(\"{:.}\", 1)", "format got an invalid spec {:.}"),
+            ("format(\"{name}\", 1)", "format got an invalid spec {name}"),
+            (
+                "format(\"{:18446744073709551615}\", 1)",
+                "format got an invalid spec {:18446744073709551615}",
+            ),
+            (
+                "format(\"{:.18446744073709551615}\", 1)",
+                "format got an invalid spec {:.18446744073709551615}",
+            ),
+            (
+                "format(\"{:10001}\", 1)",
+                "format got an invalid spec {:10001}",
+            ),
+            (
+                "format(\"{:.2}\", \"x\")",
+                "format precision expected a number but got string",
+            ),
+            (
+                "format(\"{} {}\", 1)",
+                "format has more placeholders than arguments",
+            ),
+            (
+                "format(\"{}\", 1, 2)",
+                "format has more arguments than placeholders",
+            ),
+            ("format(\"{\", 1)", "format has an unclosed placeholder"),
+            ("format()", "format expected at least 1 arguments but got 0"),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn runtime_error_messages() {
+        let tests = [
+            ("let a = foo + 1", "undefined variable foo"),
+            ("x = 5", "undefined variable x"),
+            ("len = 5", "undefined variable len"),
+            ("fn f() {\n  y += 1\n}\nf()", "undefined variable y"),
+            (
+                "1 < \"a\"",
+                "operands must be two numbers or two strings, got integer and string",
+            ),
+            ("-true", "operand must be a number, got boolean"),
+            ("-\"a\"", "operand must be a number, got string"),
+            (
+                "true + 1",
+                "operands must be two numbers or two strings, got boolean and integer",
+            ),
+            (
+                "\"a\" - 1",
+                "operands must be numbers, got string and integer",
+            ),
+            (
+                "\"a\" + 1",
+                "operands must be two numbers or two strings, got string and integer",
+            ),
+            ("null * 2", "operands must be numbers, got null and integer"),
+            ("1 / 0", "division by zero"),
+            ("1.5 % 0", "modulo by zero"),
+            ("9223372036854775807 + 1", "integer overflow"),
+            (
+                "fn f(x) {\n  return x\n}\nf(1, 2)",
+                "f expected 1 arguments but got 2",
+            ),
+            ("[1][\"a\"]", "array index must be a number, got string"),
+            ("true[0]", "boolean can't be indexed"),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError, "{}", input);
+            assert_eq!(vm.runtime_error, Some(expected.to_string()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn math_builtins() {
+        let input = "let high = clamp(5, 0, 3)
+        let low = clamp(-1, 0, 3)
+        let inside = clamp(2, 0, 3)
+        let negative = sign(-7)
+        let zero = sign(0)
+        let positive = sign(0.5)
+        let float = clamp(5, 0, 2.5)";
+
+        let vm = run_globals(input);
+
+        let integer = |value: i64| Some(Value::Integer(value));
+        assert_eq!(vm.globals.get("high").cloned(), integer(3));
+        assert_eq!(vm.globals.get("low").cloned(), integer(0));
+        assert_eq!(vm.globals.get("inside").cloned(), integer(2));
+        assert_eq!(vm.globals.get("negative").cloned(), integer(-1));
+        assert_eq!(vm.globals.get("zero").cloned(), integer(0));
+        assert_eq!(vm.globals.get("positive").cloned(), integer(1));
+        assert_eq!(vm.globals.get("float").cloned(), Some(Value::Number(2.5)));
+    }
+
+    #[test]
+    fn math_builtin_errors() {
+        let tests = [
+            ("clamp(1, 3, 0)", "clamp expected lo <= hi but got 3 > 0"),
+            (
+                "let n = 10.0 ** 400 - 10.0 ** 400
+                clamp(1, 0, n)",
+                "clamp expected lo <= hi but got 0 > NaN",
+            ),
+            ("sign(\"a\")", "sign expected a number but got string"),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn seeded_random_is_reproducible() {
+        let input = "seed(42)
+        let a = random()
+        let b = random()
+        let c = random_int(1, 6)";
+
+        let first = run_globals(input);
+        let second = run_globals(input);
+
+        for name in ["a", "b", "c"] {
+            assert_eq!(first.globals.get(name), second.globals.get(name));
+        }
+        assert_ne!(first.globals.get("a"), first.globals.get("b"));
+        let Some(Value::Number(a)) = first.globals.get("a") else {
+            panic!("random should return a number");
+        };
+        assert!((0.0..1.0).contains(a));
+    }
+
+    #[test]
+    fn random_int_stays_in_range() {
+        let input = "seed(7)
+        let low = 6
+        let high = 1
+        let i = 0
+        while i < 1000 {
+            let roll = random_int(1, 6)
+            if roll < low {
+                low = roll
+            }
+            if roll > high {
+                high = roll
+            }
+            i = i + 1
+        }";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("low"), Some(&Value::Integer(1)));
+        assert_eq!(vm.globals.get("high"), Some(&Value::Integer(6)));
+    }
+
+    #[test]
+    fn string_builtins() {
+        let input = "let start = trim_start(\"  x \")
+        let end = trim_end(\" x  \")
+        let all = replace(\"aaa\", \"a\", \"b\")
+        let overlapping = replace(\"aaaa\", \"aa\", \"b\")
+        let loud = upper(\"Hola\")
+        let quiet = lower(\"Hola\")";
+
+        let vm = run_globals(input);
+
+        let string = |value: &str| Some(Value::String(value.into()));
+        assert_eq!(vm.globals.get("start").cloned(), string("x "));
+        assert_eq!(vm.globals.get("end").cloned(), string(" x"));
+        assert_eq!(vm.globals.get("all").cloned(), string("bbb"));
+        assert_eq!(vm.globals.get("overlapping").cloned(), string("bb"));
+        assert_eq!(vm.globals.get("loud").cloned(), string("HOLA"));
+        assert_eq!(vm.globals.get("quiet").cloned(), string("hola"));
+    }
+
+    #[test]
+    fn method_calls() {
+        let input = "let loud = \"hi\".upper()
+        let length = [1, 2, 3].len()
+        let padded = \"7\".pad_left(3, \"0\").upper()
+        fn add(a, b) {
+            return a + b
+        }
+        let sum = (1 + 2).add(4)
+        let chained = [\"a\"][0].upper().len()";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("loud"), Some(&Value::String("HI".into())));
+        assert_eq!(vm.globals.get("length"), Some(&Value::Integer(3)));
+        assert_eq!(vm.globals.get("padded"), Some(&Value::String("007".into())));
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Integer(7)));
+        assert_eq!(vm.globals.get("chained"), Some(&Value::Integer(1)));
+    }
+
+    #[test]
+    fn method_call_errors() {
+        let tests = [
+            ("1.5.upper()", "upper expected a string but got number"),
+            ("let a = 1\na.missing()", "undefined variable missing"),
+            ("\"a\".len(2)", "len expected 1 arguments but got 2"),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError, "{input}");
+            assert_eq!(vm.runtime_error, Some(expected.to_string()), "{input}");
+        }
+    }
+
+    #[test]
+    fn string_builtin_errors() {
+        let tests = [
+            (
+                "replace(\"abc\", \"\", \"x\")",
+                "replace expected a non-empty pattern",
+            ),
+            ("trim_end(1)", "trim_end expected a string but got integer"),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn prefix_and_suffix_builtins() {
+        let input = "let prefix = starts_with(\"hola\", \"ho\")
+        let suffix = ends_with(\"hola\", \"la\")
+        let missing = starts_with(\"hola\", \"la\")";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("prefix"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.globals.get("suffix"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.globals.get("missing"), Some(&Value::Boolean(false)));
+
+        let lexer = Lexer::new("ends_with(\"hola\", 1)");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(
+            vm.runtime_error,
+            Some("ends_with expected a string but got integer".to_string())
+        );
+    }
+
+    #[test]
+    fn globals_shadow_builtins() {
+        let vm =
+            run_globals("let before = len(\"abc\") + len([1, 2])\nlet len = 5\nlet after = len");
+
+        assert_eq!(vm.globals.get("before"), Some(&Value::Integer(5)));
+        assert_eq!(vm.globals.get("after"), Some(&Value::Integer(5)));
+
+        let lexer = Lexer::new("let len = 5\nlen(\"x\")");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(
+            vm.runtime_error,
+            Some("integer is not callable".to_string())
+        );
+    }
+
+    #[test]
+    fn globals_are_listed_by_name() {
+        let vm = run_globals("let c = 3\nlet a = 1\nlet b = 2");
+
+        let names: Vec<&str> = vm
+            .sorted_globals()
+            .into_iter()
+            .map(|(name, _)| name.as_ref())
+            .collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn parse_int_builtin() {
+        let input = "let hex = parse_int(\"ff\", 16)
+        let binary = parse_int(\"101\", 2)
+        let decimal = parse_int(\"-42\")";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("hex"), Some(&Value::Integer(255)));
+        assert_eq!(vm.globals.get("binary"), Some(&Value::Integer(5)));
+        assert_eq!(vm.globals.get("decimal"), Some(&Value::Integer(-42)));
+
+        let tests = [
+            ("parse_int(\"12\", 2)", "parse_int can't parse 12 in base 2"),
+            (
+                "parse_int(\"1\", 37)",
+                "parse_int expected a base between 2 and 36 but got 37",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn character_builtins() {
+        let input = "let upper = ord(\"A\")
+        let lower = chr(97)
+        let round_trip = chr(ord(\"ñ\"))";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("upper"), Some(&Value::Integer(65)));
+        assert_eq!(vm.globals.get("lower"), Some(&Value::String("a".into())));
+        assert_eq!(
+            vm.globals.get("round_trip"),
+            Some(&Value::String("ñ".into()))
+        );
+
+        let tests = [
+            ("ord(\"ab\")", "ord expected a single character but got ab"),
+            ("ord(\"\")", "ord expected a single character but got "),
+            (
+                "chr(55296)",
+                "chr expected a valid code point but got 55296",
+            ),
+            ("chr(-1)", "chr expected a valid code point but got -1"),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn unused_local_warning() {
+        let input =
+            "fn f(unused_parameter) {\n  let a = 1\n  let b = 2\n  let _c = 3\n  return b\n}";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        assert_eq!(compiler.compile(), InterpretResult::Ok);
+        let warnings: Vec<String> = compiler.warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec!["Line 2:7: unused variable a"]);
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.warnings_as_errors = true;
+
+        assert_eq!(compiler.compile(), InterpretResult::CompileError);
+        assert_eq!(
+            compiler.errors[0].to_string(),
+            "Line 2:7: unused variable a"
+        );
+    }
+
+    #[test]
+    fn constant_arithmetic_is_folded() {
+        let tests = [
+            ("-5", Value::Integer(-5)),
+            ("-(2 + 3)", Value::Integer(-5)),
+            ("--5", Value::Integer(5)),
+            ("- -5", Value::Integer(5)),
+            ("1 + 2 * 3", Value::Integer(7)),
+            ("(1 - 4) / 2", Value::Number(-1.5)),
+            ("-2.5 * 2", Value::Number(-5.0)),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let chunk = compiler.current_chunk();
+            assert_eq!(
+                chunk.code,
+                vec![OpCode::Constant(0), OpCode::Return],
+                "{}",
+                input
+            );
+            assert_eq!(chunk.constants, vec![expected], "{}", input);
+        }
+    }
+
+    #[test]
+    fn non_constant_arithmetic_is_not_folded() {
+        let tests = [
+            ("let x = 2\n--x", vec![OpCode::Negate, OpCode::Negate]),
+            ("let x = 2\n-x + 1", vec![OpCode::Negate, OpCode::Add]),
+            ("1 / 0", vec![OpCode::Divide]),
+            ("\"a\" + 1", vec![OpCode::Add]),
+        ];
+
+        for (input, operators) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let code = &compiler.current_chunk().code;
+            let emitted: Vec<&OpCode> = code
+                .iter()
+                .filter(|instruction| operators.contains(instruction))
+                .collect();
+            assert_eq!(emitted, operators.iter().collect::<Vec<_>>(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn constant_conditions_drop_dead_branches() {
+        let lexer = Lexer::new("if (false) {\n  return 1\n} else {\n  return 2\n}");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let warnings: Vec<String> = compiler.warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec!["Line 1:12: unreachable code"]);
+        assert_eq!(
+            compiler.current_chunk().disassemble("script"),
+            "== script ==\n\
+             0000    4 Constant(0) '2'\n\
+             0001    | Return\n\
+             0002    5 Null\n\
+             0003    | Return\n"
+        );
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(vm.stack.last(), Some(&Value::Integer(2)));
+
+        let tests = [
+            ("let x = 0\nif true {\n  x = 1\n} else {\n  x = 2\n}", 1),
+            (
+                "let x = 0\nif false {\n  x = 1\n} elif true {\n  x = 2\n}",
+                2,
+            ),
+            (
+                "let x = 0\nwhile x < 5 {\n  if false {\n    break\n  }\n  x += 1\n}",
+                5,
+            ),
+        ];
+        for (input, expected) in tests {
+            let vm = run_globals(input);
+            assert_eq!(
+                vm.globals.get("x"),
+                Some(&Value::Integer(expected)),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn disassembly_resolves_jumps() {
+        let mut chunk = Chunk::new();
+        let index = chunk.add_constant(Value::String("x".into()));
+        chunk.write(OpCode::True, 1);
+        chunk.write(OpCode::JumpIfFalse(3), 1);
+        chunk.write(OpCode::Pop, 2);
+        chunk.write(OpCode::Constant(index), 2);
+        chunk.write(OpCode::Loop(4), 2);
+        chunk.write(OpCode::Jump(0), 3);
+
+        assert_eq!(
+            chunk.disassemble("jumps"),
+            "== jumps ==\n\
+             0000    1 True\n\
+             0001    | JumpIfFalse(3) -> 0005\n\
+             0002    2 Pop\n\
+             0003    | Constant(0) 'x'\n\
+             0004    | Loop(4) -> 0001\n\
+             0005    3 Jump(0) -> 0006\n"
+        );
+    }
+
+    #[test]
+    fn chunk_summary() {
+        let lexer = Lexer::new("let a = 1\na + 2");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let chunk = compiler.current_chunk();
+        let summary = chunk.summary();
+        // Constant, DefineGlobal, GetGlobal, Constant, Add and Return
+        assert_eq!(summary.instructions, 6);
+        // a twice, 1 and 2
+        assert_eq!(summary.constants, 4);
+        assert_eq!(summary.bytes, chunk.byte_size());
+        assert!(summary.bytes >= 6 * size_of::<OpCode>() + 4 * size_of::<Value>() + 2);
+        assert_eq!(
+            summary.to_string(),
+            format!("6 instructions, 4 constants, {} bytes", summary.bytes)
+        );
+
+        // A longer string constant only adds its text
+        let mut longer = chunk.clone();
+        longer.constants[0] = Value::String("abc".into());
+        assert_eq!(longer.byte_size(), summary.bytes + 2);
+    }
+
+    #[test]
+    fn literals_use_dedicated_opcodes() {
+        let lexer = Lexer::new("let t = true\nlet f = false\nlet n = null");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        assert_eq!(
+            compiler.current_chunk().disassemble("script"),
+            "== script ==\n\
+             0000    1 True\n\
+             0001    | DefineGlobal(0) 't'\n\
+             0002    2 False\n\
+             0003    | DefineGlobal(1) 'f'\n\
+             0004    3 Null\n\
+             0005    | DefineGlobal(2) 'n'\n\
+             0006    | Null\n\
+             0007    | Return\n"
+        );
+        // Only the global names go to the constant pool
+        assert!(compiler
+            .current_chunk()
+            .constants
+            .iter()
+            .all(|constant| matches!(constant, Value::String(_))));
+    }
+
+    #[test]
+    fn time_ns_reads_monotonic_clock() {
+        let lexer = Lexer::new("let start = time_ns()\nlet end = time_ns()\nlet now = clock()");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        let mut now = 1000;
+        vm.set_monotonic_clock(Box::new(move || {
+            now += 250;
+            now
+        }));
+
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(vm.globals.get("start"), Some(&Value::Integer(1250)));
+        assert_eq!(vm.globals.get("end"), Some(&Value::Integer(1500)));
+        assert!(matches!(vm.globals.get("now"), Some(Value::Number(seconds)) if *seconds > 0.0));
+    }
+
+    #[test]
+    fn elif_and_else_if_branches() {
+        let input = "fn grade(n) {
+            if n > 8 {
+                return \"a\"
+            } elif n > 5 {
+                return \"b\"
+            } else if n > 3 {
+                return \"c\"
+            } else {
+                return \"d\"
+            }
+        }
+        let a = grade(9)
+        let b = grade(6)
+        let c = grade(4)
+        let d = grade(1)";
+
+        let vm = run_globals(input);
+
+        for name in ["a", "b", "c", "d"] {
+            assert_eq!(vm.globals.get(name), Some(&Value::String(name.into())));
+        }
+    }
+
+    #[test]
+    fn optional_index() {
+        let input = r#"let missing = null
+        let a = missing?[0]
+        let b = missing?.[0]
+        let values = [1, 2]
+        let c = values?.[1]
+        let d = values?[0] + 1
+        let e = null?["x"][0]
+        let f = missing?.x.y
+        let m = {"x": [4, 5]}
+        let g = m?.["x"][1]
+        let h = m?.x
+        let i = m.x[0]
+        let j = ({"x": 1})?.["x"]"#;
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("a"), Some(&Value::Null));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Null));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Integer(2)));
+        assert_eq!(vm.globals.get("d"), Some(&Value::Integer(2)));
+        assert_eq!(vm.globals.get("e"), Some(&Value::Null));
+        assert_eq!(vm.globals.get("f"), Some(&Value::Null));
+        assert_eq!(vm.globals.get("g"), Some(&Value::Integer(5)));
+        assert!(matches!(vm.globals.get("h"), Some(Value::Array(_))));
+        assert_eq!(vm.globals.get("i"), Some(&Value::Integer(4)));
+        assert_eq!(vm.globals.get("j"), Some(&Value::Integer(1)));
+    }
+
+    #[test]
+    fn null_coalescing() {
+        let input = "let called = false
+        fn fallback() {
+            called = true
+            return 5
+        }
+        let a = null ?? 5
+        let b = 3 ?? fallback()
+        let c = null ?? null ?? 7
+        let d = [null]?[0] ?? 1 + 1";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(5)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Integer(3)));
+        assert_eq!(vm.globals.get("called"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Integer(7)));
+        assert_eq!(vm.globals.get("d"), Some(&Value::Integer(2)));
+    }
+
+    #[test]
+    fn global_redefinition() {
+        let input = "let a = 1\nlet a = 2";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        assert_eq!(compiler.compile(), InterpretResult::CompileError);
+        assert_eq!(
+            compiler.errors[0].to_string(),
+            "Line 2:5: Global a is already defined"
+        );
+
+        // The REPL allows it and sees the new value
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.allow_global_redefinition = true;
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(2)));
+    }
+
+    // Calls its first argument with the second one, through the VM
+    fn apply(vm: &mut VirtualMachine, arguments: &[Value]) -> Result<Value, String> {
+        vm.push(Value::Null);
+        let result = vm.call_value(arguments[0].clone(), &arguments[1..]);
+        assert_eq!(vm.pop(), Some(Value::Null));
+        result
+    }
+
+    fn run_with_apply(input: &str) -> VirtualMachine {
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let native = NativeFunction {
+            name: "apply",
+            min_arity: 2,
+            max_arity: 2,
+            function: apply,
+        };
+        let globals = [("apply".into(), Value::NativeFunction(native))].into();
+
+        let mut vm = VirtualMachine::new_with_globals(take(&mut compiler.function), globals);
+        vm.interpret();
+        vm
+    }
+
+    #[test]
+    fn natives_call_values() {
+        let input = "let offset = 10
+        fn add_offset(x) {
+            return x + offset
+        }
+        let a = apply(add_offset, 5) * 2
+        fn twice(s) {
+            return s + s
+        }
+        let b = apply(twice, \"abc\")
+        let c = apply(len, [1, 2])";
+
+        let vm = run_with_apply(input);
+
+        assert_eq!(vm.runtime_error, None);
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(30)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::String("abcabc".into())));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Integer(2)));
+    }
+
+    #[test]
+    fn natives_call_value_errors() {
+        let tests = [
+            (
+                "fn pair(a, b) {\n  return a\n}\napply(pair, 1)",
+                "pair expected 2 arguments but got 1",
+            ),
+            (
+                "fn sixth(x) {\n  return x[5]\n}\napply(sixth, [1])",
+                "index out of bounds: 5",
+            ),
+            ("apply(3, 1)", "integer is not callable"),
+        ];
+
+        for (input, expected) in tests {
+            let vm = run_with_apply(input);
+
+            assert_eq!(vm.runtime_error, Some(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn destructure_multiple_return_values() {
+        let input = "fn minmax(a, b) {
+            if a < b {
+                return [a, b]
+            }
+            return [b, a]
+        }
+        let [low, high] = minmax(7, 3)
+        fn spread() {
+            let [first, second] = minmax(1, 2)
+            return second * 10 + first
+        }
+        let local = spread()";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("low"), Some(&Value::Integer(3)));
+        assert_eq!(vm.globals.get("high"), Some(&Value::Integer(7)));
+        assert_eq!(vm.globals.get("local"), Some(&Value::Integer(21)));
+    }
+
+    #[test]
+    fn destructure_length_mismatch() {
+        let lexer = Lexer::new("let [a, b] = [1, 2, 3]");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(
+            vm.runtime_error,
+            Some("expected 2 values to unpack but got 3".to_string())
+        );
+    }
+
+    #[test]
+    fn nested_function_keeps_outer_scope() {
+        let input = "fn outer() {
+            let x = 1
+            fn inner() {
+                let x = 10
+                let y = 20
+                return x + y
+            }
+            let result = inner()
+            return x + result
+        }
+        let value = outer()";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        // The inner function is a constant of the outer one
+        let outer = compiler
+            .function
+            .chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Value::Function(function) if function.name == "outer" => Some(function),
+                _ => None,
+            })
+            .expect("outer function is not a constant");
+        assert!(outer
+            .chunk
+            .constants
+            .iter()
+            .any(|constant| matches!(constant, Value::Function(inner) if inner.name == "inner")));
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(vm.globals.get("value"), Some(&Value::Integer(31)));
+        assert_eq!(vm.globals.get("y"), None);
+        assert_eq!(vm.globals.get("inner"), None);
+    }
+
+    #[test]
+    fn integer_literal_out_of_range() {
+        let lexer = Lexer::new("let a = 1 + 9223372036854775808");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        assert_eq!(compiler.compile(), InterpretResult::CompileError);
+        assert_eq!(
+            compiler.errors[0].to_string(),
+            "Line 1:13: integer literal out of range"
+        );
+    }
+
+    #[test]
+    fn string_escapes() {
+        let vm = run_globals("let s = \"a\\nb\"\nlet size = len(s)\nlet quote = \"\\\"\"");
+
+        assert_eq!(vm.globals.get("s"), Some(&Value::String("a\nb".into())));
+        assert_eq!(vm.globals.get("size"), Some(&Value::Integer(3)));
+        assert_eq!(vm.globals.get("quote"), Some(&Value::String("\"".into())));
+
+        let tests = [
+            ("let s = \"a\\qb\"", "Line 1:9: unknown escape \\q"),
+            ("let s = \"open", "Line 1:9: unterminated string"),
+        ];
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+            assert_eq!(compiler.compile(), InterpretResult::CompileError);
+            assert_eq!(compiler.errors[0].to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn pure_expression_statements_are_elided() {
+        let lexer = Lexer::new("fn f() {\n}\n1;\nf();\ntrue;\nlet a = 2\n");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        assert_eq!(
+            compiler.current_chunk().code,
+            vec![
+                OpCode::Closure(1),
+                OpCode::DefineGlobal(0),
+                // Constant 2 belongs to the elided 1
+                OpCode::GetGlobal(3),
+                OpCode::Call(0),
+                OpCode::Pop,
+                OpCode::Constant(5),
+                OpCode::DefineGlobal(4),
+                OpCode::Null,
+                OpCode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn elided_final_expression_is_the_result() {
+        for (input, expected) in [("5", 5), ("1;\n7", 7), ("let a = 1\n3;\n", 3)] {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::Ok);
+            assert_eq!(vm.stack.last(), Some(&Value::Integer(expected)));
+        }
+    }
+
+    #[test]
+    fn local_used_before_declaration() {
+        for (input, expected) in [
+            (
+                "let x = 1\nif true {\n  let y = x\n  let x = 2\n}",
+                "Line 3:11: Can't use x before its declaration",
+            ),
+            (
+                "if true {\n  if true {\n    x\n  }\n  let x = 2\n}",
+                "Line 3:5: Can't use x before its declaration",
+            ),
+            (
+                "let a = 1\nif true {\n  let a = a\n}",
+                "Line 3:11: Can't read local variable in its own initializer",
+            ),
+        ] {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+            assert_eq!(compiler.compile(), InterpretResult::CompileError);
+            assert_eq!(compiler.errors[0].to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn forward_references_across_functions() {
+        let vm = run_globals(
+            "fn is_even(n) {\n  if n == 0 {\n    return true\n  }\n  return is_odd(n - 1)\n}\n\
+             fn is_odd(n) {\n  if n == 0 {\n    return false\n  }\n  return is_even(n - 1)\n}\n\
+             let result = is_even(10)\n\
+             if true {\n  let x = 1\n  let y = x\n}",
+        );
+
+        assert_eq!(vm.globals.get("result"), Some(&Value::Boolean(true)));
+    }
+
+    // Coarse guard against pathological slowdowns in the dispatch loop, not a
+    // benchmark: the limit is far above the expected time even in debug builds
+    #[test]
+    fn dispatch_loop_stays_fast() {
+        let start = Instant::now();
+        let vm = run_globals(
+            "let sum = 0\nlet i = 0\nwhile i < 1000000 {\n  sum = sum + i\n  i = i + 1\n}",
+        );
+
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Integer(499999500000)));
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "a million iterations took {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn index_assignment_yields_the_value() {
+        let vm = run_globals(
+            "let arr = [1, 2, 3]\nlet y = arr[0] = 9\narr[-1] = arr[1] + 1\n\
+             let b = 0\nlet c = b = 5\n\
+             fn f() {\n  let local = [0]\n  local[0] = 4\n  return local\n}\nlet d = f()",
+        );
+
+        assert_eq!(vm.globals.get("y"), Some(&Value::Integer(9)));
+        assert_eq!(
+            vm.globals.get("arr"),
+            Some(&Value::Array(vec![
+                Value::Integer(9),
+                Value::Integer(2),
+                Value::Integer(3)
+            ]))
+        );
+        assert_eq!(vm.globals.get("c"), Some(&Value::Integer(5)));
+        assert_eq!(
+            vm.globals.get("d"),
+            Some(&Value::Array(vec![Value::Integer(4)]))
+        );
+    }
+
+    #[test]
+    fn generators_share_captured_locals() {
+        let input = "fn* reader() {
+            let n = 0
+            let get = fn() {
+                return n
+            }
+            yield 1
+            n = 5
+            yield get()
+        }
+        let r = reader()
+        let first = next(r)
+        let second = next(r)
+        fn* counter() {
+            let n = 1
+            yield fn() {
+                n = n + 10
+            }
+            yield n
+        }
+        let c = counter()
+        let bump = next(c)
+        bump()
+        let bumped = next(c)";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("first"), Some(&Value::Integer(1)));
+        assert_eq!(vm.globals.get("second"), Some(&Value::Integer(5)));
+        // Changed by the closure while the generator was suspended
+        assert_eq!(vm.globals.get("bumped"), Some(&Value::Integer(11)));
+    }
+
+    #[test]
+    fn generators_yield_values() {
+        let input = "fn* gen() {
+            yield 1
+            yield 2
+        }
+        let g = gen()
+        let a = next(g)
+        let b = next(g)
+        let c = next(g)
+        let d = next(g)
+        fn* count(from, to) {
+            let i = from
+            while i < to {
+                yield i
+                i = i + 1
+            }
+        }
+        let numbers = count(3, 6)
+        let total = 0
+        let n = next(numbers)
+        while n != null {
+            total = total + n
+            n = next(numbers)
+        }
+        let first = next(gen())";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(1)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Integer(2)));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Null));
+        assert_eq!(vm.globals.get("d"), Some(&Value::Null));
+        assert_eq!(vm.globals.get("total"), Some(&Value::Integer(12)));
+        assert_eq!(vm.globals.get("first"), Some(&Value::Integer(1)));
+    }
+
+    #[test]
+    fn while_let_loops() {
+        let input = "fn* lines() {
+            yield \"first\"
+            yield \"\"
+            yield \"third\"
+        }
+        let read = \"\"
+        let count = 0
+        let g = lines()
+        while let line = next(g) {
+            count = count + 1
+            if line == \"\" {
+                continue
+            }
+            read = read + line + \";\"
+        }
+        let closures = [null, null, null]
+        let values = [1, 2, 3, null, 4]
+        let i = 0
+        while let value = values[i] {
+            i = i + 1
+            if value == 3 {
+                break
+            }
+            closures[i - 1] = fn() {
+                return value
+            }
+        }
+        let first = closures[0]()
+        let second = closures[1]()
+        let never = 0
+        while let nothing = null {
+            never = never + 1
+        }";
+
+        let vm = run_globals(input);
+
+        assert_eq!(
+            vm.globals.get("read"),
+            Some(&Value::String("first;third;".into()))
+        );
+        assert_eq!(vm.globals.get("count"), Some(&Value::Integer(3)));
+        assert_eq!(vm.globals.get("i"), Some(&Value::Integer(3)));
+        assert_eq!(vm.globals.get("first"), Some(&Value::Integer(1)));
+        assert_eq!(vm.globals.get("second"), Some(&Value::Integer(2)));
+        assert_eq!(vm.globals.get("never"), Some(&Value::Integer(0)));
+    }
+
+    #[test]
+    fn generator_errors() {
+        let lexer = Lexer::new("fn f() {\n  yield 1\n}");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+        assert_eq!(compiler.compile(), InterpretResult::CompileError);
+        assert_eq!(
+            compiler.errors[0].to_string(),
+            "Line 2:3: Can't yield outside a generator, found yield with lexeme yield"
+        );
+
+        for (input, expected) in [
+            (
+                "next(1)",
+                "next expected a generator or an iterator but got integer",
+            ),
+            (
+                "fn* fail() {\n  yield 1\n  yield [][0]\n}\nlet g = fail()\nnext(g)\nnext(g)",
+                "index out of bounds: 0",
+            ),
+        ] {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn iterator_protocol() {
+        let input = "let it = iter([10, 20, 30])
+        let seen = [has_next(it), next(it), has_next(it), has_next(it), next(it), next(it)]
+        let done = has_next(it)
+        let after = next(it)";
+
+        let vm = run_globals(input);
+
+        assert_eq!(
+            vm.globals.get("seen"),
+            Some(&Value::Array(vec![
+                Value::Boolean(true),
+                Value::Integer(10),
+                Value::Boolean(true),
+                Value::Boolean(true),
+                Value::Integer(20),
+                Value::Integer(30),
+            ]))
+        );
+        assert_eq!(vm.globals.get("done"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.globals.get("after"), Some(&Value::Null));
+    }
+
+    #[test]
+    fn all_and_any() {
+        let input = "let mixed = [1, 0, \"\", null, true]
+        let tests = [all(mixed), any(mixed), all([1, 0, \"\"]), any([false, null]), all([]), any([])]
+        let it = iter([1, false, 2, true])
+        let stopped_all = all(it)
+        let after_all = next(it)
+        let stopped_any = any(it)
+        let after_any = next(it)
+        let resumed = 0
+        fn* flags() {
+          resumed += 1
+          yield true
+          resumed += 1
+          yield true
+        }
+        let found = any(iter(flags()))";
+
+        let vm = run_globals(input);
+
+        assert_eq!(
+            vm.globals.get("tests"),
+            Some(&Value::Array(vec![
+                Value::Boolean(false),
+                Value::Boolean(true),
+                Value::Boolean(true),
+                Value::Boolean(false),
+                Value::Boolean(true),
+                Value::Boolean(false),
+            ]))
+        );
+        // Each call stops right after the element deciding it
+        assert_eq!(vm.globals.get("stopped_all"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.globals.get("after_all"), Some(&Value::Integer(2)));
+        assert_eq!(vm.globals.get("stopped_any"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.globals.get("after_any"), Some(&Value::Null));
+        assert_eq!(vm.globals.get("found"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.globals.get("resumed"), Some(&Value::Integer(1)));
+    }
+
+    #[test]
+    fn all_and_any_errors() {
+        let tests = [
+            (
+                "all(1)",
+                "all expected an array or an iterator but got integer",
+            ),
+            (
+                "any({\"a\": 1})",
+                "any expected an array or an iterator but got map",
+            ),
+            (
+                "map(1, fn(x) {\n return x\n})",
+                "map expected an array or an iterator but got integer",
+            ),
+            ("map([1], 2)", "integer is not callable"),
+            (
+                "fn pair(a, b) {\n return a\n}\nmap([1], pair)",
+                "pair expected 2 arguments but got 1",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError, "{input}");
+            assert_eq!(vm.runtime_error, Some(expected.to_string()), "{input}");
+        }
+    }
+
+    #[test]
+    fn iterators_over_every_iterable() {
+        let input = "fn* count() {
+          yield 1
+          yield 2
+        }
+        let chars = \"\"
+        for (_, c in iter(\"ñu\")) {
+          chars = chars + c + \".\"
+        }
+        let entries = [null, null]
+        let entry_count = 0
+        for (i, entry in iter({\"a\": 1, \"b\": 2})) {
+          entries[i] = entry
+          entry_count += 1
+        }
+        let total = 0
+        let it = iter(count())
+        while has_next(it) {
+          total += next(iter(it))
+        }";
+
+        let vm = run_globals(input);
+
+        let string = |value: &str| Value::String(value.into());
+        assert_eq!(vm.globals.get("chars"), Some(&string("ñ.u.")));
+        assert_eq!(
+            vm.globals.get("entries"),
+            Some(&Value::Array(vec![
+                Value::Array(vec![string("a"), Value::Integer(1)]),
+                Value::Array(vec![string("b"), Value::Integer(2)]),
+            ]))
+        );
+        assert_eq!(vm.globals.get("entry_count"), Some(&Value::Integer(2)));
+        assert_eq!(vm.globals.get("total"), Some(&Value::Integer(3)));
+    }
+
+    #[test]
+    fn map_literals_and_indexing() {
+        let input = "let m = {\"a\": 1, \"b\": 2, \"a\": 3}
+        m[\"c\"] = m[\"b\"] * 10
+        let a = m[\"a\"]
+        let keys = {1: \"one\", true: \"yes\", null: \"none\", 0.0: \"zero\", -0.0: \"minus zero\"}
+        let one = keys[2 - 1]
+        let float_one = keys[1.0]
+        let yes = keys[1 < 2]
+        let none = keys[null]
+        let zero = keys[0]
+        let minus_zero = keys[-0.0]
+        let big = {9007199254740992: \"a\", 9007199254740993: \"b\", 0.5: \"half\"}
+        let big_a = big[9007199254740992]
+        let big_b = big[9007199254740993]
+        let big_float = big[9007199254740992.0]
+        let half = big[0.5]
+        let empty = {}";
+
+        let vm = run_globals(input);
+
+        let string = |value: &str| Value::String(value.into());
+        let mut expected = Map::new();
+        for (key, value) in [("a", 3), ("b", 2), ("c", 20)] {
+            expected.insert(string(key), Value::Integer(value)).unwrap();
+        }
+        assert_eq!(vm.globals.get("m"), Some(&Value::Map(expected)));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(3)));
+        assert_eq!(vm.globals.get("one"), Some(&string("one")));
+        assert_eq!(vm.globals.get("float_one"), Some(&string("one")));
+        assert_eq!(vm.globals.get("yes"), Some(&string("yes")));
+        assert_eq!(vm.globals.get("none"), Some(&string("none")));
+        assert_eq!(vm.globals.get("zero"), Some(&string("zero")));
+        assert_eq!(vm.globals.get("minus_zero"), Some(&string("minus zero")));
+        // Integers past 2^53 keep distinct keys
+        assert!(matches!(vm.globals.get("big"), Some(Value::Map(big)) if big.entries().len() == 3));
+        assert_eq!(vm.globals.get("big_a"), Some(&string("a")));
+        assert_eq!(vm.globals.get("big_b"), Some(&string("b")));
+        assert_eq!(vm.globals.get("big_float"), Some(&string("a")));
+        assert_eq!(vm.globals.get("half"), Some(&string("half")));
+        assert_eq!(vm.globals.get("empty"), Some(&Value::Map(Map::new())));
+    }
+
+    #[test]
+    fn signed_zero_functions_stay_distinct() {
+        let input = "let zero = fn() {
+            return 0.0
+        }
+        let minus_zero = fn() {
+            return -0.0
+        }
+        let m = {-0.0: \"neg\", 0: \"pos\"}
+        let neg = m[minus_zero()]
+        let pos = m[zero()]";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("neg"), Some(&Value::String("neg".into())));
+        assert_eq!(vm.globals.get("pos"), Some(&Value::String("pos".into())));
+    }
+
+    #[test]
+    fn map_lookup_errors() {
+        let tests = [
+            ("let m = {\"a\": 1}\nm[\"z\"]", "missing key z"),
+            ("let m = {[1]: 1}", "array can't be a map key"),
+            ("let m = {}\nm[[1]]", "array can't be a map key"),
+            ("let m = {}\nm[{}] = 1", "map can't be a map key"),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn for_in_loops() {
+        let input = "let keys = \"\"
+        let total = 0
+        for (k, v in {\"a\": 1, \"b\": 2}) {
+            keys = keys + k
+            total = total + v
+        }
+        fn sum_after_first(values) {
+            let sum = 0
+            for (i, value in values) {
+                if i == 0 {
+                    continue
+                }
+                sum = sum + value
+            }
+            return sum
+        }
+        let rest = sum_after_first([5, 6, 7])";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("keys"), Some(&Value::String("ab".into())));
+        assert_eq!(vm.globals.get("total"), Some(&Value::Integer(3)));
+        assert_eq!(vm.globals.get("rest"), Some(&Value::Integer(13)));
+    }
+
+    #[test]
+    fn globals_persist_between_runs() {
+        let mut globals = HashMap::new();
+
+        for input in ["let x = 5", "let y = x * 2"] {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new_with_globals(take(&mut compiler.function), globals);
+            assert_eq!(vm.interpret(), InterpretResult::Ok);
+            globals = vm.globals;
+        }
+
+        assert_eq!(globals.get("x"), Some(&Value::Integer(5)));
+        assert_eq!(globals.get("y"), Some(&Value::Integer(10)));
+    }
+
+    #[test]
+    fn compile_cache_reuses_chunks() {
+        let mut cache = CompileCache::new();
+        let source = "fn square(x) {\n  return x * x\n}\nsquare(len([1, 2, 3]))";
+
+        let mut results = vec![];
+        for _ in 0..2 {
+            let mut vm = VirtualMachine::new(Function::new());
+            assert_eq!(cache.run(source, &mut vm), InterpretResult::Ok);
+            results.push(vm.stack.last().cloned());
+        }
+        assert_eq!(cache.compiles(), 1);
+        assert_eq!(results, vec![Some(Value::Integer(9)); 2]);
+
+        // Cached scripts run again in the same VM keep updating its globals
+        let mut vm = VirtualMachine::new(Function::new());
+        vm.globals.insert("count".into(), Value::Integer(0));
+        for _ in 0..3 {
+            assert_eq!(cache.run("count = count + 1", &mut vm), InterpretResult::Ok);
+        }
+        assert_eq!(cache.compiles(), 2);
+        assert_eq!(vm.globals.get("count"), Some(&Value::Integer(3)));
+
+        // Scripts that don't compile are compiled again on every run
+        for _ in 0..2 {
+            assert_eq!(cache.run("let = 1", &mut vm), InterpretResult::CompileError);
+        }
+        assert_eq!(cache.compiles(), 4);
+    }
+
+    #[test]
+    fn every_opcode_runs() {
+        let mut chunk = Chunk::new();
+        let zero = chunk.add_constant(Value::Integer(0));
+        let one = chunk.add_constant(Value::Integer(1));
+        let name = chunk.add_constant(Value::String("g".into()));
+        let next = chunk.add_constant(Value::String("next".into()));
+        let len = chunk.add_constant(Value::String("len".into()));
+
+        // Reads and writes the local captured from the script
+        let mut upvalue_chunk = Chunk::new();
+        for instruction in [OpCode::GetUpvalue(0), OpCode::SetUpvalue(0), OpCode::Return] {
+            upvalue_chunk.write(instruction, 1);
+        }
+        let closure = chunk.add_constant(Value::Function(Function {
+            chunk: upvalue_chunk,
+            upvalues: vec![UpvalueRef {
+                is_local: true,
+                index: 1,
+            }],
+            ..Function::new()
+        }));
+
+        let mut generator_chunk = Chunk::new();
+        for instruction in [OpCode::True, OpCode::Yield, OpCode::Null, OpCode::Return] {
+            generator_chunk.write(instruction, 1);
+        }
+        let generator = chunk.add_constant(Value::Function(Function {
+            chunk: generator_chunk,
+            is_generator: true,
+            ..Function::new()
+        }));
+
+        let binary = [
+            OpCode::Equal,
+            OpCode::NotEqual,
+            OpCode::Greater,
+            OpCode::GreaterEqual,
+            OpCode::Less,
+            OpCode::LessEqual,
+            OpCode::Add,
+            OpCode::Subtract,
+            OpCode::Multiply,
+            OpCode::Divide,
+            OpCode::Modulo,
+            OpCode::Power,
+        ];
+        let mut code = vec![];
+        for instruction in binary {
+            code.extend([
+                OpCode::Constant(one),
+                OpCode::Constant(one),
+                instruction,
+                OpCode::Pop,
+            ]);
+        }
+        code.extend([
+            OpCode::Null,
+            OpCode::Pop,
+            OpCode::False,
+            OpCode::Not,
+            OpCode::Pop,
+            OpCode::Constant(one),
+            OpCode::Negate,
+            OpCode::Pop,
+            // Globals
+            OpCode::Constant(one),
+            OpCode::DefineGlobal(name),
+            OpCode::Constant(zero),
+            OpCode::SetGlobal(name),
+            OpCode::Pop,
+            OpCode::GetGlobal(name),
+            // Local in slot 1, captured by the closure
+            OpCode::GetLocal(1),
+            OpCode::SetLocal(1),
+            OpCode::Pop,
+            OpCode::Closure(closure),
+            OpCode::Call(0),
+            OpCode::Pop,
+            OpCode::CloseUpvalue,
+            // Jumps to the next instruction
+            OpCode::Jump(0),
+            OpCode::True,
+            OpCode::JumpIfFalse(0),
+            OpCode::JumpIfNull(0),
+            OpCode::Pop,
+            OpCode::Loop(0),
+            // Arrays
+            OpCode::Constant(zero),
+            OpCode::Constant(one),
+            OpCode::BuildArray(2),
+            OpCode::Constant(zero),
+            OpCode::Index,
+            OpCode::Pop,
+            OpCode::Constant(zero),
+            OpCode::Constant(one),
+            OpCode::BuildArray(2),
+            OpCode::Constant(zero),
+            OpCode::Constant(one),
+            OpCode::SetIndex,
+            OpCode::Unpack(2),
+            OpCode::Pop,
+            OpCode::Pop,
+            OpCode::Pop,
+            // Maps
+            OpCode::Constant(zero),
+            OpCode::Constant(one),
+            OpCode::BuildMap(1),
+            OpCode::Constant(zero),
+            OpCode::HasEntry,
+            OpCode::Pop,
+            OpCode::Constant(zero),
+            OpCode::Constant(one),
+            OpCode::BuildMap(1),
+            OpCode::Constant(zero),
+            OpCode::Entry,
+            OpCode::Pop,
+            OpCode::Pop,
+            OpCode::Constant(zero),
+            OpCode::Constant(one),
+            OpCode::BuildMap(1),
+            OpCode::Constant(zero),
+            OpCode::UnpackMap(1),
+            OpCode::Pop,
+            // Method calls
+            OpCode::BuildArray(0),
+            OpCode::Invoke(len, 1),
+            OpCode::Pop,
+            // Generators
+            OpCode::GetGlobal(next),
+            OpCode::Closure(generator),
+            OpCode::Call(0),
+            OpCode::Call(1),
+            OpCode::Return,
+        ]);
+        for instruction in code {
+            chunk.write(instruction, 1);
+        }
+
+        let functions = chunk
+            .constants
+            .iter()
+            .filter_map(|constant| match constant {
+                Value::Function(function) => Some(&function.chunk.code),
+                _ => None,
+            });
+        let mut covered: Vec<usize> = functions
+            .chain([&chunk.code])
+            .flatten()
+            .map(OpCode::index)
+            .collect();
+        covered.sort_unstable();
+        covered.dedup();
+        assert_eq!(covered, (0..OpCode::COUNT).collect::<Vec<usize>>());
+        for instruction in &chunk.code {
+            assert!(format!("{:?}", instruction).starts_with(OPCODE_NAMES[instruction.index()]));
+        }
+
+        let mut vm = VirtualMachine::new(Function {
+            chunk,
+            ..Function::new()
+        });
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(vm.runtime_error, None);
+        assert_eq!(vm.stack.last(), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn while_loop_factorial() {
+        let input = "let factorial = 1
+        let i = 1
+        while (i <= 5) {
+            factorial = factorial * i
+            i = i + 1
+        }
+        let grouped = (1 + 2) * 3";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("factorial"), Some(&Value::Integer(120)));
+        assert_eq!(vm.globals.get("grouped"), Some(&Value::Integer(9)));
+    }
+
+    #[test]
+    fn strict_arithmetic() {
+        // Squaring ten times gives 10^1024, past the largest float
+        let input = "let big = 10.0
+        let i = 0
+        while i < 10 {
+            big = big * big
+            i = i + 1
+        }";
+
+        for (strict_arithmetic, expected) in [
+            (false, InterpretResult::Ok),
+            (true, InterpretResult::RuntimeError),
+        ] {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+            vm.strict_arithmetic = strict_arithmetic;
+
+            assert_eq!(vm.interpret(), expected);
+            if strict_arithmetic {
+                assert_eq!(
+                    vm.runtime_error,
+                    Some("arithmetic produced inf".to_string())
+                );
+            } else {
+                assert_eq!(vm.globals.get("big"), Some(&Value::Number(f64::INFINITY)));
+            }
+        }
+    }
+
+    #[test]
+    fn for_loop_sum() {
+        let input = "let sum = 0
+        for (let i = 0; i < 10; i = i + 1) {
+            sum = sum + i
+        }
+        let steps = 0
+        for (; steps < 4; steps = steps + 1) {
+        }";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Integer(45)));
+        assert_eq!(vm.globals.get("steps"), Some(&Value::Integer(4)));
+    }
+
+    #[test]
+    fn map_destructuring() {
+        let input = "let {a, b} = {\"a\": 1, \"b\": 2, \"c\": 3}
+        fn swap(point) {
+            let {x: y, y: x} = point
+            return [x, y]
+        }
+        let swapped = swap({\"x\": 5, \"y\": 6})";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(1)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Integer(2)));
+        assert_eq!(
+            vm.globals.get("swapped"),
+            Some(&Value::Array(vec![Value::Integer(6), Value::Integer(5)]))
+        );
+    }
+
+    #[test]
+    fn map_destructuring_errors() {
+        let tests = [
+            ("let {a, z} = {\"a\": 1}", "missing key z"),
+            ("let {a} = [1]", "only maps can be unpacked by key"),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        let input = "let a = false && [][0]
+        let b = true || [][0]
+        let c = true || false
+        let d = 1 < 2 && 3
+        let e = null || \"default\"
+        let f = false || true && false";
+
+        let vm = run_globals(input);
+
+        assert_eq!(vm.globals.get("a"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.globals.get("d"), Some(&Value::Integer(3)));
+        assert_eq!(vm.globals.get("e"), Some(&Value::String("default".into())));
+        assert_eq!(vm.globals.get("f"), Some(&Value::Boolean(false)));
+    }
+
+    #[test]
+    fn compiles_large_generated_program() {
+        // The compiler emits bytecode as it reads tokens, without a syntax tree
+        let mut input = String::from("let total = 0\n");
+        for i in 0..5000 {
+            input.push_str(&format!("let v{i} = {i}\ntotal = total + v{i}\n"));
+        }
+
+        let vm = run_globals(&input);
+
+        assert_eq!(vm.globals.get("total"), Some(&Value::Integer(12497500)));
+        assert_eq!(vm.globals.get("v4999"), Some(&Value::Integer(4999)));
+    }
+
+    #[test]
+    fn block_locals_use_their_slots() {
+        let input = "let result = 0
+        {
+          let first = 1
+          let second = 2
+          let third = 3
+          first = first + 10
+          result = first * 100 + second * 10 + third
+        }";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        // Slot 0 holds the script, locals are numbered from the frame start
+        let code = &compiler.current_chunk().code;
+        assert!(code.contains(&OpCode::GetLocal(1)));
+        assert!(code.contains(&OpCode::SetLocal(1)));
+        assert!(code.contains(&OpCode::GetLocal(3)));
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(vm.globals.get("result"), Some(&Value::Integer(1123)));
+    }
+
+    #[test]
+    fn invalid_local_slots() {
+        for instruction in [OpCode::GetLocal(5), OpCode::SetLocal(usize::MAX)] {
+            let mut chunk = Chunk::new();
+            chunk.write(OpCode::Null, 1);
+            chunk.write(instruction, 1);
+
+            let mut vm = VirtualMachine::new(Function {
+                chunk,
+                ..Default::default()
+            });
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+            assert_eq!(vm.runtime_error, Some("invalid local slot".to_string()));
+        }
+    }
+
+    #[test]
+    fn set_local_on_empty_stack() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Pop, 1);
+        chunk.write(OpCode::SetLocal(0), 1);
+
+        let mut vm = VirtualMachine::new(Function {
+            chunk,
+            ..Default::default()
+        });
+
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(vm.runtime_error, Some("stack underflow".to_string()));
+    }
+
+    #[test]
+    fn functions_with_many_locals() {
+        // Slots are usize operands, so locals past 255 need no wider opcode
+        let mut input = String::from("fn many() {\n");
+        for i in 0..300 {
+            input.push_str(&format!("let v{i} = {i}\n"));
+        }
+        input.push_str("v299 = v299 + v0 + 1\nreturn v299 + v256\n}\nlet result = many()");
+
+        let lexer = Lexer::new(&input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let Some(Value::Function(many)) = compiler
+            .current_chunk()
+            .constants
+            .iter()
+            .find(|constant| matches!(constant, Value::Function(_)))
+        else {
+            panic!("expected the function constant");
+        };
+        // Slot 0 holds the function, so v299 is in slot 300
+        assert!(many.chunk.code.contains(&OpCode::SetLocal(300)));
+        assert!(many.chunk.code.contains(&OpCode::GetLocal(257)));
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(vm.globals.get("result"), Some(&Value::Integer(556)));
+    }
+
+    #[test]
+    fn call_result_is_script_result() {
+        let input = "fn add(a, b) {
+            return a + b
+        }
+        add(add(1, 1), 3)";
+
+        let vm = run_globals(input);
+
+        // Each call's frame is gone, leaving the script and its result
+        assert_eq!(vm.stack.last(), Some(&Value::Integer(5)));
+        assert_eq!(vm.stack.len(), 2);
+    }
+
+    #[test]
+    fn identical_functions_share_constant() {
+        let input = "let inc = fn(x) {
+            return x + 1
+        }
+        let also_inc = fn(x) {
+            return x + 1
+        }
+        fn counters() {
+            let n = 1
+            let first = fn() {
+                return n
+            }
+            let second = fn() {
+                return n
+            }
+            return [first, second]
+        }";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let functions = |chunk: &Chunk| -> Vec<Function> {
+            chunk
+                .constants
+                .iter()
+                .filter_map(|constant| match constant {
+                    Value::Function(function) => Some(function.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let script_functions = functions(&compiler.function.chunk);
+        // inc and also_inc share one constant, counters has its own
+        assert_eq!(script_functions.len(), 2);
+        // Capturing functions stay distinct
+        assert_eq!(functions(&script_functions[1].chunk).len(), 2);
+    }
+
+    // Writer whose output can be read after the VM takes it
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn value_type_names() {
+        let vm = run_globals(
+            "fn* gen() {\n  yield 1\n}\nlet g = gen()\nlet it = iter([])\nfn f() {\n  return 1\n}\nlet native = len",
+        );
+
+        let tests = [
+            (Value::Integer(1), "integer"),
+            (Value::Number(1.5), "number"),
+            (Value::Boolean(true), "boolean"),
+            (Value::String("a".into()), "string"),
+            (Value::Function(Function::new()), "function"),
+            (vm.globals["f"].clone(), "function"),
+            (vm.globals["native"].clone(), "function"),
+            (Value::Array(vec![]), "array"),
+            (Value::Map(Map::new()), "map"),
+            (vm.globals["g"].clone(), "generator"),
+            (vm.globals["it"].clone(), "iterator"),
+            (Value::Null, "null"),
+        ];
+
+        for (value, expected) in tests {
+            assert_eq!(value.type_name(), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn type_builtin() {
+        let input = "let names = [type(1), type(1.5), type(\"a\"), type(null), type([]), type({}), type(len)]
+        let error = -[]";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        let names = [
+            "integer", "number", "string", "null", "array", "map", "function",
+        ]
+        .map(|name| Value::String(name.into()));
+        assert_eq!(vm.globals.get("names"), Some(&Value::Array(names.to_vec())));
+        // Error messages use the same names
+        assert_eq!(
+            vm.runtime_error,
+            Some(format!(
+                "operand must be a number, got {}",
+                Value::Array(vec![]).type_name()
+            ))
+        );
+    }
+
+    #[test]
+    fn value_display_and_debug() {
+        let vm = run_globals(
+            "fn* gen() {\n  yield 1\n}\nlet g = gen()\nfn f() {\n  return 1\n}\nlet native = len",
+        );
+        let string = |value: &str| Value::String(value.into());
+        let mut map = Map::new();
+        map.insert(string("a"), Value::Integer(1)).unwrap();
+        map.insert(
+            Value::Number(2.5),
+            Value::Array(vec![Value::Boolean(true), Value::Null]),
+        )
+        .unwrap();
+        let mut single = Map::new();
+        single.insert(string("a"), Value::Integer(1)).unwrap();
+
+        let display = [
+            (Value::Integer(-3), "-3"),
+            (Value::Number(2.0), "2"),
+            (Value::Number(-0.5), "-0.5"),
+            (Value::Number(-0.0), "-0"),
+            (Value::Number(f64::INFINITY), "inf"),
+            (Value::Number(f64::NEG_INFINITY), "-inf"),
+            (Value::Number(f64::NAN), "NaN"),
+            (Value::Boolean(false), "false"),
+            (string("a b"), "a b"),
+            (Value::Null, "null"),
+            (
+                Value::Array(vec![
+                    Value::Integer(1),
+                    Value::Array(vec![Value::Number(2.5), string("x")]),
+                    Value::Array(vec![]),
+                ]),
+                "[1, [2.5, x], []]",
+            ),
+            (Value::Map(map), "{a: 1, 2.5: [true, null]}"),
+            (Value::Map(Map::new()), "{}"),
+            (Value::Function(Function::new()), "function"),
+            (vm.globals["f"].clone(), "function"),
+            (vm.globals["native"].clone(), "<native len>"),
+            (vm.globals["g"].clone(), "<generator gen>"),
+        ];
+        for (value, expected) in display {
+            assert_eq!(value.to_string(), expected, "{:?}", value);
+        }
+
+        let debug = [
+            (Value::Integer(-3), "Integer(-3)"),
+            (Value::Number(2.0), "Number(2.0)"),
+            (Value::Number(f64::NAN), "Number(NaN)"),
+            (Value::Boolean(true), "Boolean(true)"),
+            (string("a"), "String(\"a\")"),
+            (Value::Null, "Null"),
+            (
+                Value::Array(vec![Value::Integer(1), Value::Array(vec![Value::Null])]),
+                "Array([Integer(1), Array([Null])])",
+            ),
+            (
+                Value::Map(single),
+                "Map(Map { entries: [(String(\"a\"), Integer(1))], positions: {String(\"a\"): 0} })",
+            ),
+        ];
+        for (value, expected) in debug {
+            assert_eq!(format!("{:?}", value), expected);
+        }
+    }
+
+    #[test]
+    fn debug_builtin() {
+        let input = "let a = 1
+        let x = debug(2 + 2) * 10
+        debug([a, \"b\"])";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        vm.set_error_writer(Box::new(SharedBuffer(output.clone())));
+
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(vm.globals.get("x"), Some(&Value::Integer(40)));
+        assert_eq!(
+            String::from_utf8_lossy(&output.borrow()),
+            "[line 2] 4: integer\n[line 3] [1, b]: array\n"
+        );
+    }
+
+    #[test]
+    fn output_writer_captures_print() {
+        let lexer = Lexer::new("print(1)\nprintln(\"a\")\nprint([2])");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        vm.set_output_writer(Box::new(SharedBuffer(output.clone())));
+
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(String::from_utf8_lossy(&output.borrow()), "1a\n[2]");
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let input = "fn f(n) {
+            return f(n + 1)
+        }
+        f(0)";
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(vm.runtime_error, Some("stack overflow".to_string()));
+
+        // Bytecode pushing a value on every iteration
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Null, 1);
+        chunk.write(OpCode::Loop(2), 1);
+        let mut vm = VirtualMachine::new(Function {
+            chunk,
+            ..Function::new()
+        });
+        vm.max_stack = 100;
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(vm.runtime_error, Some("stack overflow".to_string()));
+        assert_eq!(vm.stack.len(), 101);
+
+        // Each level resumes a generator from next, nesting runs of the VM
+        let input = "fn* gen(n) {
+            yield go(n)
+        }
+        fn go(n) {
+            if n == 0 {
+                return 0
+            }
+            return next(gen(n - 1))
+        }
+        let shallow = go(20)
+        go(300)";
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        assert_eq!(vm.interpret(), InterpretResult::RuntimeError);
+        assert_eq!(vm.runtime_error, Some("stack overflow".to_string()));
+        assert_eq!(vm.globals.get("shallow"), Some(&Value::Integer(0)));
+    }
+
+    #[test]
+    fn trace() {
+        // 1 + 2 is folded, so the script only loads the constant
+        let tests = [
+            (
+                "1 + 2",
+                "0000 Constant(0) [function]\n0001 Return [3, function]\n",
+            ),
+            (
+                "let a = 1\na + 2",
+                "0000 Constant(1) [function]\n\
+                 0001 DefineGlobal(0) [1, function]\n\
+                 0002 GetGlobal(2) [function]\n\
+                 0003 Constant(3) [1, function]\n\
+                 0004 Add [2, 1, function]\n\
+                 0005 Return [3, function]\n",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let output = Rc::new(RefCell::new(vec![]));
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+            vm.trace = true;
+            vm.set_error_writer(Box::new(SharedBuffer(output.clone())));
+
+            assert_eq!(vm.interpret(), InterpretResult::Ok);
+            assert_eq!(
+                String::from_utf8_lossy(&output.borrow()),
+                expected,
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn sandbox() {
+        let tests = [
+            ("let t = clock()", "builtin not available"),
+            ("let t = time_ns(1, 2)", "builtin not available"),
+            ("while true {\n}", "interrupted"),
+            ("fn f(n) {\nreturn f(n + 1)\n}\nf(0)", "stack overflow"),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+            compiler.compile();
+            check_compiler_errors(&compiler);
+
+            let mut vm = VirtualMachine::new(take(&mut compiler.function));
+            vm.sandbox();
+
+            assert_eq!(vm.interpret(), InterpretResult::RuntimeError, "{input}");
+            assert_eq!(vm.runtime_error, Some(expected.to_string()), "{input}");
+        }
+
+        // Everything else still runs, printing through the host's writer
+        let lexer = Lexer::new(
+            "fn f(n) {\nif n == 0 {\nreturn 0\n}\nreturn f(n - 1)\n}\nlet a = f(100)\nprintln(a)",
+        );
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        vm.set_output_writer(Box::new(SharedBuffer(output.clone())));
+        vm.sandbox();
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+        assert_eq!(vm.globals.get("a"), Some(&Value::Integer(0)));
+        assert_eq!(String::from_utf8_lossy(&output.borrow()), "0\n");
+    }
+
+    #[test]
+    fn compile_against_known_symbols() {
+        let lexer = Lexer::new("let x = 5");
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        assert_eq!(compiler.compile(), InterpretResult::Ok);
+        let symbols = take(&mut compiler.symbols);
+
+        let tests = [
+            ("let y = x * len(\"ab\")", None),
+            ("fn f() {\n  return x + y\n}\nlet y = 1", None),
+            ("x = missing", Some("Line 1:5: Undefined variable missing")),
+            ("fn f() {\n  return g()\n}", None),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut compiler = Compiler::new_with_symbols(
+                Rc::new(RefCell::new(lexer)),
+                FunctionType::Script,
+                symbols.clone(),
+            );
+            compiler.compile();
+
+            let error = compiler.errors.first().map(|error| error.to_string());
+            assert_eq!(error.as_deref(), expected, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn profile_counts_opcodes() {
+        let input = "let sum = 0
+        let i = 0
+        while i < 10 {
+            sum = sum + i
+            i = i + 1
+        }";
+
+        let lexer = Lexer::new(input);
+        let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+        compiler.compile();
+        check_compiler_errors(&compiler);
+
+        let mut vm = VirtualMachine::new(take(&mut compiler.function));
+        assert_eq!(vm.profile(), vec![]);
+        vm.enable_profile();
+        assert_eq!(vm.interpret(), InterpretResult::Ok);
+
+        let profile = vm.profile();
+        let count = |name: &str| profile.iter().find(|(n, _)| *n == name).map(|(_, c)| *c);
+        assert_eq!(count("Loop"), Some(10));
+        // Two additions per iteration
+        assert_eq!(count("Add"), Some(20));
+        // The condition is checked once more to exit
+        assert_eq!(count("Less"), Some(11));
+        assert!(profile.windows(2).all(|pair| pair[0].1 >= pair[1].1));
     }
 }
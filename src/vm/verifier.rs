@@ -0,0 +1,112 @@
+use super::chunk::{Chunk, OpCode};
+
+impl OpCode {
+    // Number of values the instruction needs on the stack and the net change it produces
+    pub fn stack_effect(&self) -> (usize, isize) {
+        match self {
+            OpCode::Constant(_)
+            | OpCode::Null
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal(_)
+            | OpCode::GetLocal(_)
+            | OpCode::GetUpvalue(_)
+            | OpCode::Closure(_) => (0, 1),
+            OpCode::Not | OpCode::Negate => (1, 0),
+            OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Power => (2, -1),
+            OpCode::Pop | OpCode::DefineGlobal(_) | OpCode::CloseUpvalue | OpCode::Yield => (1, -1),
+            OpCode::SetGlobal(_)
+            | OpCode::SetLocal(_)
+            | OpCode::SetUpvalue(_)
+            | OpCode::JumpIfFalse(_)
+            | OpCode::JumpIfNull(_) => (1, 0),
+            OpCode::Jump(_) | OpCode::Loop(_) => (0, 0),
+            // Callee and arguments are replaced by the result
+            OpCode::Call(arguments) => (
+                arguments.saturating_add(1),
+                0isize.saturating_sub_unsigned(*arguments),
+            ),
+            // The receiver and arguments are replaced by the result
+            OpCode::Invoke(_, values) => (*values, 1isize.saturating_sub_unsigned(*values)),
+            OpCode::Return => (1, -1),
+            // Elements are replaced by the array
+            OpCode::BuildArray(elements) => (*elements, 1isize.saturating_sub_unsigned(*elements)),
+            OpCode::Index => (2, -1),
+            OpCode::SetIndex => (3, -1),
+            // The array is replaced by its elements
+            OpCode::Unpack(elements) => (1, (*elements as isize).saturating_sub(1)),
+            // Keys and values are replaced by the map
+            OpCode::BuildMap(entries) => (
+                entries.saturating_mul(2),
+                1isize.saturating_sub_unsigned(entries.saturating_mul(2)),
+            ),
+            // The collection and position are replaced by the check or the entry
+            OpCode::HasEntry => (2, -1),
+            OpCode::Entry => (2, 0),
+            // The map and keys are replaced by the values
+            OpCode::UnpackMap(keys) => (keys.saturating_add(1), -1),
+        }
+    }
+}
+
+// Simulates the stack depth along every path of the chunk, checking that no
+// instruction pops values it doesn't own and that paths merge with the same depth
+pub fn verify_stack_effect(chunk: &Chunk) -> Result<(), String> {
+    let mut depths: Vec<Option<usize>> = vec![None; chunk.code.len() + 1];
+    let mut pending = vec![(0, 0)];
+
+    while let Some((pc, depth)) = pending.pop() {
+        match depths.get(pc) {
+            Some(Some(known)) if *known == depth => continue,
+            Some(Some(known)) => {
+                return Err(format!(
+                    "stack imbalance at instruction {}: depth {} and {}",
+                    pc, known, depth
+                ))
+            }
+            Some(None) => depths[pc] = Some(depth),
+            None => return Err(format!("jump to {} is outside the chunk", pc)),
+        }
+
+        let Some(instruction) = chunk.code.get(pc) else {
+            // Falling off the end finishes the function
+            continue;
+        };
+
+        let (needed, effect) = instruction.stack_effect();
+        if depth < needed {
+            return Err(format!(
+                "stack underflow at instruction {} ({:?})",
+                pc, instruction
+            ));
+        }
+        let next_depth = depth.saturating_add_signed(effect);
+
+        match instruction {
+            OpCode::Return => (),
+            OpCode::Jump(offset) => pending.push(((pc + 1).saturating_add(*offset), next_depth)),
+            OpCode::JumpIfFalse(offset) | OpCode::JumpIfNull(offset) => {
+                pending.push((pc + 1, next_depth));
+                pending.push(((pc + 1).saturating_add(*offset), next_depth));
+            }
+            OpCode::Loop(offset) => match (pc + 1).checked_sub(*offset) {
+                Some(target) => pending.push((target, next_depth)),
+                None => return Err(format!("loop at {} jumps before the chunk", pc)),
+            },
+            _ => pending.push((pc + 1, next_depth)),
+        }
+    }
+
+    Ok(())
+}
@@ -1,27 +1,128 @@
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, Write};
 use std::mem::take;
+use std::time::Instant;
 use std::{collections::HashMap, rc::Rc};
 
-use crate::common::lexer::lexer_impl::Lexer;
+use crate::common::{indexing::resolve_index, lexer::lexer_impl::Lexer};
 
 use super::{
-    chunk::{value_equal, OpCode, Value},
+    chunk::{
+        arithmetic, compare_numbers, negate, value_equal, value_loose_equal, OpCode, Value,
+        OPCODE_NAMES,
+    },
     compiler::Compiler,
-    object::{Function, FunctionType},
+    disassembler::{disassemble_all, find_function, summarize_all},
+    natives::{builtins, MonotonicClock, Random},
+    object::{
+        Closure, Function, FunctionType, Generator, GeneratorState, Interner, Map, NativeFunction,
+        Upvalue, ValueIterator,
+    },
 };
 
 #[derive(Debug)]
 struct CallFrame {
-    function: Function,
+    closure: Closure,
     pc: usize,
     slots_start: usize,
+    // Generator resumed by this frame, yield saves the frame into it
+    generator: Option<Rc<RefCell<Generator>>>,
 }
 
+impl CallFrame {
+    // Stack position of a local, None when the operand can't be a slot
+    fn slot(&self, index: usize) -> Option<usize> {
+        self.slots_start.checked_add(index)
+    }
+}
+
+// Host callback consulted every `interval` instructions to allow cancellation
+pub struct Watchdog {
+    interval: usize,
+    countdown: usize,
+    should_continue: Box<dyn FnMut() -> bool>,
+}
+
+impl Watchdog {
+    fn tick(&mut self) -> bool {
+        self.countdown -= 1;
+        if self.countdown > 0 {
+            return true;
+        }
+
+        self.countdown = self.interval;
+        (self.should_continue)()
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Watchdog({})", self.interval)
+    }
+}
+
+// Destination of the output of natives, the host can replace it
+pub(super) struct HostWriter(pub(super) Box<dyn Write>);
+
+impl fmt::Debug for HostWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostWriter")
+    }
+}
+
+// Default bounds of the call depth and the values on the stack, past them
+// the script fails with a stack overflow instead of growing until the
+// process dies
+const MAX_FRAMES: usize = 1024;
+const MAX_STACK: usize = 1 << 20;
+// Natives calling back into the VM, like next on a generator, nest a Rust
+// call of run for each level. A debug build uses tens of KB of native stack
+// per level, so this stays well within a 2 MB thread
+const MAX_NATIVE_DEPTH: usize = 32;
+
+// Builtins reading the host clock, unavailable in a sandbox
+const SANDBOX_DISABLED_BUILTINS: [&str; 2] = ["clock", "time_ns"];
+const SANDBOX_MAX_STEPS: usize = 10_000_000;
+const SANDBOX_MAX_FRAMES: usize = 256;
+const SANDBOX_MAX_STACK: usize = 1 << 16;
+
 #[derive(Debug)]
 pub struct VirtualMachine {
     frames: Vec<CallFrame>,
     pub stack: Vec<Value>,
-    pub globals: HashMap<String, Value>,
+    pub globals: HashMap<Rc<str>, Value>,
+    // Native functions, read when no global has the same name
+    builtins: HashMap<String, Value>,
+    pub runtime_error: Option<String>,
+    // Upvalues still pointing to the stack, ordered by slot
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    // Compare values of different types by their printed form
+    pub loose_equality: bool,
+    // Arithmetic producing inf or NaN is a runtime error instead of a value
+    pub strict_arithmetic: bool,
+    // Write each instruction with the stack, top first, to the error writer
+    // before running it
+    pub trace: bool,
+    watchdog: Option<Watchdog>,
+    // State of the random builtins
+    pub(super) random: Random,
+    pub(super) monotonic_clock: MonotonicClock,
+    // Where print and println write, stdout by default
+    pub(super) output_writer: HostWriter,
+    // Where debug writes, stderr by default
+    pub(super) error_writer: HostWriter,
+    // Calls deeper than this are a runtime error
+    pub max_frames: usize,
+    // As are more values than this on the stack
+    pub max_stack: usize,
+    // Runs started from natives that haven't returned yet
+    native_depth: usize,
+    // Times each opcode ran, by opcode index, when profiling
+    profile: Option<Box<[u64; OpCode::COUNT]>>,
+    // Constants and concatenations share their text through here
+    pub(super) strings: Interner,
 }
 
 #[derive(Debug, PartialEq)]
@@ -33,55 +134,481 @@ pub enum InterpretResult {
 
 impl VirtualMachine {
     pub fn new(function: Function) -> VirtualMachine {
-        let stack = vec![Value::Function(function.clone())];
+        VirtualMachine::new_with_globals(function, HashMap::new())
+    }
 
-        let call_frame = CallFrame {
-            function,
+    pub fn new_with_globals(
+        function: Function,
+        globals: HashMap<Rc<str>, Value>,
+    ) -> VirtualMachine {
+        let mut vm = VirtualMachine {
+            frames: vec![],
+            stack: vec![],
+            globals,
+            builtins: builtins(),
+            runtime_error: None,
+            open_upvalues: vec![],
+            loose_equality: false,
+            strict_arithmetic: false,
+            trace: false,
+            watchdog: None,
+            random: Random::from_time(),
+            monotonic_clock: MonotonicClock::Steady(Instant::now()),
+            output_writer: HostWriter(Box::new(io::stdout())),
+            error_writer: HostWriter(Box::new(io::stderr())),
+            max_frames: MAX_FRAMES,
+            max_stack: MAX_STACK,
+            native_depth: 0,
+            profile: None,
+            strings: Interner::default(),
+        };
+        vm.load(function);
+        vm
+    }
+
+    // Replaces the script to run, keeping the globals and the settings of
+    // the host
+    pub fn load(&mut self, mut function: Function) {
+        self.strings.intern_constants(&mut function.chunk);
+        self.load_shared(Rc::new(function));
+    }
+
+    // Like load for a script shared with other VMs, whose string constants
+    // aren't interned
+    pub fn load_shared(&mut self, function: Rc<Function>) {
+        let closure = Closure::new(function);
+        self.stack = vec![Value::Closure(closure.clone())];
+        self.frames = vec![CallFrame {
+            closure,
             pc: 0,
             slots_start: 0,
+            generator: None,
+        }];
+        self.open_upvalues.clear();
+        self.runtime_error = None;
+    }
+
+    // Installs a callback checked every `interval` instructions, execution
+    // stops with a runtime error when it returns false
+    pub fn set_watchdog(&mut self, interval: usize, should_continue: Box<dyn FnMut() -> bool>) {
+        let interval = interval.max(1);
+        self.watchdog = Some(Watchdog {
+            interval,
+            countdown: interval,
+            should_continue,
+        });
+    }
+
+    // Replaces the source of time_ns, which returns the callback's nanoseconds
+    pub fn set_monotonic_clock(&mut self, clock: Box<dyn FnMut() -> u64>) {
+        self.monotonic_clock = MonotonicClock::Custom(clock);
+    }
+
+    // Starts counting the opcodes executed from now on
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Box::new([0; OpCode::COUNT]));
+    }
+
+    // Opcodes executed while profiling, the most frequent first
+    pub fn profile(&self) -> Vec<(&'static str, u64)> {
+        let Some(counts) = &self.profile else {
+            return vec![];
         };
-        let frames = vec![call_frame];
+        let mut profile: Vec<(&'static str, u64)> = OPCODE_NAMES
+            .into_iter()
+            .zip(counts.iter().copied())
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        profile.sort_by(|(a_name, a_count), (b_name, b_count)| {
+            b_count.cmp(a_count).then(a_name.cmp(b_name))
+        });
+        profile
+    }
+
+    // Replaces stderr as the destination of debug
+    pub fn set_error_writer(&mut self, writer: Box<dyn Write>) {
+        self.error_writer = HostWriter(writer);
+    }
+
+    // Replaces stdout as the destination of print and println
+    pub fn set_output_writer(&mut self, writer: Box<dyn Write>) {
+        self.output_writer = HostWriter(writer);
+    }
+
+    // Keeps the name of a builtin bound to a native that always fails, so
+    // scripts get a clear error instead of an undefined variable
+    pub fn disable_builtin(&mut self, name: &str) {
+        if let Some(Value::NativeFunction(native)) = self.builtins.get_mut(name) {
+            native.min_arity = 0;
+            native.max_arity = usize::MAX;
+            native.function = unavailable;
+        }
+    }
+
+    // Preset for untrusted scripts: no access to the host clock, a lower
+    // call depth and stack size and a cap on the instructions run, after
+    // which the script is interrupted
+    pub fn sandbox(&mut self) {
+        for name in SANDBOX_DISABLED_BUILTINS {
+            self.disable_builtin(name);
+        }
+        self.max_frames = SANDBOX_MAX_FRAMES;
+        self.max_stack = SANDBOX_MAX_STACK;
+        self.set_watchdog(SANDBOX_MAX_STEPS, Box::new(|| false));
+    }
+
+    // Source line of the instruction being executed, for natives it's the
+    // line of the call
+    pub(super) fn current_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+        frame
+            .closure
+            .function
+            .chunk
+            .get_line(frame.pc.checked_sub(1)?)
+    }
+
+    pub fn sorted_globals(&self) -> Vec<(&Rc<str>, &Value)> {
+        sorted_globals(&self.globals)
+    }
+
+    // Pushes the result of an arithmetic operation, checking it's finite in
+    // strict mode
+    fn push_number(&mut self, number: f64) -> Result<(), InterpretResult> {
+        if self.strict_arithmetic && !number.is_finite() {
+            return Err(self.report_error(&format!("arithmetic produced {}", number)));
+        }
+        self.stack.push(Value::Number(number));
+        Ok(())
+    }
+
+    fn values_equal(&self, a: Value, b: Value) -> bool {
+        if self.loose_equality {
+            value_loose_equal(a, b)
+        } else {
+            value_equal(a, b)
+        }
+    }
+
+    fn report_error(&mut self, message: &str) -> InterpretResult {
+        self.runtime_error = Some(message.to_string());
+        InterpretResult::RuntimeError
+    }
 
-        VirtualMachine {
-            frames,
-            stack,
-            globals: HashMap::new(),
+    // Runs a native with the arguments on top of the stack, replacing the
+    // callee and the arguments with the result
+    fn call_native(
+        &mut self,
+        native: NativeFunction,
+        slots_start: usize,
+        arguments_count: usize,
+    ) -> Result<(), String> {
+        if arguments_count < native.min_arity || arguments_count > native.max_arity {
+            return Err(format!(
+                "{} expected {} arguments but got {}",
+                native.name,
+                arity_range(native.min_arity, native.max_arity),
+                arguments_count
+            ));
         }
+
+        let arguments = self.stack.split_off(slots_start + 1);
+        self.stack.pop();
+
+        let result = (native.function)(self, &arguments)?;
+        self.stack.push(result);
+        Ok(())
     }
 
-    pub fn new_with_globals(function: Function, globals: HashMap<String, Value>) -> VirtualMachine {
-        let stack = vec![Value::Function(function.clone())];
+    pub fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
 
-        let call_frame = CallFrame {
-            function,
+    pub fn pop(&mut self) -> Option<Value> {
+        self.stack.pop()
+    }
+
+    // Calls a function from a native, running it to completion before returning
+    pub fn call_value(&mut self, callee: Value, arguments: &[Value]) -> Result<Value, String> {
+        let slots_start = self.stack.len();
+        self.stack.push(callee.clone());
+        self.stack.extend_from_slice(arguments);
+
+        let closure = match callee {
+            Value::Closure(closure) => closure,
+            Value::Function(function) => Closure::new(Rc::new(function)),
+            Value::NativeFunction(native) => {
+                self.call_native(native, slots_start, arguments.len())?;
+                return Ok(self.stack.pop().unwrap_or(Value::Null));
+            }
+            callee => {
+                self.stack.truncate(slots_start);
+                return Err(format!("{} is not callable", callee.type_name()));
+            }
+        };
+
+        if arguments.len() != closure.function.arity {
+            self.stack.truncate(slots_start);
+            return Err(format!(
+                "{} expected {} arguments but got {}",
+                closure.function.name,
+                closure.function.arity,
+                arguments.len()
+            ));
+        }
+
+        if closure.function.is_generator {
+            return Ok(self.create_generator(closure, slots_start));
+        }
+
+        self.run_frame(CallFrame {
+            closure,
             pc: 0,
-            slots_start: 0,
+            slots_start,
+            generator: None,
+        })
+    }
+
+    // Moves the callee and arguments into a generator that runs on next
+    fn create_generator(&mut self, closure: Closure, slots_start: usize) -> Value {
+        let generator = Generator {
+            closure,
+            pc: 0,
+            slots: self.stack.split_off(slots_start),
+            captured: vec![],
+            state: GeneratorState::Suspended,
         };
-        let frames = vec![call_frame];
+        Value::Generator(Rc::new(RefCell::new(generator)))
+    }
 
-        VirtualMachine {
-            frames,
-            stack,
-            globals,
+    // Runs the generator until its next yield, returning the yielded value or
+    // null once it has finished
+    pub(super) fn resume(&mut self, generator: Rc<RefCell<Generator>>) -> Result<Value, String> {
+        let slots_start = self.stack.len();
+        let (closure, pc) = {
+            let mut suspended = generator.borrow_mut();
+            match suspended.state {
+                GeneratorState::Done => return Ok(Value::Null),
+                GeneratorState::Running => return Err("generator is already running".to_string()),
+                GeneratorState::Suspended => (),
+            }
+            suspended.state = GeneratorState::Running;
+            self.stack.append(&mut suspended.slots);
+            // Closures may have changed the captured locals while suspended.
+            // Open upvalues are all below the new frame, so order is kept
+            for (offset, upvalue) in take(&mut suspended.captured) {
+                let slot = slots_start + offset;
+                if let (Upvalue::Closed(value), Some(local)) =
+                    (&*upvalue.borrow(), self.stack.get_mut(slot))
+                {
+                    *local = value.clone();
+                }
+                *upvalue.borrow_mut() = Upvalue::Open(slot);
+                self.open_upvalues.push(upvalue);
+            }
+            (suspended.closure.clone(), suspended.pc)
+        };
+
+        let result = self.run_frame(CallFrame {
+            closure,
+            pc,
+            slots_start,
+            generator: Some(generator.clone()),
+        });
+
+        let mut resumed = generator.borrow_mut();
+        // Only a yield leaves it suspended, returning or failing finishes it
+        if resumed.state == GeneratorState::Running {
+            resumed.state = GeneratorState::Done;
+        }
+        result
+    }
+
+    // Next item of the iterator, None once it's exhausted. A generator ends at
+    // its first null, like in while let
+    pub(super) fn next_item(
+        &mut self,
+        iterator: &Rc<RefCell<ValueIterator>>,
+    ) -> Result<Option<Value>, String> {
+        let generator = {
+            let mut iterator = iterator.borrow_mut();
+            if let Some(item) = iterator.peeked.take() {
+                return Ok(Some(item));
+            }
+            match iterator.generator() {
+                Some(generator) => generator,
+                None => return Ok(iterator.advance()),
+            }
+        };
+        match self.resume(generator)? {
+            Value::Null => Ok(None),
+            item => Ok(Some(item)),
+        }
+    }
+
+    // Reads the next item ahead, so next returns it
+    pub(super) fn has_next(
+        &mut self,
+        iterator: &Rc<RefCell<ValueIterator>>,
+    ) -> Result<bool, String> {
+        let item = self.next_item(iterator)?;
+        let has_next = item.is_some();
+        iterator.borrow_mut().peeked = item;
+        Ok(has_next)
+    }
+
+    // Calls the callee below the top `arguments_count` values, pushing a
+    // frame for closures and running natives and generators in place
+    fn call(&mut self, arguments_count: usize) -> Result<(), InterpretResult> {
+        // Slot 0 of the frame is the callee, followed by the arguments
+        let Some(slots_start) = self
+            .stack
+            .len()
+            .checked_sub(1)
+            .and_then(|last| last.checked_sub(arguments_count))
+        else {
+            return Err(InterpretResult::RuntimeError);
+        };
+        let Some(callee) = self.stack.get(slots_start) else {
+            return Err(InterpretResult::RuntimeError);
+        };
+
+        let closure = match callee {
+            Value::Closure(closure) => closure.clone(),
+            Value::Function(function) => Closure::new(Rc::new(function.clone())),
+            Value::NativeFunction(native) => {
+                let native = native.clone();
+                if let Err(message) = self.call_native(native, slots_start, arguments_count) {
+                    return Err(self.report_error(&message));
+                }
+                return Ok(());
+            }
+            callee => {
+                let message = format!("{} is not callable", callee.type_name());
+                return Err(self.report_error(&message));
+            }
+        };
+
+        if arguments_count != closure.function.arity {
+            let message = format!(
+                "{} expected {} arguments but got {}",
+                closure.function.name, closure.function.arity, arguments_count
+            );
+            return Err(self.report_error(&message));
+        }
+
+        if closure.function.is_generator {
+            let generator = self.create_generator(closure, slots_start);
+            self.stack.push(generator);
+            return Ok(());
+        }
+
+        let new_frame = CallFrame {
+            closure,
+            pc: 0,
+            slots_start,
+            generator: None,
+        };
+
+        self.push_frame(new_frame)
+            .map_err(|message| self.report_error(&message))
+    }
+
+    // Pushes a call, failing past the maximum depth
+    fn push_frame(&mut self, frame: CallFrame) -> Result<(), String> {
+        if self.frames.len() >= self.max_frames {
+            return Err("stack overflow".to_string());
+        }
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    // Runs a frame pushed from outside the dispatch loop until it returns,
+    // leaving the stack as it was
+    fn run_frame(&mut self, frame: CallFrame) -> Result<Value, String> {
+        let slots_start = frame.slots_start;
+        let base_frames = self.frames.len();
+        if self.native_depth >= MAX_NATIVE_DEPTH {
+            self.stack.truncate(slots_start);
+            return Err("stack overflow".to_string());
+        }
+        if let Err(message) = self.push_frame(frame) {
+            self.stack.truncate(slots_start);
+            return Err(message);
+        }
+
+        self.native_depth += 1;
+        let result = self.run(base_frames);
+        self.native_depth -= 1;
+        match result {
+            InterpretResult::Ok => Ok(self.stack.pop().unwrap_or(Value::Null)),
+            _ => {
+                // Unwind the failed call so the caller finds the stack as it was
+                close_upvalues(&mut self.open_upvalues, &self.stack, slots_start);
+                self.frames.truncate(base_frames);
+                self.stack.truncate(slots_start);
+                Err(self
+                    .runtime_error
+                    .clone()
+                    .unwrap_or_else(|| "runtime error".to_string()))
+            }
         }
     }
 
     pub fn interpret(&mut self) -> InterpretResult {
+        self.run(0)
+    }
+
+    // Executes until the number of frames goes back to `base_frames`. The script
+    // leaves its result above its slot, calls from natives replace the callee
+    // and arguments with it
+    fn run(&mut self, base_frames: usize) -> InterpretResult {
         loop {
+            if let Some(watchdog) = self.watchdog.as_mut() {
+                if !watchdog.tick() {
+                    return self.report_error("interrupted");
+                }
+            }
+            if self.stack.len() > self.max_stack {
+                return self.report_error("stack overflow");
+            }
+
             // Gets next OpCode using current PC
             let Some(frame) = self.frames.last_mut() else {
                 return InterpretResult::RuntimeError;
             };
-            let chunk = &mut frame.function.chunk;
+            let chunk = &frame.closure.function.chunk;
             let Some(instruction) = chunk.get(frame.pc) else {
+                let slots_start = frame.slots_start;
                 self.frames.pop();
-                if self.frames.is_empty() {
+                if self.frames.len() <= base_frames {
+                    if base_frames > 0 {
+                        self.stack.truncate(slots_start);
+                        self.stack.push(Value::Null);
+                    }
                     return InterpretResult::Ok;
                 }
                 continue;
             };
 
+            if self.trace {
+                let stack: Vec<String> = self.stack.iter().rev().map(Value::to_string).collect();
+                // Tracing is best effort, a failing writer doesn't stop the script
+                let _ = writeln!(
+                    self.error_writer.0,
+                    "{:04} {:?} [{}]",
+                    frame.pc,
+                    instruction,
+                    stack.join(", ")
+                );
+            }
+
             frame.pc += 1; // Increases current PC for next instruction
+            if let Some(counts) = self.profile.as_mut() {
+                counts[instruction.index()] += 1;
+            }
+            // Keep this match without a wildcard arm, so adding an opcode
+            // doesn't compile until the VM executes it
             match instruction {
                 OpCode::Constant(index) => {
                     let Some(constant) = chunk.get_constant(*index).cloned() else {
@@ -100,90 +627,99 @@ impl VirtualMachine {
                     }
                 }
                 OpCode::Equal => match (self.stack.pop(), self.stack.pop()) {
-                    (Some(a), Some(b)) => self.stack.push(Value::Boolean(value_equal(a, b))),
+                    (Some(a), Some(b)) => {
+                        let equal = self.values_equal(a, b);
+                        self.stack.push(Value::Boolean(equal));
+                    }
                     (_, _) => return InterpretResult::RuntimeError,
                 },
                 OpCode::NotEqual => match (self.stack.pop(), self.stack.pop()) {
-                    (Some(a), Some(b)) => self.stack.push(Value::Boolean(!value_equal(a, b))),
-                    (_, _) => return InterpretResult::RuntimeError,
-                },
-                OpCode::Greater => match (self.stack.pop(), self.stack.pop()) {
-                    (Some(Value::Number(first_value)), Some(Value::Number(second_value))) => {
-                        self.stack.push(Value::Boolean(second_value > first_value));
-                    }
-                    _ => return InterpretResult::RuntimeError,
-                },
-                OpCode::GreaterEqual => match (self.stack.pop(), self.stack.pop()) {
-                    (Some(Value::Number(first_value)), Some(Value::Number(second_value))) => {
-                        self.stack.push(Value::Boolean(second_value >= first_value));
-                    }
-                    _ => return InterpretResult::RuntimeError,
-                },
-                OpCode::Less => match (self.stack.pop(), self.stack.pop()) {
-                    (Some(Value::Number(first_value)), Some(Value::Number(second_value))) => {
-                        self.stack.push(Value::Boolean(second_value < first_value));
-                    }
-                    _ => return InterpretResult::RuntimeError,
-                },
-                OpCode::LessEqual => match (self.stack.pop(), self.stack.pop()) {
-                    (Some(Value::Number(first_value)), Some(Value::Number(second_value))) => {
-                        self.stack.push(Value::Boolean(second_value <= first_value));
+                    (Some(a), Some(b)) => {
+                        let equal = self.values_equal(a, b);
+                        self.stack.push(Value::Boolean(!equal));
                     }
-                    _ => return InterpretResult::RuntimeError,
+                    (_, _) => return InterpretResult::RuntimeError,
                 },
-                OpCode::Negate => {
-                    if let Some(value) = self.stack.last_mut() {
-                        match value {
-                            Value::Number(n) => *value = Value::Number(-*n),
-                            _ => return InterpretResult::RuntimeError,
+                OpCode::Greater | OpCode::GreaterEqual | OpCode::Less | OpCode::LessEqual => {
+                    let (Some(first_value), Some(second_value)) =
+                        (self.stack.pop(), self.stack.pop())
+                    else {
+                        return InterpretResult::RuntimeError;
+                    };
+                    // Comparisons with NaN are false, strings compare lexicographically
+                    let ordering = match (&second_value, &first_value) {
+                        (Value::String(second), Value::String(first)) => Some(second.cmp(first)),
+                        (second, first) if second.is_number() && first.is_number() => {
+                            compare_numbers(second, first)
                         }
-                    } else {
+                        (second, first) => {
+                            let message =
+                                operands_error("two numbers or two strings", second, first);
+                            return self.report_error(&message);
+                        }
+                    };
+                    let result = match instruction {
+                        OpCode::Greater => ordering == Some(Ordering::Greater),
+                        OpCode::GreaterEqual => {
+                            matches!(ordering, Some(Ordering::Greater | Ordering::Equal))
+                        }
+                        OpCode::Less => ordering == Some(Ordering::Less),
+                        _ => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                    };
+                    self.stack.push(Value::Boolean(result));
+                }
+                OpCode::Negate => {
+                    let Some(value) = self.stack.last_mut() else {
                         return InterpretResult::RuntimeError;
-                    }
+                    };
+                    let Some(negated) = negate(value) else {
+                        let message = match value {
+                            Value::Integer(_) => "integer overflow".to_string(),
+                            value => format!("operand must be a number, got {}", value.type_name()),
+                        };
+                        return self.report_error(&message);
+                    };
+                    *value = negated;
                 }
-                OpCode::Add => {
-                    for v in &self.stack {
-                        println!("{}", v);
+                OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Modulo
+                | OpCode::Power => {
+                    let (Some(first_value), Some(second_value)) =
+                        (self.stack.pop(), self.stack.pop())
+                    else {
+                        return InterpretResult::RuntimeError;
+                    };
+                    if let (OpCode::Add, Value::String(first), Value::String(second)) =
+                        (instruction, &first_value, &second_value)
+                    {
+                        let string = self.strings.intern(&format!("{}{}", second, first));
+                        self.stack.push(Value::String(string));
+                        continue;
                     }
-                    match (self.stack.pop(), self.stack.pop()) {
-                        (Some(Value::Number(first_value)), Some(Value::Number(second_value))) => {
-                            self.stack.push(Value::Number(first_value + second_value));
+                    match arithmetic(instruction, &second_value, &first_value) {
+                        Some(Value::Number(number)) => {
+                            if let Err(error) = self.push_number(number) {
+                                return error;
+                            }
                         }
-                        (Some(Value::String(first_value)), Some(Value::String(second_value))) => {
-                            self.stack
-                                .push(Value::String(format!("{}{}", second_value, first_value)));
+                        Some(value) => self.stack.push(value),
+                        None => {
+                            let message =
+                                arithmetic_error(instruction, &second_value, &first_value);
+                            return self.report_error(&message);
                         }
-                        _ => return InterpretResult::RuntimeError,
                     }
                 }
-                OpCode::Subtract => match (self.stack.pop(), self.stack.pop()) {
-                    (Some(Value::Number(first_value)), Some(Value::Number(second_value))) => {
-                        self.stack.push(Value::Number(second_value - first_value));
-                    }
-                    _ => return InterpretResult::RuntimeError,
-                },
-                OpCode::Multiply => match (self.stack.pop(), self.stack.pop()) {
-                    (Some(Value::Number(first_value)), Some(Value::Number(second_value))) => {
-                        self.stack.push(Value::Number(first_value * second_value));
-                    }
-                    _ => return InterpretResult::RuntimeError,
-                },
-                OpCode::Divide => match (self.stack.pop(), self.stack.pop()) {
-                    (Some(Value::Number(first_value)), Some(Value::Number(second_value))) => {
-                        if first_value == 0.0 {
-                            return InterpretResult::RuntimeError;
-                        }
-                        self.stack.push(Value::Number(second_value / first_value));
-                    }
-                    _ => return InterpretResult::RuntimeError,
-                },
                 OpCode::Pop => {
                     self.stack.pop();
                 }
                 OpCode::DefineGlobal(index) => {
-                    match (chunk.get_constant(*index), self.stack.last()) {
-                        (Some(Value::String(name)), Some(last)) => {
-                            self.globals.insert(name.clone(), last.clone());
+                    match (chunk.get_constant(*index), self.stack.pop()) {
+                        (Some(Value::String(name)), Some(value)) => {
+                            self.globals.insert(name.clone(), value);
                         }
                         (_, _) => return InterpretResult::RuntimeError,
                     }
@@ -193,9 +729,16 @@ impl VirtualMachine {
                         return InterpretResult::RuntimeError;
                     };
 
-                    match self.globals.get(name) {
+                    match self
+                        .globals
+                        .get(name.as_ref())
+                        .or_else(|| self.builtins.get(name.as_ref()))
+                    {
                         Some(value) => self.stack.push(value.clone()),
-                        None => return InterpretResult::RuntimeError,
+                        None => {
+                            let message = format!("undefined variable {}", name);
+                            return self.report_error(&message);
+                        }
                     };
                 }
                 OpCode::SetGlobal(index) => {
@@ -203,26 +746,33 @@ impl VirtualMachine {
                         return InterpretResult::RuntimeError;
                     };
 
-                    match self.stack.pop() {
-                        Some(value) => self.globals.insert(name.to_string(), value),
+                    // Only let defines globals
+                    let Some(global) = self.globals.get_mut(name.as_ref()) else {
+                        let message = format!("undefined variable {}", name);
+                        return self.report_error(&message);
+                    };
+                    match self.stack.last() {
+                        Some(value) => *global = value.clone(),
                         None => return InterpretResult::RuntimeError,
                     };
                 }
                 OpCode::GetLocal(index) => {
-                    let Some(value) = self.stack.get(*index+frame.slots_start-1) else {
-                        return InterpretResult::RuntimeError;
+                    let Some(value) = frame.slot(*index).and_then(|slot| self.stack.get(slot))
+                    else {
+                        return self.report_error("invalid local slot");
                     };
                     self.stack.push(value.clone());
                 }
                 OpCode::SetLocal(index) => {
                     let Some(last) = self.stack.last() else {
-                        return InterpretResult::RuntimeError;
+                        return self.report_error("stack underflow");
                     };
 
                     let last_value = last.clone();
 
-                    let Some(slot) = self.stack.get_mut(*index+frame.slots_start-1) else {
-                        return InterpretResult::RuntimeError;
+                    let Some(slot) = frame.slot(*index).and_then(|slot| self.stack.get_mut(slot))
+                    else {
+                        return self.report_error("invalid local slot");
                     };
 
                     *slot = last_value;
@@ -233,58 +783,448 @@ impl VirtualMachine {
                     };
 
                     if is_falsey(last) {
-                        frame.pc += target;
+                        let Some(pc) = jump_target(frame.pc, *target, chunk.code.len()) else {
+                            return self.report_error("invalid jump target");
+                        };
+                        frame.pc = pc;
+                    }
+                }
+                OpCode::JumpIfNull(target) => {
+                    if matches!(self.stack.last(), Some(Value::Null)) {
+                        let Some(pc) = jump_target(frame.pc, *target, chunk.code.len()) else {
+                            return self.report_error("invalid jump target");
+                        };
+                        frame.pc = pc;
                     }
                 }
                 OpCode::Jump(target) => {
-                    frame.pc += target;
+                    let Some(pc) = jump_target(frame.pc, *target, chunk.code.len()) else {
+                        return self.report_error("invalid jump target");
+                    };
+                    frame.pc = pc;
                 }
                 OpCode::Loop(target) => {
-                    frame.pc -= target;
+                    let Some(pc) = frame.pc.checked_sub(*target) else {
+                        return self.report_error("invalid loop target");
+                    };
+                    frame.pc = pc;
                 }
-                OpCode::Call(arguments_count) => {
-                    let Some(callee) = self.stack.get(self.stack.len() - 1 - arguments_count)
-                    else {
+                OpCode::Closure(index) => {
+                    let Some(Value::Function(function)) = chunk.get_constant(*index) else {
                         return InterpretResult::RuntimeError;
                     };
 
-                    let Value::Function(function) = callee else {
+                    let mut closure = Closure::new(Rc::new(function.clone()));
+                    for upvalue in &function.upvalues {
+                        let captured = if upvalue.is_local {
+                            let Some(slot) = frame.slot(upvalue.index) else {
+                                return InterpretResult::RuntimeError;
+                            };
+                            capture_upvalue(&mut self.open_upvalues, slot)
+                        } else {
+                            let Some(captured) = frame.closure.upvalues.get(upvalue.index) else {
+                                return InterpretResult::RuntimeError;
+                            };
+                            captured.clone()
+                        };
+                        closure.upvalues.push(captured);
+                    }
+
+                    self.stack.push(Value::Closure(closure));
+                }
+                OpCode::GetUpvalue(index) => {
+                    let Some(upvalue) = frame.closure.upvalues.get(*index) else {
                         return InterpretResult::RuntimeError;
                     };
 
-                    if *arguments_count != function.arity {
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack.get(*slot).cloned(),
+                        Upvalue::Closed(value) => Some(value.clone()),
+                    };
+                    let Some(value) = value else {
                         return InterpretResult::RuntimeError;
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue(index) => {
+                    let (Some(upvalue), Some(value)) =
+                        (frame.closure.upvalues.get(*index), self.stack.last())
+                    else {
+                        return InterpretResult::RuntimeError;
+                    };
+
+                    let value = value.clone();
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(slot) => match self.stack.get_mut(*slot) {
+                            Some(stack_value) => *stack_value = value,
+                            None => return InterpretResult::RuntimeError,
+                        },
+                        Upvalue::Closed(closed) => *closed = value,
                     }
+                }
+                OpCode::CloseUpvalue => {
+                    let Some(last_slot) = self.stack.len().checked_sub(1) else {
+                        return InterpretResult::RuntimeError;
+                    };
+                    close_upvalues(&mut self.open_upvalues, &self.stack, last_slot);
+                    self.stack.pop();
+                }
+                OpCode::Call(arguments_count) => {
+                    let arguments_count = *arguments_count;
+                    if let Err(result) = self.call(arguments_count) {
+                        return result;
+                    }
+                }
+                OpCode::Invoke(index, values_count) => {
+                    let Some(Value::String(name)) = chunk.get_constant(*index) else {
+                        return InterpretResult::RuntimeError;
+                    };
+                    let Some(receiver) = self.stack.len().checked_sub(*values_count) else {
+                        return InterpretResult::RuntimeError;
+                    };
 
-                    let new_frame = CallFrame {
-                        function: function.clone(),
-                        pc: 0,
-                        // Move the pointer to where function arguments start
-                        slots_start: self.stack.len() - arguments_count,
+                    // The function goes below the receiver, as if it was
+                    // called with it as the first argument
+                    let Some(function) = self
+                        .globals
+                        .get(name.as_ref())
+                        .or_else(|| self.builtins.get(name.as_ref()))
+                    else {
+                        let message = format!("undefined variable {}", name);
+                        return self.report_error(&message);
+                    };
+                    self.stack.insert(receiver, function.clone());
+                    let values_count = *values_count;
+                    if let Err(result) = self.call(values_count) {
+                        return result;
+                    }
+                }
+                OpCode::BuildArray(elements_count) => {
+                    let Some(start) = self.stack.len().checked_sub(*elements_count) else {
+                        return InterpretResult::RuntimeError;
+                    };
+                    let elements = self.stack.split_off(start);
+                    self.stack.push(Value::Array(elements));
+                }
+                OpCode::Index => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(index), Some(Value::Array(mut elements))) if index.is_number() => {
+                        let position = array_index(&index)
+                            .and_then(|index| resolve_index(index, elements.len()));
+                        let Some(position) = position else {
+                            return self.report_error(&format!("index out of bounds: {}", index));
+                        };
+                        self.stack.push(elements.swap_remove(position));
+                    }
+                    (Some(key), Some(Value::Map(map))) => match map.get(&key) {
+                        Ok(Some(value)) => self.stack.push(value.clone()),
+                        Ok(None) => return self.report_error(&format!("missing key {}", key)),
+                        Err(message) => return self.report_error(&message),
+                    },
+                    (Some(index), Some(Value::Array(_))) => {
+                        let message =
+                            format!("array index must be a number, got {}", index.type_name());
+                        return self.report_error(&message);
+                    }
+                    (Some(_), Some(collection)) => {
+                        let message = format!("{} can't be indexed", collection.type_name());
+                        return self.report_error(&message);
+                    }
+                    _ => return InterpretResult::RuntimeError,
+                },
+                OpCode::SetIndex => {
+                    let (Some(value), Some(index), Some(array)) =
+                        (self.stack.pop(), self.stack.pop(), self.stack.pop())
+                    else {
+                        return InterpretResult::RuntimeError;
+                    };
+                    if let Value::Map(mut map) = array {
+                        if let Err(message) = map.insert(index, value.clone()) {
+                            return self.report_error(&message);
+                        }
+                        self.stack.push(value);
+                        self.stack.push(Value::Map(map));
+                        continue;
+                    }
+                    let (true, Value::Array(mut elements)) = (index.is_number(), array) else {
+                        return self.report_error("only arrays and maps support index assignment");
+                    };
+                    let position =
+                        array_index(&index).and_then(|index| resolve_index(index, elements.len()));
+                    let Some(position) = position else {
+                        return self.report_error(&format!("index out of bounds: {}", index));
                     };
+                    elements[position] = value.clone();
+                    // The value stays below the array so storing the array
+                    // back leaves the value as the result
+                    self.stack.push(value);
+                    self.stack.push(Value::Array(elements));
+                }
+                OpCode::Unpack(elements_count) => {
+                    let elements_count = *elements_count;
+                    let Some(Value::Array(elements)) = self.stack.pop() else {
+                        return self.report_error("only arrays can be unpacked");
+                    };
+                    if elements.len() != elements_count {
+                        return self.report_error(&format!(
+                            "expected {} values to unpack but got {}",
+                            elements_count,
+                            elements.len()
+                        ));
+                    }
+                    self.stack.extend(elements);
+                }
+                OpCode::UnpackMap(keys_count) => {
+                    let Some(start) = self.stack.len().checked_sub(*keys_count) else {
+                        return InterpretResult::RuntimeError;
+                    };
+                    let keys = self.stack.split_off(start);
+                    let Some(Value::Map(map)) = self.stack.pop() else {
+                        return self.report_error("only maps can be unpacked by key");
+                    };
+                    for key in keys {
+                        let value = match map.get(&key) {
+                            Ok(Some(value)) => value.clone(),
+                            Ok(None) => return self.report_error(&format!("missing key {}", key)),
+                            Err(message) => return self.report_error(&message),
+                        };
+                        self.stack.push(value);
+                    }
+                }
+                OpCode::BuildMap(entries_count) => {
+                    let Some(start) = self
+                        .stack
+                        .len()
+                        .checked_sub(entries_count.saturating_mul(2))
+                    else {
+                        return InterpretResult::RuntimeError;
+                    };
+                    let mut map = Map::new();
+                    let mut values = self.stack.split_off(start).into_iter();
+                    while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                        if let Err(message) = map.insert(key, value) {
+                            return self.report_error(&message);
+                        }
+                    }
+                    self.stack.push(Value::Map(map));
+                }
+                // Entries of arrays are their indices and elements, for loops
+                // walk them by position. Entries of iterators are the count of
+                // items read and the next item
+                OpCode::HasEntry | OpCode::Entry => {
+                    let is_check = matches!(instruction, OpCode::HasEntry);
+                    let (Some(Value::Integer(position)), Some(collection)) =
+                        (self.stack.pop(), self.stack.pop())
+                    else {
+                        return InterpretResult::RuntimeError;
+                    };
+                    let position = position as usize;
+                    let entry = match collection {
+                        Value::Array(mut elements) if position < elements.len() => Some((
+                            Value::Integer(position as i64),
+                            elements.swap_remove(position),
+                        )),
+                        Value::Map(map) if position < map.entries().len() => {
+                            Some(map.entries()[position].clone())
+                        }
+                        Value::Array(_) | Value::Map(_) => None,
+                        // The check reads the item ahead, only its presence matters
+                        Value::Iterator(iterator) if is_check => match self.has_next(&iterator) {
+                            Ok(has_next) => has_next.then_some((Value::Null, Value::Null)),
+                            Err(message) => return self.report_error(&message),
+                        },
+                        Value::Iterator(iterator) => match self.next_item(&iterator) {
+                            Ok(item) => item.map(|item| (Value::Integer(position as i64), item)),
+                            Err(message) => return self.report_error(&message),
+                        },
+                        v => {
+                            let message = format!("{} is not iterable", v.type_name());
+                            return self.report_error(&message);
+                        }
+                    };
+                    match entry {
+                        _ if is_check => self.stack.push(Value::Boolean(entry.is_some())),
+                        Some((key, value)) => {
+                            self.stack.push(key);
+                            self.stack.push(value);
+                        }
+                        None => return InterpretResult::RuntimeError,
+                    }
+                }
+                OpCode::Yield => {
+                    let Some(generator) = frame.generator.clone() else {
+                        return self.report_error("yield outside a generator");
+                    };
+                    let value = self.stack.pop().unwrap_or(Value::Null);
+                    let (slots_start, pc) = (frame.slots_start, frame.pc);
+                    self.frames.pop();
 
-                    self.frames.push(new_frame);
+                    // Captured locals are closed while suspended and kept to
+                    // be opened again on resume
+                    let captured: Vec<(usize, Rc<RefCell<Upvalue>>)> = self
+                        .open_upvalues
+                        .iter()
+                        .filter_map(|upvalue| match *upvalue.borrow() {
+                            Upvalue::Open(slot) if slot >= slots_start => {
+                                Some((slot - slots_start, upvalue.clone()))
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                    close_upvalues(&mut self.open_upvalues, &self.stack, slots_start);
+                    let slots = self.stack.split_off(slots_start.min(self.stack.len()));
+                    {
+                        let mut suspended = generator.borrow_mut();
+                        suspended.pc = pc;
+                        suspended.slots = slots;
+                        suspended.captured = captured;
+                        suspended.state = GeneratorState::Suspended;
+                    }
+                    self.stack.push(value);
+
+                    if self.frames.len() <= base_frames {
+                        return InterpretResult::Ok;
+                    }
                 }
                 OpCode::Return => {
-                    let result = self.stack.pop().unwrap_or(Value::Null);
+                    // Generators hand out null once they finish
+                    let result = match frame.generator {
+                        Some(_) => Value::Null,
+                        None => self.stack.pop().unwrap_or(Value::Null),
+                    };
                     let slots_start = frame.slots_start;
+                    // Variables of the frame captured by closures outlive it
+                    close_upvalues(&mut self.open_upvalues, &self.stack, slots_start);
 
                     self.frames.pop();
 
                     if self.frames.is_empty() {
+                        // The script's return value is left as the program result
+                        self.stack.push(result);
                         return InterpretResult::Ok;
                     }
 
-                    // Remove slots used for the frame
+                    // Remove slots used for the frame, including the callee
                     self.stack.truncate(slots_start);
                     self.stack.push(result);
+
+                    if self.frames.len() <= base_frames {
+                        return InterpretResult::Ok;
+                    }
                 }
             }
         }
     }
 }
 
-fn is_falsey(value: &Value) -> bool {
+// Globals sorted by name, the map order isn't stable between runs so
+// anything listing globals should go through here
+pub fn sorted_globals(globals: &HashMap<Rc<str>, Value>) -> Vec<(&Rc<str>, &Value)> {
+    let mut globals: Vec<_> = globals.iter().collect();
+    globals.sort_by_key(|(name, _)| *name);
+    globals
+}
+
+// Returns the upvalue pointing to the stack slot, creating it if the slot
+// wasn't captured yet
+fn capture_upvalue(
+    open_upvalues: &mut Vec<Rc<RefCell<Upvalue>>>,
+    slot: usize,
+) -> Rc<RefCell<Upvalue>> {
+    let position = open_upvalues.partition_point(|upvalue| match &*upvalue.borrow() {
+        Upvalue::Open(open_slot) => *open_slot < slot,
+        Upvalue::Closed(_) => true,
+    });
+
+    if let Some(upvalue) = open_upvalues.get(position) {
+        if *upvalue.borrow() == Upvalue::Open(slot) {
+            return upvalue.clone();
+        }
+    }
+
+    let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+    open_upvalues.insert(position, upvalue.clone());
+    upvalue
+}
+
+// Integer value of an array index, floats are accepted when they're whole
+fn array_index(index: &Value) -> Option<i64> {
+    match index {
+        Value::Integer(index) => Some(*index),
+        Value::Number(index) if index.fract() == 0.0 => Some(*index as i64),
+        _ => None,
+    }
+}
+
+// Moves the values of the slots from `first_slot` up into their upvalues
+fn close_upvalues(
+    open_upvalues: &mut Vec<Rc<RefCell<Upvalue>>>,
+    stack: &[Value],
+    first_slot: usize,
+) {
+    while let Some(upvalue) = open_upvalues.last() {
+        let Upvalue::Open(slot) = *upvalue.borrow() else {
+            break;
+        };
+        if slot < first_slot {
+            break;
+        }
+
+        let value = stack.get(slot).cloned().unwrap_or(Value::Null);
+        *upvalue.borrow_mut() = Upvalue::Closed(value);
+        open_upvalues.pop();
+    }
+}
+
+// Type error of a binary operator, naming what it expects and what it got
+fn operands_error(expected: &str, left: &Value, right: &Value) -> String {
+    format!(
+        "operands must be {}, got {} and {}",
+        expected,
+        left.type_name(),
+        right.type_name()
+    )
+}
+
+// Native bound to the builtins disabled by the host
+fn unavailable(_: &mut VirtualMachine, _: &[Value]) -> Result<Value, String> {
+    Err("builtin not available".to_string())
+}
+
+// Why an arithmetic operation gave no value
+fn arithmetic_error(operator: &OpCode, left: &Value, right: &Value) -> String {
+    if !left.is_number() || !right.is_number() {
+        let expected = match operator {
+            OpCode::Add => "two numbers or two strings",
+            _ => "numbers",
+        };
+        return operands_error(expected, left, right);
+    }
+    let is_zero =
+        matches!(right, Value::Integer(0)) || matches!(right, Value::Number(n) if *n == 0.0);
+    match operator {
+        OpCode::Divide if is_zero => "division by zero".to_string(),
+        OpCode::Modulo if is_zero => "modulo by zero".to_string(),
+        _ => "integer overflow".to_string(),
+    }
+}
+
+fn arity_range(min_arity: usize, max_arity: usize) -> String {
+    if min_arity == max_arity {
+        min_arity.to_string()
+    } else if max_arity == usize::MAX {
+        format!("at least {}", min_arity)
+    } else {
+        format!("{} to {}", min_arity, max_arity)
+    }
+}
+
+// Forward jumps may land at most right past the last instruction, which ends
+// the function like reaching its end
+fn jump_target(pc: usize, offset: usize, len: usize) -> Option<usize> {
+    pc.checked_add(offset).filter(|target| *target <= len)
+}
+
+pub(super) fn is_falsey(value: &Value) -> bool {
     match value {
         Value::Boolean(bool) => !bool,
         Value::Null => true,
@@ -292,19 +1232,189 @@ fn is_falsey(value: &Value) -> bool {
     }
 }
 
-pub fn compile_and_run(input: String) {
+// How compile_and_run reports on a script and limits it
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    // Print the final value of the script
+    pub print_last: bool,
+    pub warnings_as_errors: bool,
+    // Print how many times each opcode ran to stderr
+    pub profile: bool,
+    pub sandbox: bool,
+    // Instructions run before the script is interrupted, replacing the
+    // sandbox's
+    pub max_steps: Option<usize>,
+    // Values on the stack before a stack overflow, replacing the sandbox's
+    pub max_stack: Option<usize>,
+    // Print every instruction to stderr
+    pub trace: bool,
+    // Print the size of the compiled functions to stderr
+    pub stats: bool,
+}
+
+// Runs a script with the given options. Returns how it ended and its
+// globals, empty when it doesn't compile. Everything it prints goes through
+// the writers the VM is then given, so a sandboxed script never writes to the
+// host directly
+pub fn compile_and_run(
+    input: String,
+    options: &RunOptions,
+) -> (InterpretResult, HashMap<Rc<str>, Value>) {
+    let mut output = HostWriter(Box::new(io::stdout()));
+    let mut errors = HostWriter(Box::new(io::stderr()));
+
     let lexer = Lexer::new(&input);
     let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+    compiler.warnings_as_errors = options.warnings_as_errors;
+
+    // Reporting is best effort, a failing writer doesn't change the result
+    let result = compiler.compile();
+    for warning in &compiler.warnings {
+        let _ = writeln!(errors.0, "compiler warning: {}", warning.render(&input));
+    }
+    if matches!(result, InterpretResult::CompileError) {
+        let _ = writeln!(output.0, "compiler has {} errors", compiler.errors.len());
+        for error in &compiler.errors {
+            let _ = writeln!(output.0, "compiler error: {}", error.render(&input));
+        }
+        return (InterpretResult::CompileError, HashMap::new());
+    }
+    if options.stats {
+        let _ = write!(
+            errors.0,
+            "{}",
+            summarize_all(&compiler.function.chunk, "script")
+        );
+    }
 
-    if matches!(compiler.compile(), InterpretResult::CompileError) {
+    let mut vm = VirtualMachine::new(take(&mut compiler.function));
+    vm.output_writer = output;
+    vm.error_writer = errors;
+    if options.profile {
+        vm.enable_profile();
+    }
+    if options.sandbox {
+        vm.sandbox();
+    }
+    if let Some(steps) = options.max_steps {
+        vm.set_watchdog(steps, Box::new(|| false));
+    }
+    if let Some(max_stack) = options.max_stack {
+        vm.max_stack = max_stack;
+    }
+    vm.trace = options.trace;
+
+    let result = vm.interpret();
+    if options.profile {
+        let _ = writeln!(vm.error_writer.0, "opcode profile:");
+        for (name, count) in vm.profile() {
+            let _ = writeln!(vm.error_writer.0, "{:>12} {}", count, name);
+        }
+    }
+    let output = &mut vm.output_writer.0;
+    let _ = match &result {
+        InterpretResult::Ok if options.print_last => match vm.stack.last() {
+            Some(value) => writeln!(output, "{}", value),
+            None => Ok(()),
+        },
+        InterpretResult::RuntimeError => match &vm.runtime_error {
+            Some(message) => writeln!(output, "runtime error: {}", message),
+            None => writeln!(output, "runtime error"),
+        },
+        _ => Ok(()),
+    };
+    let _ = output.flush();
+    (result, vm.globals)
+}
+
+// Evaluates a single expression, printing its value
+pub fn eval_expression(input: String) -> InterpretResult {
+    let lexer = Lexer::new(&input);
+    let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+    if matches!(compiler.compile_expression(), InterpretResult::CompileError) {
         println!("compiler has {} errors", compiler.errors.len());
-        for error in compiler.errors {
-            println!("compiler error: {}", error);
+        for error in &compiler.errors {
+            println!("compiler error: {}", error.render(&input));
         }
-        return;
+        return InterpretResult::CompileError;
     }
 
     let mut vm = VirtualMachine::new(take(&mut compiler.function));
+    let result = vm.interpret();
+    match &result {
+        InterpretResult::Ok => {
+            if let Some(value) = vm.stack.last() {
+                println!("{}", value);
+            }
+        }
+        InterpretResult::RuntimeError => match &vm.runtime_error {
+            Some(message) => println!("runtime error: {}", message),
+            None => println!("runtime error"),
+        },
+        InterpretResult::CompileError => (),
+    }
+    result
+}
+
+// Prints the bytecode of the script and all its functions instead of running it
+pub fn disassemble(input: String) -> InterpretResult {
+    let lexer = Lexer::new(&input);
+    let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+    let result = compiler.compile();
+    if matches!(result, InterpretResult::CompileError) {
+        for error in &compiler.errors {
+            println!("compiler error: {}", error.render(&input));
+        }
+        return result;
+    }
+
+    print!("{}", disassemble_all(&compiler.function.chunk, "script"));
+    result
+}
+
+// Prints the bytecode of the function with the given name instead of running
+// the script, a missing function fails like a compile error
+pub fn dump_bytecode_for(input: String, name: &str) -> InterpretResult {
+    let lexer = Lexer::new(&input);
+    let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
 
-    vm.interpret();
+    let result = compiler.compile();
+    if matches!(result, InterpretResult::CompileError) {
+        for error in &compiler.errors {
+            println!("compiler error: {}", error.render(&input));
+        }
+        return result;
+    }
+
+    match find_function(&compiler.function.chunk, name) {
+        Some(function) => {
+            print!("{}", function.chunk.disassemble(name));
+            result
+        }
+        None => {
+            println!("function {} not found", name);
+            InterpretResult::CompileError
+        }
+    }
+}
+
+// Prints the globals declared by the script with their kind, without running it
+pub fn dump_symbols(input: String) -> InterpretResult {
+    let lexer = Lexer::new(&input);
+    let mut compiler = Compiler::new(Rc::new(RefCell::new(lexer)), FunctionType::Script);
+
+    let result = compiler.compile();
+    if matches!(result, InterpretResult::CompileError) {
+        for error in &compiler.errors {
+            println!("compiler error: {}", error.render(&input));
+        }
+        return result;
+    }
+
+    for (name, kind) in &compiler.symbols {
+        println!("{} {}", kind, name);
+    }
+    result
 }
@@ -0,0 +1,365 @@
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+};
+
+// Writes the source to a temporary file and runs the interpreter on it
+fn run_file(name: &str, source: &str, args: &[&str]) -> Output {
+    let path: PathBuf = std::env::temp_dir().join(format!("interpreter_cli_{}.txt", name));
+    fs::write(&path, source).expect("Failed to write the test file");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_interpreter"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .expect("Failed to run the interpreter");
+
+    fs::remove_file(&path).ok();
+    output
+}
+
+// Like run_file but feeds the given input through stdin
+fn run_file_with_input(name: &str, source: &str, args: &[&str], input: &str) -> Output {
+    let path: PathBuf = std::env::temp_dir().join(format!("interpreter_cli_{}.txt", name));
+    fs::write(&path, source).expect("Failed to write the test file");
+
+    let path_arg = path.to_string_lossy().to_string();
+    let args: Vec<&str> = args.iter().copied().chain([path_arg.as_str()]).collect();
+    let output = run_with_input(&args, input);
+
+    fs::remove_file(&path).ok();
+    output
+}
+
+// Runs the interpreter feeding the input through stdin, like typing in the REPL
+fn run_with_input(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_interpreter"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run the interpreter");
+    child
+        .stdin
+        .take()
+        .expect("stdin should be piped")
+        .write_all(input.as_bytes())
+        .expect("Failed to write the input");
+    child
+        .wait_with_output()
+        .expect("Failed to run the interpreter")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[test]
+fn only_print_writes_output() {
+    let source = "fn add(a, b) {\n  return a + b\n}\nadd(2, 3)\nprintln(add(1, 1))\nreturn 7\n";
+
+    let output = run_file("only_print", source, &[]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "2\n");
+}
+
+#[test]
+fn print_builtins() {
+    let source = "print(\"hi\")\nprint(1)\nprintln(\"\")\nprintln([1, \"a\"])\n";
+
+    let output = run_file("print_builtins", source, &["--print-last"]);
+    assert_eq!(stdout(&output), "hi1\n[1, a]\nnull\n");
+}
+
+#[test]
+fn profile() {
+    let source = "let i = 0\nwhile i < 3 {\n  i = i + 1\n}\nprintln(i)\n";
+
+    let output = run_file("profile", source, &["--profile"]);
+    assert_eq!(stdout(&output), "3\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("opcode profile:\n"));
+    assert!(stderr.contains("           3 Loop\n"));
+}
+
+#[test]
+fn print_last() {
+    let source = "let a = 1\n1 + 2\n";
+
+    let output = run_file("print_last", source, &["--print-last"]);
+    assert_eq!(stdout(&output), "3\n");
+
+    let output = run_file("print_last_silent", source, &[]);
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn repl_eval() {
+    let output = run_with_input(&["--repl-eval", "1 + 2 * len([1, 2])"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "5\n");
+
+    let output = run_with_input(&["--repl-eval", "1 +"], "");
+    assert_eq!(output.status.code(), Some(65));
+    assert!(stdout(&output).starts_with("compiler has 1 errors\n"));
+
+    // Statements aren't expressions
+    let output = run_with_input(&["--repl-eval", "let a = 1"], "");
+    assert_eq!(output.status.code(), Some(65));
+
+    let output = run_with_input(&["--repl-eval", "return 5"], "");
+    assert_eq!(output.status.code(), Some(65));
+    assert!(stdout(&output).contains("Can't return outside a function"));
+
+    let output = run_with_input(&["--repl-eval", "[1][5]"], "");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "runtime error: index out of bounds: 5\n");
+}
+
+#[test]
+fn typecheck() {
+    let output = run_file("typecheck_error", "let a = \"a\" - 1\n", &["--typecheck"]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        stdout(&output),
+        "type error: Line 1:13: cannot apply - to string and number\nlet a = \"a\" - 1\n            ^\n"
+    );
+
+    let source = "let x: number = null\nlet y: string = \"a\"\n";
+    let output = run_file("typecheck_annotation", source, &["--typecheck"]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        stdout(&output),
+        "type error: Line 1: type mismatch: x is declared as number but initialized with null\n"
+    );
+
+    let source = "1 + 2\n";
+    let output = run_file("typecheck_ok", source, &["--typecheck", "--print-last"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "3\n");
+}
+
+#[test]
+fn disassemble() {
+    let source = "fn add(a, b) {\n    return a + b\n}\nif add(1, 2) > 2 {\n  add(0, 0)\n}\n";
+
+    let output = run_file("disassemble", source, &["--disassemble"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.starts_with("== script ==\n"));
+    assert!(stdout.contains("| JumpIfFalse(7) -> 0016\n"));
+    assert!(stdout.contains("6 Jump(1) -> 0017\n"));
+    assert!(stdout.contains("== add ==\n0000    2 GetLocal(1)\n"));
+
+    let output = run_file("disassemble_error", "let = 1\n", &["--disassemble"]);
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn dump_bytecode_for() {
+    let source = "fn add(a, b) {\n    return a + b\n}\nadd(1, 2)\n";
+
+    let output = run_file("dump_add", source, &["--dump-bytecode-for", "add"]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "== add ==\n\
+         0000    2 GetLocal(1)\n\
+         0001    | GetLocal(2)\n\
+         0002    | Add\n\
+         0003    | Return\n\
+         0004    3 Null\n\
+         0005    | Return\n"
+    );
+
+    let output = run_file("dump_missing", source, &["--dump-bytecode-for", "sub"]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout(&output), "function sub not found\n");
+
+    let output = run_file("dump_error", "let = 1\n", &["--dump-bytecode-for", "add"]);
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn interactive() {
+    let output = run_file_with_input(
+        "interactive",
+        "let base = 10\n",
+        &["--interactive"],
+        "base + 5\n",
+    );
+
+    assert!(output.status.success());
+    assert!(stdout(&output).ends_with("> 15\n> "));
+}
+
+#[test]
+fn werror() {
+    let source = "if true {\n  let unused = 1\n}\n1 + 2\n";
+
+    let output = run_file("werror_default", source, &["--print-last"]);
+    assert_eq!(stdout(&output), "3\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unused variable unused"));
+
+    let output = run_file("werror", source, &["--werror", "--print-last"]);
+    assert_eq!(
+        stdout(&output),
+        "compiler has 1 errors\ncompiler error: Line 2:7: unused variable unused\n  let unused = 1\n      ^\n"
+    );
+}
+
+#[test]
+fn dump_symbols() {
+    let source = "let a = 1\nlet [b, d] = [2, 3]\nfn c() {\n  let local = 1\n  return local\n}\n";
+
+    let output = run_file("dump_symbols", source, &["--dump-symbols"]);
+    assert_eq!(
+        stdout(&output),
+        "variable a\nvariable b\nvariable d\nfunction c\n"
+    );
+    assert!(output.status.success());
+
+    let output = run_file("dump_symbols_error", "let = 1\n", &["--dump-symbols"]);
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn stats() {
+    let source = "fn add(a, b) {\n    return a + b\n}\nprintln(add(1, 2))\n";
+
+    let output = run_file("stats", source, &["--stats"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("script: "));
+    assert!(lines[1].starts_with("add: 6 instructions, 0 constants, "));
+}
+
+#[test]
+fn trace() {
+    let output = run_file("trace", "let a = 1\n", &["--trace"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("0000 Constant(1) ["));
+    assert!(stderr.contains("0001 DefineGlobal(0) [1, "));
+}
+
+#[test]
+fn sandbox() {
+    let output = run_file("sandbox", "println(1)\nclock()\n", &["--sandbox"]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "1\nruntime error: builtin not available\n");
+
+    let output = run_file("sandbox_clock", "clock()\n", &[]);
+    assert!(output.status.success());
+
+    let output = run_file("sandbox_loop", "while true {\n}\n", &["--sandbox"]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "runtime error: interrupted\n");
+
+    let output = run_file("max_steps", "while true {\n}\n", &["--max-steps", "1000"]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "runtime error: interrupted\n");
+
+    let wide = format!("let x = 1\nlet a = [{}x]\n", "x, ".repeat(20));
+    let output = run_file("max_stack", &wide, &["--max-stack", "10"]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "runtime error: stack overflow\n");
+}
+
+#[test]
+fn exit_codes() {
+    let output = run_file("exit_ok", "let a = 1\n", &[]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run_file("exit_compile_error", "let = 1\n", &[]);
+    assert_eq!(output.status.code(), Some(65));
+
+    let output = run_file("exit_runtime_error", "let a = [1]\na[3]\n", &[]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "runtime error: index out of bounds: 3\n");
+}
+
+#[test]
+fn vm_repl_submissions() {
+    let input = "let a = 1\nlet b = 2\na + b\nlet c = 3; let d = 4; c * d\n\
+                 fn add(x, y) {\n  return x + y\n}\nadd(a, d)\nif a < d {\n  a = d\n}\na\n";
+    let output = run_with_input(&[], input);
+
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    let session = stdout.split_once('\n').map_or("", |(_, session)| session);
+    assert_eq!(
+        session,
+        "> > > 3\n\
+         > 12\n\
+         > ... ... > 5\n\
+         > ... ... > 4\n\
+         > "
+    );
+}
+
+#[test]
+fn vm_repl_forward_reference() {
+    let input = "fn f() {\n  return g()\n}\nfn g() {\n  return 1\n}\nf()\n";
+    let output = run_with_input(&[], input);
+
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    let session = stdout.split_once('\n').map_or("", |(_, session)| session);
+    assert_eq!(session, "> ... ... > ... ... > 1\n> ");
+}
+
+#[test]
+fn vm_repl_paste() {
+    let input = "\x1b[200~let a = 1\nlet b = 2\na + b\n1\n2\x1b[201~\na + b\n";
+    let output = run_with_input(&[], input);
+
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    let session = stdout.split_once('\n').map_or("", |(_, session)| session);
+    assert_eq!(session, "> 2\n> 3\n> ");
+}
+
+#[test]
+fn vm_repl_last_value() {
+    let output = run_with_input(&[], "_\n1 + 2\n_ * 10\nlet a = 5\n_\n.reset\n_\na\n");
+
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    let session = stdout.split_once('\n').map_or("", |(_, session)| session);
+    assert_eq!(
+        session,
+        "> null\n\
+         > 3\n\
+         > 30\n\
+         > > 30\n\
+         > > null\n\
+         > Line 1:1: Undefined variable a\n\
+         a\n^\n\
+         > "
+    );
+}
+
+#[test]
+fn vm_repl() {
+    let output = run_with_input(&[], "let a = 1\nlet = 2\na + 2\n[1][4]\na\n");
+
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    let session = stdout.split_once('\n').map_or("", |(_, session)| session);
+    assert_eq!(
+        session,
+        "> > Line 1:5: Expected next token to be Identifier, got = with lexeme = instead\n\
+         let = 2\n    ^\n\
+         > 3\n\
+         > runtime error: index out of bounds: 4\n\
+         > 1\n\
+         > "
+    );
+}